@@ -0,0 +1,322 @@
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// How the target is computed between the points of the table.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    /// Monotone cubic spline (Fritsch-Carlson), which does not overshoot between the points.
+    MonotoneSpline,
+}
+
+/// How voltages outside of the table are handled.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Extrapolation {
+    /// Use the target of the nearest end of the table.
+    #[default]
+    Clamp,
+    /// Continue with the slope at the end of the table.
+    Linear,
+    /// Fault the control loop.
+    Error,
+}
+
+impl FromStr for Interpolation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Linear" => Ok(Self::Linear),
+            "MonotoneSpline" => Ok(Self::MonotoneSpline),
+            _ => Err(anyhow!("Unknown interpolation {}", s)),
+        }
+    }
+}
+
+impl FromStr for Extrapolation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Clamp" => Ok(Self::Clamp),
+            "Linear" => Ok(Self::Linear),
+            "Error" => Ok(Self::Error),
+            _ => Err(anyhow!("Unknown extrapolation {}", s)),
+        }
+    }
+}
+
+fn default_voltage() -> u8 {
+    1
+}
+
+/// Measured relation between a voltage and the target of an axis,
+/// used instead of the formula of the axis.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationConfig {
+    /// The voltage the table is looked up with, 1 for `v1` and 2 for `v2`.
+    #[serde(default = "default_voltage")]
+    pub voltage: u8,
+    /// CSV file with lines of `voltage,target in mm`, used instead of `points` if set.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Pairs of voltage and target in mm.
+    #[serde(default)]
+    pub points: Vec<[f64; 2]>,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    #[serde(default)]
+    pub extrapolation: Extrapolation,
+}
+
+/// Parses lines of `voltage,target`, a header in the first line and lines starting
+/// with `#` are skipped.
+pub fn parse_csv(content: &str) -> Result<Vec<[f64; 2]>> {
+    let mut points = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if i == 0 && fields[0].parse::<f64>().is_err() {
+            continue;
+        }
+        if fields.len() != 2 {
+            return Err(anyhow!("line {}: expected 2 values, got {}", i + 1, fields.len()));
+        }
+
+        let parse = |field: &str| {
+            field
+                .parse::<f64>()
+                .map_err(|e| anyhow!("line {}: invalid value '{}': {}", i + 1, field, e))
+        };
+        points.push([parse(fields[0])?, parse(fields[1])?]);
+    }
+
+    Ok(points)
+}
+
+/// Looks up the target in mm of a voltage in a calibration table.
+#[derive(Clone, Debug)]
+pub struct CalibrationTable {
+    /// Index of the voltage in `CycleData::voltages`.
+    pub voltage: usize,
+    /// Points sorted by the voltage.
+    points: Vec<[f64; 2]>,
+    /// Slopes at the points in mm/V.
+    slopes: Vec<f64>,
+    interpolation: Interpolation,
+    extrapolation: Extrapolation,
+}
+
+impl CalibrationTable {
+    /// Reads the points from the CSV file if the config has a path.
+    pub fn new(config: &CalibrationConfig) -> Result<Self> {
+        let mut points = match &config.path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?;
+                parse_csv(&content).map_err(|e| anyhow!("{}: {}", path.display(), e))?
+            }
+            None => config.points.clone(),
+        };
+        if !(1..=2).contains(&config.voltage) {
+            return Err(anyhow!("Unknown voltage v{}", config.voltage));
+        }
+        if points.len() < 2 {
+            return Err(anyhow!("The calibration table needs at least 2 points"));
+        }
+        if points.iter().flatten().any(|x| !x.is_finite()) {
+            return Err(anyhow!("The calibration table contains invalid numbers"));
+        }
+
+        points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        if let Some(w) = points.windows(2).find(|w| w[0][0] == w[1][0]) {
+            return Err(anyhow!(
+                "The voltage {} V appears twice in the calibration table",
+                w[0][0]
+            ));
+        }
+
+        let slopes = match config.interpolation {
+            Interpolation::Linear => Vec::new(),
+            Interpolation::MonotoneSpline => spline_slopes(&points),
+        };
+
+        Ok(Self {
+            voltage: config.voltage as usize - 1,
+            points,
+            slopes,
+            interpolation: config.interpolation.clone(),
+            extrapolation: config.extrapolation.clone(),
+        })
+    }
+
+    /// Returns the target in mm for the voltage `v`.
+    pub fn lookup(&self, v: f64) -> Result<f64> {
+        let n = self.points.len();
+        let [first, last] = [self.points[0], self.points[n - 1]];
+        if v < first[0] || v > last[0] {
+            let (end, slope) = match v < first[0] {
+                true => (first, self.end_slope(false)),
+                false => (last, self.end_slope(true)),
+            };
+            return match self.extrapolation {
+                Extrapolation::Clamp => Ok(end[1]),
+                Extrapolation::Linear => Ok(end[1] + slope * (v - end[0])),
+                Extrapolation::Error => Err(anyhow!(
+                    "The voltage {:.3} V is outside of the calibration table from {} V to {} V",
+                    v,
+                    first[0],
+                    last[0]
+                )),
+            };
+        }
+
+        // Index of the segment containing `v`
+        let k = self.points.partition_point(|p| p[0] <= v).clamp(1, n - 1) - 1;
+        let ([x0, y0], [x1, y1]) = (self.points[k], self.points[k + 1]);
+        let h = x1 - x0;
+        let t = (v - x0) / h;
+
+        match self.interpolation {
+            Interpolation::Linear => Ok(y0 + t * (y1 - y0)),
+            Interpolation::MonotoneSpline => {
+                let (m0, m1) = (self.slopes[k], self.slopes[k + 1]);
+                let t2 = t * t;
+                let t3 = t2 * t;
+                Ok((2. * t3 - 3. * t2 + 1.) * y0
+                    + (t3 - 2. * t2 + t) * h * m0
+                    + (-2. * t3 + 3. * t2) * y1
+                    + (t3 - t2) * h * m1)
+            }
+        }
+    }
+
+    /// Slope at the first or the last point of the table.
+    fn end_slope(&self, last: bool) -> f64 {
+        let n = self.points.len();
+        match (&self.interpolation, last) {
+            (Interpolation::Linear, false) => secant(self.points[0], self.points[1]),
+            (Interpolation::Linear, true) => secant(self.points[n - 2], self.points[n - 1]),
+            (Interpolation::MonotoneSpline, false) => self.slopes[0],
+            (Interpolation::MonotoneSpline, true) => self.slopes[n - 1],
+        }
+    }
+}
+
+fn secant(a: [f64; 2], b: [f64; 2]) -> f64 {
+    (b[1] - a[1]) / (b[0] - a[0])
+}
+
+/// Slopes at the points of a monotone cubic spline by Fritsch and Carlson.
+fn spline_slopes(points: &[[f64; 2]]) -> Vec<f64> {
+    let n = points.len();
+    let secants: Vec<f64> = points.windows(2).map(|w| secant(w[0], w[1])).collect();
+
+    let mut slopes = vec![0.; n];
+    slopes[0] = secants[0];
+    slopes[n - 1] = secants[n - 2];
+    for k in 1..n - 1 {
+        let (d0, d1) = (secants[k - 1], secants[k]);
+        // Local extrema are flat, so the spline does not overshoot
+        if d0 * d1 <= 0. {
+            continue;
+        }
+        let h0 = points[k][0] - points[k - 1][0];
+        let h1 = points[k + 1][0] - points[k][0];
+        let w0 = 2. * h1 + h0;
+        let w1 = h1 + 2. * h0;
+        slopes[k] = (w0 + w1) / (w0 / d0 + w1 / d1);
+    }
+
+    slopes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(interpolation: Interpolation, extrapolation: Extrapolation) -> CalibrationConfig {
+        CalibrationConfig {
+            voltage: 1,
+            path: None,
+            points: vec![[2., 20.], [0., 0.], [1., 5.], [3., 21.]],
+            interpolation,
+            extrapolation,
+        }
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let points = parse_csv("voltage,target\n# comment\n0.5, 1\n\n1,2.5\n").unwrap();
+        assert_eq!(points, vec![[0.5, 1.], [1., 2.5]]);
+
+        assert!(parse_csv("0.5,1\n1").is_err());
+        assert!(parse_csv("0.5,1\n1,a").is_err());
+    }
+
+    #[test]
+    fn test_linear() {
+        let table = CalibrationTable::new(&config(Interpolation::Linear, Extrapolation::Linear))
+            .unwrap();
+
+        assert_eq!(table.lookup(0.5).unwrap(), 2.5);
+        assert_eq!(table.lookup(1.5).unwrap(), 12.5);
+        assert_eq!(table.lookup(3.).unwrap(), 21.);
+        assert_eq!(table.lookup(-1.).unwrap(), -5.);
+        assert_eq!(table.lookup(4.).unwrap(), 22.);
+    }
+
+    #[test]
+    fn test_extrapolation() {
+        let table = CalibrationTable::new(&config(Interpolation::Linear, Extrapolation::Clamp))
+            .unwrap();
+        assert_eq!(table.lookup(-1.).unwrap(), 0.);
+        assert_eq!(table.lookup(4.).unwrap(), 21.);
+
+        let table = CalibrationTable::new(&config(Interpolation::Linear, Extrapolation::Error))
+            .unwrap();
+        assert!(table.lookup(-1.).is_err());
+        assert!(table.lookup(4.).is_err());
+    }
+
+    #[test]
+    fn test_monotone_spline() {
+        let table = CalibrationTable::new(&config(
+            Interpolation::MonotoneSpline,
+            Extrapolation::Clamp,
+        ))
+        .unwrap();
+
+        for [v, target] in [[0., 0.], [1., 5.], [2., 20.], [3., 21.]] {
+            assert!((table.lookup(v).unwrap() - target).abs() < 1e-9);
+        }
+
+        // Monotone between the points without overshooting the last one
+        let targets: Vec<f64> = (0..=300)
+            .map(|i| table.lookup(i as f64 / 100.).unwrap())
+            .collect();
+        assert!(targets.windows(2).all(|w| w[0] <= w[1]));
+        assert!(targets.iter().all(|&target| target <= 21.));
+    }
+
+    #[test]
+    fn test_invalid_table() {
+        let mut config = config(Interpolation::Linear, Extrapolation::Clamp);
+        config.points = vec![[0., 0.]];
+        assert!(CalibrationTable::new(&config).is_err());
+
+        config.points = vec![[0., 0.], [0., 1.]];
+        assert!(CalibrationTable::new(&config).is_err());
+
+        config.points = vec![[0., 0.], [1., 1.]];
+        config.voltage = 3;
+        assert!(CalibrationTable::new(&config).is_err());
+    }
+}
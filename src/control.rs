@@ -1,22 +1,262 @@
 use crate::{
-    utils::{self, ExecState},
-    zaber::{
-        get_pos_zaber, init_zaber, init_zaber_mock, mm_to_steps, move_coax_zaber, move_cross_zaber,
-        Adc, ZaberConn,
-    },
+    deadband::Deadband,
+    error::ControlError,
+    filter::FilterChain,
+    formula::Formulas,
+    homing::PositionStore,
+    pid::Pid,
+    predictor::Predictor,
+    ramp::Waveform,
+    retry::RetryPolicy,
+    sequence::SequencePlayer,
+    timing::{next_deadline, CycleTimer},
+    transition::Transition,
+    utils::{self, Config, ControlMode, Estop, ExecState, LimitPolicy, SharedState},
+    watchdog::Watchdog,
+    zaber::{estop_serial, init_zaber, init_zaber_mock, mm_to_steps, steps_to_mm, Adc},
 };
 use ads1x1x::{channel::{DifferentialA0A1, DifferentialA2A3}, Ads1x1x, FullScaleRange, TargetAddr};
 use anyhow::{anyhow, Result};
-use evalexpr::Value;
 use ftdi_embedded_hal::{libftd2xx::{self}, FtHal};
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use rayon::prelude::*;
 
+/// Motion backend moving the axes, which are identified by their index in the config.
 pub trait Backend {
-    fn get_target(&mut self) -> Result<(u32, u32, f64, f64)>;
-    fn get_pos(&mut self) -> Result<(u32, u32, bool, bool)>;
-    fn move_coax(&mut self, target: u32) -> Result<()>;
-    fn move_cross(&mut self, target: u32) -> Result<()>;
+    /// Returns the busy flags and the positions of all axes in microsteps.
+    fn get_pos(&mut self) -> Result<(Vec<bool>, Vec<u32>)>;
+
+    /// Moves the axis with index `axis` to the absolute position `target` in microsteps.
+    fn move_abs(&mut self, axis: usize, target: u32) -> Result<()>;
+
+    /// Moves the axis with index `axis` with a constant velocity in Zaber units.
+    fn move_vel(&mut self, axis: usize, velocity: i32) -> Result<()>;
+
+    /// Sets the max. speed in Zaber units used for moves of the axis with index `axis`.
+    fn set_maxspeed(&mut self, axis: usize, speed: u32) -> Result<()>;
+
+    /// Stops all axes with their configured deceleration.
+    fn stop(&mut self) -> Result<()>;
+
+    /// Stops all axes as fast as possible, used after faults.
+    fn estop(&mut self) -> Result<()>;
+}
+
+/// Owns the backend and emergency stops the axes if it is dropped before they were
+/// stopped with `safe_stop`, e.g. while unwinding from a panic in the control loop.
+pub struct BackendGuard {
+    backend: Box<dyn Backend>,
+    stopped: bool,
+}
+
+impl BackendGuard {
+    pub fn new(backend: Box<dyn Backend>) -> Self {
+        Self {
+            backend,
+            stopped: false,
+        }
+    }
+
+    pub fn as_mut(&mut self) -> &mut dyn Backend {
+        self.backend.as_mut()
+    }
+
+    /// See [`safe_stop`].
+    pub fn safe_stop(&mut self, fault: bool, timeout: Duration) -> bool {
+        self.stopped = true;
+        safe_stop(self.backend.as_mut(), fault, timeout)
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        if !self.stopped {
+            tracing::warn!("Backend dropped while the axes might be moving, sending estop");
+            if let Err(e) = self.backend.estop() {
+                tracing::error!("Failed to stop the axes: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Sends `stop`, or `estop` after a fault, to all axes and waits up to `timeout`
+/// for them to come to rest. Returns whether the axes are at rest.
+pub fn safe_stop(backend: &mut dyn Backend, fault: bool, timeout: Duration) -> bool {
+    let result = match fault {
+        true => backend.estop(),
+        false => backend.stop(),
+    };
+    if let Err(e) = result {
+        tracing::error!("Failed to stop the axes: {:#}", e);
+    }
+
+    let start = Instant::now();
+    loop {
+        match backend.get_pos() {
+            Ok((is_busy, _)) if !is_busy.contains(&true) => return true,
+            Ok(_) => (),
+            Err(e) => {
+                tracing::error!("Failed to check if the axes came to rest: {:#}", e);
+                return false;
+            }
+        }
+
+        if start.elapsed() > timeout {
+            tracing::error!("Axes did not come to rest within {:?}", timeout);
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Source of the two voltages the targets are computed from.
+pub trait VoltageSource {
+    fn read_voltages(&mut self) -> Result<[f64; 2]>;
+}
+
+/// Measurements of the current control cycle.
+#[derive(Clone, Debug)]
+pub struct CycleData {
+    /// The filtered voltages.
+    pub voltages: [f64; 2],
+    pub positions: Vec<u32>,
+    pub is_busy: Vec<bool>,
+    /// Time since the start of the control loop in seconds.
+    pub time: f64,
+    /// Time since the previous cycle in seconds.
+    pub dt: f64,
+}
+
+/// Source of the targets of the axes, selected by the control mode.
+pub trait TargetSource {
+    /// Returns the targets of all axes in microsteps.
+    fn get_targets(&mut self, cycle: &CycleData) -> Result<Vec<u32>>;
+
+    /// Max. speeds in Zaber units to use instead of the configured ones,
+    /// axes without an entry use the configured one.
+    fn maxspeeds(&self) -> Vec<Option<u32>> {
+        Vec::new()
+    }
+
+    /// Writes the state of the source into the published state.
+    fn publish(&self, _shared: &mut SharedState) {}
+
+    /// Picks up changed settings while the control loop runs.
+    fn reload(&mut self, _config: &Config) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// One function per axis computing its target.
+impl<F: Fn(&CycleData) -> Result<u32>> TargetSource for Vec<F> {
+    fn get_targets(&mut self, cycle: &CycleData) -> Result<Vec<u32>> {
+        self.iter().map(|f| f(cycle)).collect()
+    }
+}
+
+/// Creates a backend, homing is aborted by the emergency stop.
+pub type BackendFactory = fn(&Config, &Estop) -> Result<Box<dyn Backend>>;
+pub type VoltageSourceFactory = fn(&Config) -> Result<Box<dyn VoltageSource>>;
+
+/// All motion backends which can be selected with `backend` in the config.
+pub const BACKENDS: &[(&str, BackendFactory)] = &[
+    ("zaber", create_backend_zaber as BackendFactory),
+    ("simulator", create_backend_simulator as BackendFactory),
+];
+
+/// All voltage sources which can be selected with `voltage_source` in the config.
+pub const VOLTAGE_SOURCES: &[(&str, VoltageSourceFactory)] = &[
+    ("adc", create_voltage_source_adc as VoltageSourceFactory),
+    ("mock", create_voltage_source_mock as VoltageSourceFactory),
+];
+
+fn create_backend_zaber(config: &Config, estop: &Estop) -> Result<Box<dyn Backend>> {
+    Ok(Box::new(init_zaber(config, estop)?))
+}
+
+fn create_backend_simulator(config: &Config, estop: &Estop) -> Result<Box<dyn Backend>> {
+    Ok(Box::new(init_zaber_mock(config, estop)?))
+}
+
+fn create_voltage_source_adc(_config: &Config) -> Result<Box<dyn VoltageSource>> {
+    Ok(Box::new(init_adc()?))
+}
+
+fn create_voltage_source_mock(_config: &Config) -> Result<Box<dyn VoltageSource>> {
+    let voltages: [f64; 2] = [0.; 2];
+    Ok(Box::new(voltages))
+}
+
+fn lookup<'a, F>(registry: &'a [(&str, F)], kind: &str, name: &str) -> Result<&'a F> {
+    match registry.iter().find(|(n, _)| *n == name) {
+        Some((_, factory)) => Ok(factory),
+        None => Err(anyhow!(
+            "Unknown {} '{}', available are: {}",
+            kind,
+            name,
+            registry
+                .iter()
+                .map(|(n, _)| *n)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+pub fn create_backend(config: &Config, estop: &Estop) -> Result<Box<dyn Backend>> {
+    lookup(BACKENDS, "backend", &config.backend)?(config, estop)
+}
+
+/// Sends `estop` to the axes while the control loop is not running, e.g. if they did not
+/// come to rest after the last stop. The simulator only exists while the loop runs.
+pub fn estop_idle(config: &Config) -> Result<()> {
+    match config.backend.as_str() {
+        "zaber" => estop_serial(config),
+        _ => Ok(()),
+    }
+}
+
+/// Emergency stops the axes right away if the latch is set and returns
+/// `ControlError::EmergencyStop`, so the control loop exits.
+fn check_estop(state: &ExecState, backend: &mut dyn Backend) -> Result<()> {
+    let Some(trigger) = state.estop.triggered() else {
+        return Ok(());
+    };
+    if let Err(e) = backend.estop() {
+        tracing::error!("Failed to stop the axes: {:#}", e);
+    }
+    Err(ControlError::EmergencyStop {
+        source: trigger.source,
+    }
+    .into())
+}
+
+pub fn create_voltage_source(config: &Config) -> Result<Box<dyn VoltageSource>> {
+    lookup(VOLTAGE_SOURCES, "voltage source", &config.voltage_source)?(config)
+}
+
+impl VoltageSource for [Adc; 2] {
+    fn read_voltages(&mut self) -> Result<[f64; 2]> {
+        // TODO(marco): Try to get [f64; 2] without Vec
+        let voltage_readings: Vec<Result<f64>> = self.par_iter_mut().map(read_voltage).collect();
+
+        // Just to convert into [f64; 2]
+        let mut voltages = [0.; 2];
+        for (i, v) in voltage_readings.into_iter().enumerate() {
+            voltages[i] = v?;
+        }
+
+        Ok(voltages)
+    }
+}
+
+impl VoltageSource for [f64; 2] {
+    fn read_voltages(&mut self) -> Result<[f64; 2]> {
+        Ok(*self)
+    }
 }
 
 pub fn init_adc() -> Result<[Adc; 2]> {
@@ -82,107 +322,111 @@ pub fn init(state: &mut ExecState) -> Result<()> {
     let config = { state.config.read().unwrap().clone() };
 
     state.shared.error = None;
+    state.shared.at_rest = None;
+    state.shared.set_axes(&config.axes);
     if let Ok(mut out) = state.out_channel.try_write() {
         *out = state.shared.clone();
         drop(out);
     }
 
-    tracing::debug!("Init control with backend {:?}", config.control_mode);
-    match config.mock_zaber {
-        false => match config.mock_adc {
-            false => {
-                let adcs = init_adc()?;
-                init_backend(init_zaber(&config)?, adcs, state, [read_voltage_adc, read_voltage_adc])
+    tracing::debug!(
+        "Init control with backend {} and voltage source {}",
+        config.backend,
+        config.voltage_source
+    );
+    let mut voltage_source =
+        create_voltage_source(&config).map_err(|e| ControlError::Adc.wrap(e))?;
+    let mut backend = BackendGuard::new(create_backend(&config, &state.estop)?);
+
+    let result = init_backend(backend.as_mut(), voltage_source.as_mut(), state);
+
+    // The last move would keep executing after the loop exited
+    let at_rest = backend.safe_stop(result.is_err(), config.stop_timeout_ms);
+    state.shared.at_rest = Some(at_rest);
+
+    // The next start can skip homing if the devices still report these positions
+    if at_rest {
+        match backend.as_mut().get_pos() {
+            Ok((_, positions)) => {
+                let store = PositionStore::new(&config.axes, &positions);
+                if let Err(e) = store.save(&config.position_store_path) {
+                    tracing::warn!("{:#}", e);
+                }
             }
-            true => init_backend(init_zaber(&config)?, [0., 0.], state, [read_voltage_mock, read_voltage_mock]),
-        },
-        true => match config.mock_adc {
-            false => {
-                let adcs = init_adc()?;
-                init_backend(init_zaber_mock(&config)?, adcs, state, [read_voltage_adc, read_voltage_adc])
-            },
-            true => init_backend(init_zaber_mock(&config)?, [0., 0.], state, [read_voltage_mock, read_voltage_mock]),
-        },
+            Err(e) => tracing::warn!("Failed to read the positions to store: {:#}", e),
+        }
     }
-}
-
-fn read_voltage_adc(adc: &mut Adc) -> Result<f64> {
-    read_voltage(adc)
-}
 
-fn read_voltage_mock(val: &mut f64) -> Result<f64> {
-    Ok(*val)
+    result
 }
 
-fn init_backend<T, V: Send + Sync>(
-    mut port: ZaberConn<T>,
-    mut adcs: [V; 2],
+fn init_backend(
+    backend: &mut dyn Backend,
+    voltage_source: &mut dyn VoltageSource,
     state: &mut ExecState,
-    mut funcs_read_voltage: [fn(&mut V) -> Result<f64>; 2],
-) -> Result<()>
-where
-    T: zproto::backend::Backend,
-{
+) -> Result<()> {
     loop {
         let config = {
             let s = state.config.read().unwrap();
             s.clone()
         };
+        state.shared.sequence_step = None;
 
         let result = match config.control_mode {
             utils::ControlMode::Manual => {
                 tracing::debug!("starting in control mode Manual");
-                let funcs_voltage_to_target = [0, 1].map(|i| {
-                    let targets_shared = Arc::clone(&state.target_manual);
-                    move |_voltages: &[f64; 2]| {
-                        let targets = targets_shared.read().unwrap();
+                // The axes stay where they are until a new target is sent
+                let (_, positions) = backend.get_pos()?;
+                *state.target_manual.write().unwrap() = positions;
+                let mut funcs_voltage_to_target: Vec<_> = (0..config.axes.len())
+                    .map(|i| {
+                        let targets_shared = Arc::clone(&state.target_manual);
+                        move |cycle: &CycleData| -> Result<u32> {
+                            let targets = targets_shared.read().unwrap();
 
-                        return Ok(targets[i]);
-                    }
-                });
-                run(
-                    state,
-                    &mut port,
-                    &mut adcs,
-                    &mut funcs_read_voltage,
-                    funcs_voltage_to_target,
-                    get_pos_zaber,
-                    [move_coax_zaber, move_cross_zaber],
-                )
+                            // Axes without a manual target keep their position
+                            return Ok(targets.get(i).copied().unwrap_or(cycle.positions[i]));
+                        }
+                    })
+                    .collect();
+                run(state, backend, voltage_source, &mut funcs_voltage_to_target)
             }
 
             utils::ControlMode::Tracking => {
                 tracing::debug!("starting in control mode Tracking");
-                let funcs_voltage_to_target = [
-                    evalexpr::build_operator_tree(&config.formula_coax)?,
-                    evalexpr::build_operator_tree(&config.formula_cross)?,
-                ]
-                .map(|f: evalexpr::Node<evalexpr::DefaultNumericTypes>| {
-                    move |voltages: &[f64; 2]| {
-                        let context = evalexpr::context_map! {
-                            "v1" => Value::Float(voltages[0]),
-                            "v2" => Value::Float(voltages[1]),
-                        }?;
-
-                        let target = f.eval_number_with_context(&context)?;
-                        let target = mm_to_steps(target);
-
-                        return Ok(target);
-                    }
-                });
-
-                run(
-                    state,
-                    &mut port,
-                    &mut adcs,
-                    &mut funcs_read_voltage,
-                    funcs_voltage_to_target,
-                    get_pos_zaber,
-                    [move_coax_zaber, move_cross_zaber],
-                )
+                run(state, backend, voltage_source, &mut Formulas::new(&config)?)
+            }
+
+            utils::ControlMode::ClosedLoop => {
+                tracing::debug!("starting in control mode ClosedLoop");
+                run(state, backend, voltage_source, &mut Formulas::new(&config)?)
+            }
+
+            utils::ControlMode::Sequence => {
+                tracing::debug!("starting in control mode Sequence");
+                let waypoints = state.sequence.read().unwrap().clone();
+                let mut player = SequencePlayer::new(
+                    waypoints,
+                    config.axis_names(),
+                    config.sequence_repetitions,
+                )?;
+                run(state, backend, voltage_source, &mut player)
+            }
+
+            utils::ControlMode::Waveform => {
+                tracing::debug!("starting in control mode Waveform");
+                let mut waveform = Waveform::new(
+                    config.axes.iter().map(|axis| axis.waveform.clone()).collect(),
+                )?;
+                run(state, backend, voltage_source, &mut waveform)
             }
         };
 
+        // The loop must not be restarted with a new mode after an emergency stop
+        if state.estop.triggered().is_some() {
+            return result;
+        }
+
         // If only the control mode changes,
         // zaber does not need to re-initalized.
         let config_current = state.config.read().unwrap();
@@ -197,82 +441,471 @@ where
     }
 }
 
-pub fn run<'a, T, V: Send + Sync>(
-    mut state: &mut ExecState,
-    mut backend: &mut T,
-    adcs: &mut [V; 2],
-    funcs_read_voltage: &mut [fn(&mut V) -> Result<f64>; 2],
-    funcs_voltage_to_target: [impl Fn(&[f64; 2]) -> Result<u32>; 2],
-    func_get_pos: fn(&mut T) -> Result<([bool; 2], [u32; 2])>,
-    funcs_move: [fn(&mut T, u32) -> Result<()>; 2],
+/// Data of the control loop which is kept between cycles, the per-axis values are in the
+/// order of the configured axes.
+#[derive(Debug)]
+pub struct LoopState {
+    pub limits: Vec<[u32; 2]>,
+    pub limit_policies: Vec<LimitPolicy>,
+    /// Disabled axes are not moved.
+    pub enabled: Vec<bool>,
+    /// Only set in `ControlMode::ClosedLoop`.
+    pub pids: Option<Vec<Pid>>,
+    /// The last velocity sent to the axes in `ControlMode::ClosedLoop`.
+    pub velocity: Vec<i32>,
+    pub filters: [FilterChain; 2],
+    /// Only set in `ControlMode::Tracking` if a predictor is configured.
+    pub predictors: Option<Vec<Predictor>>,
+    /// Configured latency the targets are extrapolated by, the measured one if zero.
+    pub predictor_latency: Duration,
+    /// Only set in `ControlMode::Tracking` and `ControlMode::ClosedLoop`.
+    pub transition: Option<Transition>,
+    pub deadbands: Vec<Deadband>,
+    /// The max. speeds set in the config.
+    pub maxspeed_default: Vec<u32>,
+    /// The max. speeds currently set on the axes.
+    pub maxspeed: Vec<u32>,
+    pub retry: RetryPolicy,
+    /// Not set if the following error watchdog is disabled.
+    pub watchdog: Option<Watchdog>,
+    /// The targets the axes were last commanded to, in microsteps.
+    pub commanded: Vec<Option<u32>>,
+    pub timer: CycleTimer,
+    pub time_last: Option<Instant>,
+    /// Time since the start of the control loop in seconds.
+    pub time: f64,
+}
+
+impl LoopState {
+    pub fn new(config: &Config) -> Self {
+        let axes = &config.axes;
+        let pids = match config.control_mode {
+            ControlMode::ClosedLoop => Some(
+                axes.iter()
+                    .map(|axis| Pid::new(axis.kp, axis.ki, axis.kd, axis.maxspeed_steps() as f64))
+                    .collect(),
+            ),
+            _ => None,
+        };
+
+        Self {
+            limits: axes.iter().map(|axis| axis.limits_steps()).collect(),
+            limit_policies: axes.iter().map(|axis| axis.limit_policy.clone()).collect(),
+            enabled: axes.iter().map(|axis| axis.enabled).collect(),
+            pids,
+            velocity: vec![0; axes.len()],
+            filters: [
+                FilterChain::new(&config.filters_v1),
+                FilterChain::new(&config.filters_v2),
+            ],
+            predictors: match (&config.control_mode, &config.predictor) {
+                (ControlMode::Tracking, Some(predictor)) => Some(
+                    axes.iter()
+                        .map(|_| Predictor::new(predictor.clone()))
+                        .collect(),
+                ),
+                _ => None,
+            },
+            predictor_latency: config.predictor_latency_ms,
+            transition: match config.control_mode {
+                ControlMode::Tracking | ControlMode::ClosedLoop => Some(Transition::new(
+                    config.transition_time_ms,
+                    config.transition_speed,
+                )),
+                _ => None,
+            },
+            deadbands: axes
+                .iter()
+                .map(|axis| Deadband::new(mm_to_steps(axis.deadband), mm_to_steps(axis.hysteresis)))
+                .collect(),
+            maxspeed_default: axes.iter().map(|axis| axis.maxspeed_steps()).collect(),
+            maxspeed: axes.iter().map(|axis| axis.maxspeed_steps()).collect(),
+            retry: RetryPolicy::new(config),
+            watchdog: match config.watchdog_enabled {
+                true => Some(Watchdog::new(
+                    config.axis_names(),
+                    mm_to_steps(config.watchdog_tolerance),
+                    config.watchdog_timeout_ms,
+                    config.watchdog_stop,
+                )),
+                false => None,
+            },
+            commanded: vec![None; axes.len()],
+            timer: CycleTimer::new(config.cycle_time_ms),
+            time_last: None,
+            time: 0.,
+        }
+    }
+
+    /// Applies the settings of `new` which can be changed while the control loop runs.
+    /// Filters, deadbands, predictors and the watchdog keep their state unless their
+    /// settings changed.
+    pub fn reload(&mut self, old: &Config, new: &Config) {
+        let LoopState {
+            limits,
+            limit_policies,
+            deadbands,
+            maxspeed_default,
+            retry,
+            watchdog,
+            filters: [filter_v1, filter_v2],
+            predictors,
+            predictor_latency,
+            transition,
+            ..
+        } = LoopState::new(new);
+
+        self.limits = limits;
+        self.limit_policies = limit_policies;
+        self.maxspeed_default = maxspeed_default;
+        self.retry = retry;
+        self.predictor_latency = predictor_latency;
+        // A running transition continues with the new settings
+        if let (Some(current), Some(transition)) = (&mut self.transition, transition) {
+            current.duration = transition.duration;
+            current.speed = transition.speed;
+        }
+
+        if let Some(pids) = &mut self.pids {
+            for (pid, axis) in pids.iter_mut().zip(&new.axes) {
+                pid.kp = axis.kp;
+                pid.ki = axis.ki;
+                pid.kd = axis.kd;
+                pid.output_limit = axis.maxspeed_steps() as f64;
+            }
+        }
+        let axes = old.axes.iter().zip(&new.axes);
+        for ((current, deadband), (a, b)) in self.deadbands.iter_mut().zip(deadbands).zip(axes) {
+            if (a.deadband, a.hysteresis) != (b.deadband, b.hysteresis) {
+                *current = deadband;
+            }
+        }
+        if old.filters_v1 != new.filters_v1 {
+            self.filters[0] = filter_v1;
+        }
+        if old.filters_v2 != new.filters_v2 {
+            self.filters[1] = filter_v2;
+        }
+        if old.predictor != new.predictor {
+            self.predictors = predictors;
+        }
+        let watchdog_config = |c: &Config| {
+            (
+                c.watchdog_enabled,
+                c.watchdog_tolerance,
+                c.watchdog_timeout_ms,
+                c.watchdog_stop,
+            )
+        };
+        if watchdog_config(old) != watchdog_config(new) {
+            self.watchdog = watchdog;
+        }
+    }
+}
+
+pub fn run(
+    state: &mut ExecState,
+    backend: &mut dyn Backend,
+    voltage_source: &mut dyn VoltageSource,
+    target_source: &mut dyn TargetSource,
 ) -> Result<()> {
-    let config = state.config.read().unwrap();
-    let cycle_time = config.cycle_time_ms;
-    let limits = [
-        [config.limit_min_coax, config.limit_max_coax],
-        [config.limit_min_cross, config.limit_max_cross],
-    ];
-    drop(config);
+    let config = { state.config.read().unwrap().clone() };
+    let mut loop_state = LoopState::new(&config);
 
     tracing::info!("Starting control loop");
-    loop {
-        compute_control::<T, V>(
-            &mut state,
-            &mut backend,
-            adcs,
-            funcs_read_voltage,
-            &funcs_voltage_to_target,
-            func_get_pos,
-            &funcs_move,
-            &limits,
-        )?;
+    let result = run_loop(state, backend, voltage_source, target_source, &mut loop_state, config);
 
-        if let Ok(_) = state.rx_stop.recv_timeout(cycle_time) {
-            break;
-        }
+    // The axes are stopped and reset on every exit, an error of the loop takes
+    // precedence over an error of the cleanup.
+    let cleanup = stop_loop(backend, &loop_state);
+    if let (Err(_), Err(e)) = (&result, &cleanup) {
+        tracing::error!("Failed to stop the axes after an error: {:?}", e);
     }
+    result?;
+    cleanup?;
 
     tracing::info!("Control loop stopped");
     return Ok(());
 }
 
+fn run_loop(
+    mut state: &mut ExecState,
+    backend: &mut dyn Backend,
+    voltage_source: &mut dyn VoltageSource,
+    target_source: &mut dyn TargetSource,
+    loop_state: &mut LoopState,
+    mut config: Config,
+) -> Result<()> {
+    let cycle_time = config.cycle_time_ms;
+    // The cycles are started at absolute deadlines, so the period does not drift
+    // with the time spent in `compute_control`.
+    let mut deadline = Instant::now();
+    // Changes signalled before the start are already part of the config
+    while state.rx_reload.try_recv().is_ok() {}
+    // The latch might have been set while homing or switching the mode
+    check_estop(state, backend)?;
+    loop {
+        if state.rx_reload.try_recv().is_ok() {
+            let config_new = { state.config.read().unwrap().clone() };
+            loop_state.reload(&config, &config_new);
+            target_source.reload(&config_new)?;
+            config = config_new;
+            tracing::info!("Reloaded the config of the running control loop");
+        }
+
+        compute_control(&mut state, backend, voltage_source, target_source, loop_state)?;
+
+        let now = Instant::now();
+        let (deadline_next, overrun) = next_deadline(deadline, cycle_time, now);
+        if overrun {
+            loop_state.timer.push_overrun();
+        }
+        // Every overrun would flood the log and the history, they are summarized instead
+        if let Some(count) = loop_state.timer.overruns_due(now) {
+            let message = format!("Control cycle overran the cycle time of {:?}", cycle_time);
+            tracing::warn!("{} {} times", message, count);
+            state.history.push_warnings(None, &message, count);
+        }
+        deadline = deadline_next;
+
+        crossbeam_channel::select! {
+            recv(state.rx_stop) -> _ => return Ok(()),
+            recv(state.estop.receiver()) -> _ => check_estop(state, backend)?,
+            default(deadline.saturating_duration_since(Instant::now())) => (),
+        }
+    }
+}
+
+/// Stops the axes moving with a constant velocity and restores the default max. speeds.
+/// All axes are attempted, the first error is returned.
+fn stop_loop(backend: &mut dyn Backend, loop_state: &LoopState) -> Result<()> {
+    let mut result = Ok(());
+    // Axes moving with a constant velocity would not stop by themselves
+    if loop_state.pids.is_some() {
+        for i in 0..loop_state.enabled.len() {
+            if loop_state.enabled[i] {
+                result = result.and(backend.move_vel(i, 0));
+            }
+        }
+    }
+
+    for i in 0..loop_state.maxspeed.len() {
+        if loop_state.maxspeed[i] != loop_state.maxspeed_default[i] {
+            result = result.and(backend.set_maxspeed(i, loop_state.maxspeed_default[i]));
+        }
+    }
+
+    result
+}
+
 #[inline]
-pub fn compute_control<'a, T, V: Send + Sync>(
+pub fn compute_control(
     state: &mut ExecState,
-    backend: &mut T,
-    adcs: &mut [V; 2],
-    funcs_read_voltage: &mut [fn(&mut V) -> Result<f64>; 2],
-    funcs_voltage_to_target: &[impl Fn(&[f64; 2]) -> Result<u32>; 2],
-    func_get_pos: fn(&mut T) -> Result<([bool; 2], [u32; 2])>,
-    funcs_move: &[fn(&mut T, u32) -> Result<()>; 2],
-    limits: &[[u32; 2]; 2],
+    backend: &mut dyn Backend,
+    voltage_source: &mut dyn VoltageSource,
+    target_source: &mut dyn TargetSource,
+    loop_state: &mut LoopState,
 ) -> Result<()> {
-    // TODO(marco): Try to get [f64; 2] without Vec
-    let voltage_readings: Vec<Result<f64>> = adcs
-        .par_iter_mut()
-        .enumerate()
-        .map(|(i, adc)| funcs_read_voltage[i](adc))
-        .collect();
-
-    let (is_busy, positions) = func_get_pos(backend)?;
-
-    // Just to convert into [f64; 2]
-    let mut voltages = [0.; 2];
-    for (i, v) in voltage_readings.into_iter().enumerate() {
-        voltages[i] = v?;
-    }
-
-    for i in 0..2 {
-        let target = funcs_voltage_to_target[i](&voltages)?;
-        state.shared.position[i] = positions[i];
-        state.shared.is_busy[i] = is_busy[i];
-        state.shared.voltage[i] = voltages[i];
+    let now = Instant::now();
+    let dt = match loop_state.time_last {
+        Some(time_last) => {
+            let period = now.duration_since(time_last);
+            loop_state.timer.push_period(period);
+            period.as_secs_f64()
+        }
+        None => 0.,
+    };
+    loop_state.time_last = Some(now);
+    loop_state.time += dt;
+
+    let voltages_raw = voltage_source
+        .read_voltages()
+        .map_err(|e| ControlError::Adc.wrap(e))?;
+    let time_adc = Instant::now();
+    loop_state.timer.adc.push(time_adc - now);
+
+    let retry = loop_state.retry;
+    let errors_recovered = state.shared.errors_recovered;
+    let (is_busy, positions) = retry.run(&mut state.shared.errors_recovered, || backend.get_pos())?;
+    let time_get_pos = Instant::now();
+    loop_state.timer.get_pos.push(time_get_pos - time_adc);
+
+    let voltages: [f64; 2] =
+        std::array::from_fn(|i| loop_state.filters[i].apply(voltages_raw[i], dt));
+
+    let cycle = CycleData {
+        voltages,
+        positions,
+        is_busy,
+        time: loop_state.time,
+        dt,
+    };
+    let targets_raw = target_source
+        .get_targets(&cycle)
+        .map_err(|e| ControlError::Formula { axis: None }.wrap(e))?;
+    let n = loop_state.enabled.len();
+    if targets_raw.len() != n {
+        return Err(anyhow!("Got {} targets for {} axes", targets_raw.len(), n));
+    }
+    let targets: Vec<u32> = match &mut loop_state.predictors {
+        Some(predictors) => {
+            let latency = match loop_state.predictor_latency.is_zero() {
+                true => loop_state.timer.latency(),
+                false => loop_state.predictor_latency.as_secs_f64(),
+            };
+            predictors
+                .iter_mut()
+                .zip(&targets_raw)
+                .map(|(predictor, &target)| predictor.predict(target, dt, latency))
+                .collect()
+        }
+        None => targets_raw.clone(),
+    };
+    let targets = match &mut loop_state.transition {
+        Some(transition) => transition.apply(&targets, &cycle.positions, dt),
+        None => targets,
+    };
+    let maxspeeds = target_source.maxspeeds();
+    target_source.publish(&mut state.shared);
+    // No move must be sent after an emergency stop triggered during this cycle
+    check_estop(state, backend)?;
+    let time_formula = Instant::now();
+    loop_state.timer.formula.push(time_formula - time_get_pos);
+
+    state.shared.voltage = voltages;
+    state.shared.voltage_raw = voltages_raw;
+    for i in 0..n {
+        let target = targets[i];
+        state.shared.position[i] = cycle.positions[i];
+        state.shared.is_busy[i] = cycle.is_busy[i];
         state.shared.target[i] = target;
+        state.shared.target_raw[i] = targets_raw[i];
 
-        tracing::debug!("Position {}: target={} actual={}", i, target, positions[i]);
+        tracing::debug!("Position {}: target={} actual={}", i, target, cycle.positions[i]);
 
-        if target > limits[i][0] && target < limits[i][1] && target != positions[i] {
-            (funcs_move[i])(backend, target)?;
+        if !loop_state.enabled[i] {
+            continue;
+        }
+
+        let [limit_min, limit_max] = loop_state.limits[i];
+        let in_limits = (limit_min..=limit_max).contains(&target);
+        let was_in_limits = !state.shared.out_of_limits[i];
+        state.shared.out_of_limits[i] = !in_limits;
+        let target = match in_limits {
+            true => Some(target),
+            false => {
+                state.shared.limit_violations[i] += 1;
+                let name = &state.shared.axes[i];
+                tracing::debug!("Target {} of axis {} is outside of the limits", target, name);
+                // Every excursion is recorded once, not every cycle of it
+                if was_in_limits && loop_state.limit_policies[i] != LimitPolicy::Fault {
+                    let kind = ControlError::Limit {
+                        axis: name.clone(),
+                        target: steps_to_mm(target),
+                        min: steps_to_mm(limit_min),
+                        max: steps_to_mm(limit_max),
+                    };
+                    let message = format!("Target of axis {} is outside of the limits", name);
+                    state.history.push_warning(Some(&kind), &message);
+                }
+                match loop_state.limit_policies[i] {
+                    LimitPolicy::Clamp => Some(target.max(limit_min).min(limit_max)),
+                    LimitPolicy::Hold => None,
+                    LimitPolicy::Fault => {
+                        return Err(ControlError::Limit {
+                            axis: name.clone(),
+                            target: steps_to_mm(target),
+                            min: steps_to_mm(limit_min),
+                            max: steps_to_mm(limit_max),
+                        }
+                        .into());
+                    }
+                }
+            }
+        };
+
+        match &mut loop_state.pids {
+            Some(pids) => {
+                // Holding keeps the last setpoint, or the position if there is none yet
+                let setpoint = target
+                    .or(loop_state.commanded[i])
+                    .unwrap_or(cycle.positions[i])
+                    .max(limit_min)
+                    .min(limit_max);
+                let velocity = pids[i]
+                    .update(setpoint as f64, cycle.positions[i] as f64, dt)
+                    .round() as i32;
+
+                loop_state.commanded[i] = Some(setpoint);
+                if velocity != loop_state.velocity[i] {
+                    retry.run(&mut state.shared.errors_recovered, || {
+                        backend.move_vel(i, velocity)
+                    })?;
+                    loop_state.velocity[i] = velocity;
+                    state.shared.moves_issued[i] += 1;
+                }
+            }
+            None => {
+                if let Some(target) = target {
+                    let maxspeed = maxspeeds
+                        .get(i)
+                        .copied()
+                        .flatten()
+                        .unwrap_or(loop_state.maxspeed_default[i]);
+                    if maxspeed != loop_state.maxspeed[i] {
+                        retry.run(&mut state.shared.errors_recovered, || {
+                            backend.set_maxspeed(i, maxspeed)
+                        })?;
+                        loop_state.maxspeed[i] = maxspeed;
+                    }
+
+                    if loop_state.deadbands[i].check(target) {
+                        retry.run(&mut state.shared.errors_recovered, || {
+                            backend.move_abs(i, target)
+                        })?;
+                        loop_state.commanded[i] = Some(target);
+                        state.shared.moves_issued[i] += 1;
+                    } else {
+                        state.shared.moves_suppressed[i] += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    let time_moves = Instant::now();
+    loop_state.timer.moves.push(time_moves - time_formula);
+    if let Some(report) = loop_state.timer.report_due(time_moves) {
+        state.shared.timing = report;
+    }
+
+    if state.shared.errors_recovered > errors_recovered {
+        let kind = ControlError::ZaberComm {
+            device: None,
+            axis: None,
+        };
+        state.history.push_warning(Some(&kind), "A Zaber command succeeded after a retry");
+    }
+
+    for i in 0..n {
+        state.shared.following_error[i] = loop_state.commanded[i]
+            .map(|commanded| commanded.abs_diff(cycle.positions[i]))
+            .unwrap_or(0);
+    }
+
+    if let Some(watchdog) = &mut loop_state.watchdog {
+        if let Err(e) = watchdog.check(&loop_state.commanded, &cycle.positions, dt) {
+            if watchdog.stop {
+                tracing::error!("{}", e);
+                if let Err(e_stop) = backend.stop() {
+                    tracing::error!("Failed to stop the axes: {:#}", e_stop);
+                }
+                return Err(ControlError::FollowingError(e).into());
+            }
+            // Without stopping, the fault is only reported and the loop keeps running
+            tracing::warn!("{}", e);
+            let message = e.to_string();
+            state.history.push_warning(Some(&ControlError::FollowingError(e)), &message);
         }
     }
 
@@ -299,27 +932,20 @@ pub fn read_voltage(adc: &mut Adc) -> Result<f64> {
 mod tests {
     use std::{sync::RwLock, time::Duration};
 
-    use chrono::Local;
     use crossbeam_channel::bounded;
-    use utils::{Config, SharedState};
+    use utils::{AxisConfig, Config, Estop, SharedState};
 
-    use crate::utils::ControlStatus;
+    use crate::history::ErrorHistory;
+    use crate::zaber::{steps_to_accel, steps_to_vel};
 
     use super::*;
 
     fn prepare_state() -> ExecState {
         let (_tx_stop, rx_stop) = bounded::<()>(1);
         let (_tx_start, _rx_start) = bounded::<()>(1);
-        let target_manual = Arc::new(RwLock::new([0; 2]));
-        let shared_state = SharedState {
-            target: [0; 2],
-            position: [0; 2],
-            is_busy: [false; 2],
-            control_state: ControlStatus::Stopped,
-            error: None,
-            timestamp: Local::now(),
-            voltage: [0.; 2],
-        };
+        let (_tx_reload, rx_reload) = bounded::<()>(1);
+        let target_manual = Arc::new(RwLock::new(vec![0; 2]));
+        let shared_state = SharedState::new(&Config::default().axes);
         let state_channel = Arc::new(RwLock::new(shared_state.clone()));
 
         let state = ExecState {
@@ -329,73 +955,289 @@ mod tests {
                 cycle_time_ms: Duration::from_millis(1),
                 opcua_config_path: "".into(),
                 control_mode: utils::ControlMode::Tracking,
-                limit_max_coax: 1000,
-                limit_min_coax: 0,
-                maxspeed_coax: 10000,
-                accel_coax: 10000,
-                offset_coax: 0,
-                limit_max_cross: 1000,
-                limit_min_cross: 0,
-                maxspeed_cross: 10000,
-                accel_cross: 100000,
-                mock_zaber: true,
-                mock_adc: true,
-                formula_coax: "v1 + v2".into(),
-                formula_cross: "v1 + v2".into(),
+                backend: "simulator".into(),
+                voltage_source: "mock".into(),
                 web_port: 0,
+                axes: vec![
+                    AxisConfig {
+                        lockstep: Some(1),
+                        limit_max: steps_to_mm(1000),
+                        maxspeed: steps_to_vel(10000),
+                        accel: steps_to_accel(10000),
+                        formula: "v1 + v2".into(),
+                        ..AxisConfig::new("coax", 1)
+                    },
+                    AxisConfig {
+                        limit_max: steps_to_mm(1000),
+                        maxspeed: steps_to_vel(10000),
+                        accel: steps_to_accel(100000),
+                        formula: "v1 + v2".into(),
+                        ..AxisConfig::new("cross", 2)
+                    },
+                ],
+                ..Config::default()
             })),
             rx_stop,
+            rx_reload,
+            estop: Estop::new(),
+            history: ErrorHistory::default(),
             target_manual,
+            sequence: Arc::new(RwLock::new(Vec::new())),
             out_channel: state_channel,
         };
 
         return state;
     }
 
-    // #[test]
+    /// Backend whose axes never move, records the commands sent to it.
+    struct Recorder {
+        positions: Vec<u32>,
+        commands: Vec<String>,
+    }
+
+    impl Recorder {
+        fn new(n: usize) -> Self {
+            Self {
+                positions: vec![0; n],
+                commands: Vec::new(),
+            }
+        }
+    }
+
+    impl Backend for Recorder {
+        fn get_pos(&mut self) -> Result<(Vec<bool>, Vec<u32>)> {
+            Ok((vec![false; self.positions.len()], self.positions.clone()))
+        }
+
+        fn move_abs(&mut self, axis: usize, target: u32) -> Result<()> {
+            self.commands.push(format!("move_abs {} {}", axis, target));
+            Ok(())
+        }
+
+        fn move_vel(&mut self, axis: usize, velocity: i32) -> Result<()> {
+            self.commands.push(format!("move_vel {} {}", axis, velocity));
+            Ok(())
+        }
+
+        fn set_maxspeed(&mut self, axis: usize, speed: u32) -> Result<()> {
+            self.commands.push(format!("set_maxspeed {} {}", axis, speed));
+            Ok(())
+        }
+
+        fn stop(&mut self) -> Result<()> {
+            self.commands.push("stop".into());
+            Ok(())
+        }
+
+        fn estop(&mut self) -> Result<()> {
+            self.commands.push("estop".into());
+            Ok(())
+        }
+    }
+
+    #[test]
     fn test_run_stop() {
         let mut state = prepare_state();
-
         let config = { state.config.read().unwrap().clone() };
-        {
-            let mut out = state.out_channel.write().unwrap();
-            *out = state.shared.clone();
-        }
-        let mut port = init_zaber_mock(&config).unwrap();
-
-        let funcs_voltage_to_target = [
-            evalexpr::build_operator_tree(&config.formula_cross).unwrap(),
-            evalexpr::build_operator_tree(&config.formula_coax).unwrap(),
-        ]
-        .map(|f: evalexpr::Node<evalexpr::DefaultNumericTypes>| {
-            move |voltages: &[f64; 2]| {
-                let context = evalexpr::context_map! {
-                    "v1" => Value::Float(voltages[0]),
-                    "v2" => Value::Float(voltages[1]),
-                }?;
-
-                let target = f.eval_number_with_context(&context)?;
-                let target = mm_to_steps(target);
-
-                return Ok(target);
-            }
+        let mut backend = create_backend(&config, &state.estop).unwrap();
+        let mut voltages: [f64; 2] = [0., 0.];
+        let mut formulas = Formulas::new(&config).unwrap();
+        let (tx_stop, rx_stop) = bounded::<()>(1);
+        state.rx_stop = rx_stop;
+
+        // The loop runs until it is stopped
+        tx_stop.send(()).unwrap();
+        run(&mut state, backend.as_mut(), &mut voltages, &mut formulas).unwrap();
+
+        // A latched emergency stop ends the loop before anything is moved
+        state.estop.trigger("test");
+        let moves_issued = state.shared.moves_issued.clone();
+        let error = run(&mut state, backend.as_mut(), &mut voltages, &mut formulas).unwrap_err();
+        assert_eq!(ControlError::from_error(&error).code(), 600);
+        assert_eq!(state.shared.moves_issued, moves_issued);
+
+        // Triggered while running, the loop exits right away
+        state.estop.reset().unwrap();
+        let estop = state.estop.clone();
+        let trigger = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            estop.trigger("thread");
         });
-        run(
-            &mut state,
-            &mut port,
-            &mut [0., 0.],
-            &mut [read_voltage_mock, read_voltage_mock],
-            funcs_voltage_to_target,
-            get_pos_zaber,
-            [move_coax_zaber, move_cross_zaber],
-        )
-        .unwrap();
+        let error = run(&mut state, backend.as_mut(), &mut voltages, &mut formulas).unwrap_err();
+        trigger.join().unwrap();
+        assert!(matches!(
+            ControlError::from_error(&error),
+            ControlError::EmergencyStop { source } if source == "thread"
+        ));
+    }
+
+    #[test]
+    fn test_run_error() {
+        let mut state = prepare_state();
+        let config = Config {
+            control_mode: ControlMode::ClosedLoop,
+            ..state.config.read().unwrap().clone()
+        };
+        *state.config.write().unwrap() = config.clone();
+        let mut backend = Recorder::new(2);
+        let mut voltages: [f64; 2] = [0., 0.];
+        let mut failing = vec![|_: &CycleData| -> Result<u32> { Err(anyhow!("failed")) }; 2];
+
+        // The axes are stopped although the loop exits with an error
+        let error = run(&mut state, &mut backend, &mut voltages, &mut failing).unwrap_err();
+        assert_eq!(ControlError::from_error(&error).code(), 300);
+        assert_eq!(backend.commands, vec!["move_vel 0 0", "move_vel 1 0"]);
+    }
+
+    #[test]
+    fn test_watchdog_stop() {
+        let mut state = prepare_state();
+        let config = Config {
+            watchdog_enabled: true,
+            watchdog_tolerance: 0.1,
+            watchdog_timeout_ms: Duration::from_millis(1),
+            watchdog_stop: false,
+            ..state.config.read().unwrap().clone()
+        };
+        let mut voltages: [f64; 2] = [0., 0.];
+        let mut far = vec![|_: &CycleData| -> Result<u32> { Ok(1000) }; 2];
+        let history = state.history.clone();
+
+        let mut cycles = |backend: &mut Recorder, config: &Config| -> Result<()> {
+            let mut loop_state = LoopState::new(config);
+            loop_state.transition = None;
+            for _ in 0..5 {
+                std::thread::sleep(Duration::from_millis(2));
+                compute_control(&mut state, backend, &mut voltages, &mut far, &mut loop_state)?;
+            }
+            Ok(())
+        };
+
+        // Without stopping, the blocked axes are only reported
+        let mut backend = Recorder::new(2);
+        cycles(&mut backend, &config).unwrap();
+        assert!(!backend.commands.iter().any(|c| c == "stop" || c == "estop"));
+        assert!(history.entries().iter().any(|e| e.code == Some(401)));
+
+        let config = Config {
+            watchdog_stop: true,
+            ..config
+        };
+        let mut backend = Recorder::new(2);
+        let error = cycles(&mut backend, &config).unwrap_err();
+        assert_eq!(ControlError::from_error(&error).code(), 401);
+        assert_eq!(backend.commands.last().unwrap(), "stop");
     }
 
     #[test]
-    fn testing() {
-        let a = 1 + 2;
+    fn test_safe_stop() {
+        let state = prepare_state();
+        let config = { state.config.read().unwrap().clone() };
+        let mut backend = BackendGuard::new(create_backend(&config, &Estop::new()).unwrap());
+
+        backend.as_mut().move_abs(0, 900).unwrap();
+        assert!(backend.safe_stop(false, Duration::from_secs(1)));
+
+        let (is_busy, _) = backend.as_mut().get_pos().unwrap();
+        assert_eq!(is_busy, vec![false; 2]);
+    }
+
+    #[test]
+    fn test_limit_policy() {
+        let mut state = prepare_state();
+        let mut config = { state.config.read().unwrap().clone() };
+        config.axes[0].limit_policy = LimitPolicy::Clamp;
+        config.axes[1].limit_policy = LimitPolicy::Hold;
+        let mut backend = create_backend(&config, &Estop::new()).unwrap();
+        let mut loop_state = LoopState::new(&config);
+        let mut voltages: [f64; 2] = [0., 0.];
+        let history = state.history.clone();
+        let mut beyond = vec![|_: &CycleData| -> Result<u32> { Ok(2000) }; 2];
+        let mut at_limit = vec![|_: &CycleData| -> Result<u32> { Ok(1000) }; 2];
+
+        let mut cycle = |targets: &mut dyn TargetSource, loop_state: &mut LoopState| {
+            compute_control(&mut state, backend.as_mut(), &mut voltages, targets, loop_state)
+                .map(|_| state.shared.clone())
+        };
+
+        let shared = cycle(&mut beyond, &mut loop_state).unwrap();
+        assert_eq!(loop_state.commanded, vec![Some(1000), None]);
+        assert_eq!(shared.out_of_limits, vec![true; 2]);
+        assert_eq!(shared.limit_violations, vec![1; 2]);
+        // Only the start of an excursion is recorded
+        cycle(&mut beyond, &mut loop_state).unwrap();
+        let warnings = history.entries();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!((warnings[0].code, warnings[0].count), (Some(400), 1));
+
+        // Targets at the limits are valid
+        let shared = cycle(&mut at_limit, &mut loop_state).unwrap();
+        assert_eq!(loop_state.commanded, vec![Some(1000); 2]);
+        assert_eq!(shared.out_of_limits, vec![false; 2]);
+        assert_eq!(shared.limit_violations, vec![2; 2]);
+
+        loop_state.limit_policies[1] = LimitPolicy::Fault;
+        let error = cycle(&mut beyond, &mut loop_state).unwrap_err();
+        assert!(matches!(
+            ControlError::from_error(&error),
+            ControlError::Limit { axis, .. } if axis == "cross"
+        ));
+    }
+
+    #[test]
+    fn test_reload() {
+        let state = prepare_state();
+        let config = { state.config.read().unwrap().clone() };
+        let mut loop_state = LoopState::new(&config);
+
+        let mut config_new = config.clone();
+        config_new.axes[0].limit_max = steps_to_mm(500);
+        config_new.axes[1].limit_policy = LimitPolicy::Fault;
+        config_new.axes[1].maxspeed = steps_to_vel(5000);
+        loop_state.reload(&config, &config_new);
+
+        assert_eq!(loop_state.limits, vec![[0, 500], [0, 1000]]);
+        assert_eq!(loop_state.limit_policies, vec![LimitPolicy::Hold, LimitPolicy::Fault]);
+        assert_eq!(loop_state.maxspeed_default, vec![10000, 5000]);
+        // The speed set on the axes is changed by the next cycle
+        assert_eq!(loop_state.maxspeed, vec![10000; 2]);
+    }
+
+    #[test]
+    fn test_transition_default() {
+        let mut state = prepare_state();
+        let config = { state.config.read().unwrap().clone() };
+        let mut backend = create_backend(&config, &Estop::new()).unwrap();
+        let mut loop_state = LoopState::new(&config);
+        let mut voltages: [f64; 2] = [0., 0.];
+        let mut far = vec![|_: &CycleData| -> Result<u32> { Ok(1000) }; 2];
+
+        // The first cycle keeps the axes where they are instead of jumping to the formulas
+        compute_control(&mut state, backend.as_mut(), &mut voltages, &mut far, &mut loop_state)
+            .unwrap();
+        assert_eq!(state.shared.target_raw, vec![1000; 2]);
+        assert_eq!(state.shared.target, state.shared.position);
+        assert_ne!(state.shared.target, vec![1000; 2]);
+    }
+
+    #[test]
+    fn test_single_axis() {
+        let state = prepare_state();
+        let config = Config {
+            axes: vec![AxisConfig {
+                axis: 2,
+                ..AxisConfig::new("focus", 3)
+            }],
+            ..state.config.read().unwrap().clone()
+        };
+        let mut backend = create_backend(&config, &Estop::new()).unwrap();
+
+        backend.move_abs(0, 500).unwrap();
+        assert!(backend.move_abs(1, 500).is_err());
+        assert!(safe_stop(backend.as_mut(), false, Duration::from_secs(1)));
 
-        assert_eq!(a, 3);
+        let (is_busy, positions) = backend.get_pos().unwrap();
+        assert_eq!(is_busy, vec![false]);
+        assert_eq!(positions.len(), 1);
     }
 }
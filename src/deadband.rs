@@ -0,0 +1,84 @@
+/// Suppresses moves for target changes within a band around the last commanded target.
+///
+/// Once the target left the band, the band shrinks by `hysteresis` so that a moving
+/// target is followed smoothly, and it is widened again as soon as the target settles.
+#[derive(Clone, Debug)]
+pub struct Deadband {
+    /// Half-width of the band in microsteps.
+    pub band: u32,
+    /// Reduction of the band in microsteps while the target is moving.
+    pub hysteresis: u32,
+    target_last: Option<u32>,
+    is_moving: bool,
+}
+
+impl Deadband {
+    pub fn new(band: u32, hysteresis: u32) -> Self {
+        Self {
+            band,
+            hysteresis,
+            target_last: None,
+            is_moving: false,
+        }
+    }
+
+    /// Returns `true` if a move to `target` should be issued.
+    /// The target is then remembered as the last commanded one.
+    pub fn check(&mut self, target: u32) -> bool {
+        let Some(target_last) = self.target_last else {
+            self.target_last = Some(target);
+            return true;
+        };
+
+        let band = match self.is_moving {
+            true => self.band.saturating_sub(self.hysteresis),
+            false => self.band,
+        };
+
+        if target.abs_diff(target_last) > band {
+            self.is_moving = true;
+            self.target_last = Some(target);
+            return true;
+        }
+
+        self.is_moving = false;
+        false
+    }
+
+    /// The last target a move was issued for.
+    pub fn target_last(&self) -> Option<u32> {
+        self.target_last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deadband;
+
+    #[test]
+    fn test_deadband_without_band() {
+        let mut deadband = Deadband::new(0, 0);
+        assert!(deadband.check(100));
+        assert!(!deadband.check(100));
+        assert!(deadband.check(101));
+    }
+
+    #[test]
+    fn test_deadband_hysteresis() {
+        let mut deadband = Deadband::new(10, 8);
+        assert!(deadband.check(100));
+        assert!(!deadband.check(105));
+        assert!(!deadband.check(110));
+
+        // Leaving the band, the moving target is followed with a band of 2
+        assert!(deadband.check(111));
+        assert!(deadband.check(114));
+        assert!(deadband.check(117));
+
+        // Settled target, the full band is used again
+        assert!(!deadband.check(118));
+        assert!(!deadband.check(125));
+        assert!(deadband.check(128));
+        assert_eq!(deadband.target_last(), Some(128));
+    }
+}
@@ -0,0 +1,229 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::{utils::ConfigErrors, watchdog::FollowingError, zaber::Rejected};
+
+/// How serious a fault is for the operation of the stage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Severity {
+    /// The control continues, e.g. after a recovered communication error.
+    Warning,
+    /// The control stopped and can be started again once the cause is fixed.
+    Error,
+    /// The control stopped because the stage might be blocked or unsafe,
+    /// the stage should be checked before it is started again.
+    Critical,
+}
+
+/// Cause of a failure of the control, attached to the `anyhow` errors where they occur,
+/// either as the error itself or as its context.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "category")]
+pub enum ControlError {
+    /// The voltages could not be read from the ADCs.
+    Adc,
+    /// A Zaber device did not reply or the reply was invalid.
+    ZaberComm {
+        device: Option<u8>,
+        axis: Option<String>,
+    },
+    /// A Zaber device rejected a command.
+    ZaberRejected {
+        device: Option<u8>,
+        axis: Option<String>,
+    },
+    /// The targets could not be computed.
+    Formula { axis: Option<String> },
+    /// A target exceeded the limits of an axis with the policy `LimitPolicy::Fault`.
+    Limit {
+        axis: String,
+        /// Target and limits in mm.
+        target: f64,
+        min: f64,
+        max: f64,
+    },
+    /// An axis lagged behind its target, see `Watchdog`.
+    FollowingError(FollowingError),
+    /// A setting is invalid, `field` is named like the field of the web form.
+    Config {
+        field: Option<String>,
+        message: String,
+    },
+    EmergencyStop { source: String },
+    /// Any other failure.
+    Internal,
+}
+
+impl ControlError {
+    /// Classifies an error of the communication with the Zaber device `device`, commands
+    /// answered with the flag `RJ` fail with `zaber::Rejected`.
+    pub fn zaber(error: &anyhow::Error, device: Option<u8>, axis: Option<&str>) -> Self {
+        let axis = axis.map(str::to_string);
+        match error.chain().any(|cause| cause.is::<Rejected>()) {
+            true => Self::ZaberRejected { device, axis },
+            false => Self::ZaberComm { device, axis },
+        }
+    }
+
+    /// Finds the cause of `error`, errors without one are `ControlError::Internal`.
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        if let Some(cause) = error.downcast_ref::<ControlError>() {
+            return cause.clone();
+        }
+        if let Some(errors) = error.downcast_ref::<ConfigErrors>() {
+            return Self::Config {
+                field: errors.0.first().map(|e| e.field.clone()),
+                message: errors.to_string(),
+            };
+        }
+        Self::Internal
+    }
+
+    /// Adds `self` as context to `error`, unless it already has a cause.
+    pub fn wrap(self, error: anyhow::Error) -> anyhow::Error {
+        match error.downcast_ref::<ControlError>().is_some() {
+            true => error,
+            false => error.context(self),
+        }
+    }
+
+    /// Stable number of the category, grouped by the hundreds.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::Adc => 100,
+            Self::ZaberComm { .. } => 200,
+            Self::ZaberRejected { .. } => 201,
+            Self::Formula { .. } => 300,
+            Self::Limit { .. } => 400,
+            Self::FollowingError(_) => 401,
+            Self::Config { .. } => 500,
+            Self::EmergencyStop { .. } => 600,
+            Self::Internal => 900,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::FollowingError(_) | Self::EmergencyStop { .. } => Severity::Critical,
+            _ => Severity::Error,
+        }
+    }
+
+    /// Name of the axis the error occurred on, if known.
+    pub fn axis(&self) -> Option<&str> {
+        match self {
+            Self::ZaberComm { axis, .. }
+            | Self::ZaberRejected { axis, .. }
+            | Self::Formula { axis } => axis.as_deref(),
+            Self::Limit { axis, .. } => Some(axis),
+            Self::FollowingError(e) => Some(&e.axis),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let device = |device: &Option<u8>| match device {
+            Some(device) => format!("Zaber device {}", device),
+            None => "the Zaber devices".to_string(),
+        };
+        match self {
+            Self::Adc => write!(f, "Reading the ADCs failed"),
+            Self::ZaberComm { device: d, .. } => {
+                write!(f, "Communication with {} failed", device(d))
+            }
+            Self::ZaberRejected { device: d, .. } => {
+                write!(f, "Command rejected by {}", device(d))
+            }
+            Self::Formula { axis: Some(axis) } => write!(f, "Formula of axis {}", axis),
+            Self::Formula { axis: None } => write!(f, "Computing the targets failed"),
+            Self::Limit {
+                axis,
+                target,
+                min,
+                max,
+            } => write!(
+                f,
+                "Target {:.3} mm of axis {} exceeds the limits {:.3}..{:.3} mm",
+                target, axis, min, max
+            ),
+            Self::FollowingError(e) => write!(f, "{}", e),
+            Self::Config {
+                field: Some(field),
+                message,
+            } => write!(f, "{}: {}", field, message),
+            Self::Config {
+                field: None,
+                message,
+            } => write!(f, "{}", message),
+            Self::EmergencyStop { source } => write!(f, "Emergency stop triggered by {}", source),
+            Self::Internal => write!(f, "Internal error"),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+/// Error of the control published in `SharedState`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ErrorReport {
+    pub kind: ControlError,
+    pub code: u16,
+    pub severity: Severity,
+    /// The error with all of its causes.
+    pub message: String,
+}
+
+impl ErrorReport {
+    pub fn new(error: &anyhow::Error) -> Self {
+        let kind = ControlError::from_error(error);
+        Self {
+            code: kind.code(),
+            severity: kind.severity(),
+            message: format!("{:#}", error),
+            kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::utils::Config;
+
+    #[test]
+    fn test_error_report() {
+        let error = anyhow!("timed out").context(ControlError::ZaberComm {
+            device: Some(2),
+            axis: Some("cross".into()),
+        });
+        let report = ErrorReport::new(&error);
+        assert_eq!(report.code, 200);
+        assert_eq!(report.kind.axis(), Some("cross"));
+        assert_eq!(report.message, "Communication with Zaber device 2 failed: timed out");
+
+        // The first cause is kept
+        let error = ControlError::Formula { axis: None }.wrap(error);
+        assert_eq!(ErrorReport::new(&error).code, 200);
+
+        let mut config = Config::default();
+        config.web_port = 0;
+        let report = ErrorReport::new(&config.validate().unwrap_err().into());
+        assert_eq!(report.kind.code(), 500);
+        assert_eq!(report.severity, Severity::Error);
+        assert_eq!(report.message, "web_port: Must be between 1 and 65535");
+
+        let error = anyhow::Error::new(Rejected {
+            reason: "BADCOMMAND".into(),
+        });
+        assert_eq!(ControlError::zaber(&error, Some(1), None).code(), 201);
+        // Only the flag of the reply counts, not the message
+        let error = anyhow!("Command rejected: BADCOMMAND");
+        assert_eq!(ControlError::zaber(&error, Some(1), None).code(), 200);
+        assert_eq!(ErrorReport::new(&anyhow!("panic")).kind, ControlError::Internal);
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+/// Configuration of a single filter stage applied to an ADC channel.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum FilterConfig {
+    /// Mean of the last `window` samples.
+    MovingAverage { window: usize },
+    /// Median of the last `window` samples.
+    Median { window: usize },
+    /// First-order low-pass with the cutoff frequency in Hz.
+    LowPass { cutoff_hz: f64 },
+    /// Replaces samples deviating more than `max_deviation` volts from the
+    /// median of the last `window` accepted samples with the last accepted sample.
+    OutlierRejection { window: usize, max_deviation: f64 },
+}
+
+#[derive(Clone, Debug)]
+enum Filter {
+    MovingAverage {
+        window: usize,
+        values: VecDeque<f64>,
+    },
+    Median {
+        window: usize,
+        values: VecDeque<f64>,
+    },
+    LowPass {
+        cutoff_hz: f64,
+        output: Option<f64>,
+    },
+    OutlierRejection {
+        window: usize,
+        max_deviation: f64,
+        values: VecDeque<f64>,
+        rejected: usize,
+    },
+}
+
+fn push_window(values: &mut VecDeque<f64>, window: usize, value: f64) {
+    values.push_back(value);
+    while values.len() > window.max(1) {
+        values.pop_front();
+    }
+}
+
+fn median(values: &VecDeque<f64>) -> f64 {
+    let mut sorted: Vec<f64> = values.iter().copied().collect();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let mid = sorted.len() / 2;
+    match sorted.len() {
+        0 => 0.,
+        n if n % 2 == 0 => (sorted[mid - 1] + sorted[mid]) / 2.,
+        _ => sorted[mid],
+    }
+}
+
+impl Filter {
+    fn new(config: &FilterConfig) -> Self {
+        match *config {
+            FilterConfig::MovingAverage { window } => Self::MovingAverage {
+                window,
+                values: VecDeque::with_capacity(window),
+            },
+            FilterConfig::Median { window } => Self::Median {
+                window,
+                values: VecDeque::with_capacity(window),
+            },
+            FilterConfig::LowPass { cutoff_hz } => Self::LowPass {
+                cutoff_hz,
+                output: None,
+            },
+            FilterConfig::OutlierRejection {
+                window,
+                max_deviation,
+            } => Self::OutlierRejection {
+                window,
+                max_deviation,
+                values: VecDeque::with_capacity(window),
+                rejected: 0,
+            },
+        }
+    }
+
+    fn apply(&mut self, value: f64, dt: f64) -> f64 {
+        match self {
+            Self::MovingAverage { window, values } => {
+                push_window(values, *window, value);
+                values.iter().sum::<f64>() / values.len() as f64
+            }
+            Self::Median { window, values } => {
+                push_window(values, *window, value);
+                median(values)
+            }
+            Self::LowPass { cutoff_hz, output } => {
+                let filtered = match *output {
+                    Some(output) if dt > 0. && *cutoff_hz > 0. => {
+                        let rc = 1. / (2. * std::f64::consts::PI * *cutoff_hz);
+                        let alpha = dt / (rc + dt);
+                        output + alpha * (value - output)
+                    }
+                    Some(output) => output,
+                    None => value,
+                };
+                *output = Some(filtered);
+                filtered
+            }
+            Self::OutlierRejection {
+                window,
+                max_deviation,
+                values,
+                rejected,
+            } => {
+                // A permanent jump of the signal must not be rejected forever,
+                // so after a full window of rejected samples the value is accepted.
+                if !values.is_empty()
+                    && (value - median(values)).abs() > *max_deviation
+                    && *rejected < *window
+                {
+                    *rejected += 1;
+                    return *values.back().unwrap();
+                }
+
+                if *rejected >= *window {
+                    values.clear();
+                }
+                *rejected = 0;
+                push_window(values, *window, value);
+                value
+            }
+        }
+    }
+}
+
+/// Chain of filters which are applied one after the other to the samples of one channel.
+#[derive(Clone, Debug)]
+pub struct FilterChain {
+    filters: Vec<Filter>,
+}
+
+impl FilterChain {
+    pub fn new(configs: &[FilterConfig]) -> Self {
+        Self {
+            filters: configs.iter().map(Filter::new).collect(),
+        }
+    }
+
+    /// Filters a new sample taken `dt` seconds after the previous one.
+    pub fn apply(&mut self, value: f64, dt: f64) -> f64 {
+        self.filters
+            .iter_mut()
+            .fold(value, |value, filter| filter.apply(value, dt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FilterChain, FilterConfig};
+
+    #[test]
+    fn test_moving_average() {
+        let mut chain = FilterChain::new(&[FilterConfig::MovingAverage { window: 3 }]);
+        assert_eq!(chain.apply(3., 0.), 3.);
+        assert_eq!(chain.apply(6., 0.), 4.5);
+        assert_eq!(chain.apply(9., 0.), 6.);
+        assert_eq!(chain.apply(0., 0.), 5.);
+    }
+
+    #[test]
+    fn test_median() {
+        let mut chain = FilterChain::new(&[FilterConfig::Median { window: 3 }]);
+        assert_eq!(chain.apply(1., 0.), 1.);
+        assert_eq!(chain.apply(100., 0.), 50.5);
+        assert_eq!(chain.apply(2., 0.), 2.);
+        assert_eq!(chain.apply(3., 0.), 3.);
+    }
+
+    #[test]
+    fn test_low_pass() {
+        let mut chain = FilterChain::new(&[FilterConfig::LowPass { cutoff_hz: 1. }]);
+        assert_eq!(chain.apply(0., 0.), 0.);
+
+        let out = chain.apply(1., 0.01);
+        assert!(out > 0. && out < 0.1);
+
+        for _ in 0..1000 {
+            chain.apply(1., 0.01);
+        }
+        assert!((chain.apply(1., 0.01) - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_outlier_rejection() {
+        let mut chain = FilterChain::new(&[FilterConfig::OutlierRejection {
+            window: 3,
+            max_deviation: 0.5,
+        }]);
+        assert_eq!(chain.apply(1., 0.), 1.);
+        assert_eq!(chain.apply(1.1, 0.), 1.1);
+        assert_eq!(chain.apply(5., 0.), 1.1);
+        assert_eq!(chain.apply(1.2, 0.), 1.2);
+
+        // A permanent step is accepted after a full window
+        assert_eq!(chain.apply(5., 0.), 1.2);
+        assert_eq!(chain.apply(5., 0.), 1.2);
+        assert_eq!(chain.apply(5., 0.), 1.2);
+        assert_eq!(chain.apply(5., 0.), 5.);
+        assert_eq!(chain.apply(5.1, 0.), 5.1);
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use evalexpr::{ContextWithMutableVariables, DefaultNumericTypes, HashMapContext, Node, Value};
+
+use crate::{
+    calibration::CalibrationTable,
+    control::{CycleData, TargetSource},
+    error::ControlError,
+    utils::{AxisConfig, Config, TargetKind},
+    zaber::{mm_to_steps, steps_to_mm},
+};
+
+/// Computes the target of an axis in mm.
+#[derive(Clone, Debug)]
+enum AxisTarget {
+    Formula(Node<DefaultNumericTypes>),
+    Calibration(CalibrationTable),
+}
+
+impl AxisTarget {
+    fn new(axis: &AxisConfig) -> Result<Self> {
+        match (&axis.target_kind, &axis.calibration) {
+            (TargetKind::Formula, _) => evalexpr::build_operator_tree(&axis.formula)
+                .map(Self::Formula)
+                .map_err(|e| anyhow!("Invalid formula of axis {}: {}", axis.name, e)),
+            (TargetKind::Calibration, Some(calibration)) => CalibrationTable::new(calibration)
+                .map(Self::Calibration)
+                .map_err(|e| anyhow!("Invalid calibration of axis {}: {}", axis.name, e)),
+            (TargetKind::Calibration, None) => {
+                Err(anyhow!("Axis {} has no calibration table", axis.name))
+            }
+        }
+    }
+}
+
+/// Names of the variables of an axis in the formulas.
+#[derive(Clone, Debug)]
+struct AxisVariables {
+    /// Current position in mm.
+    position: String,
+    /// Target of the previous cycle in mm.
+    previous: String,
+    /// Target in mm, of the current cycle for the axes evaluated before.
+    target: String,
+}
+
+/// Names of the variables the formulas of `config` can use besides the constants.
+pub fn variable_names(config: &Config) -> Vec<String> {
+    let mut names: Vec<String> = ["v1", "v2", "t", "dt"].map(String::from).to_vec();
+    for axis in &config.axes {
+        names.extend(["pos", "prev", "target"].map(|prefix| format!("{}_{}", prefix, axis.name)));
+    }
+    names
+}
+
+/// Compiles `formula` and checks that it only reads the variables and constants of `config`.
+pub fn check_formula(formula: &str, config: &Config) -> Result<()> {
+    let node: Node<DefaultNumericTypes> =
+        evalexpr::build_operator_tree(formula).map_err(|e| anyhow!("{}", e))?;
+    let variables = variable_names(config);
+    let written: Vec<&str> = node.iter_write_variable_identifiers().collect();
+    let unknown = node.iter_read_variable_identifiers().find(|name| {
+        !written.contains(name)
+            && !config.constants.contains_key(*name)
+            && !variables.iter().any(|v| v == *name)
+    });
+    match unknown {
+        Some(name) => Err(anyhow!("Unknown variable {}", name)),
+        None => Ok(()),
+    }
+}
+
+/// Computes the targets of the axes from their formulas or calibration tables in
+/// `ControlMode::Tracking` and `ControlMode::ClosedLoop`.
+///
+/// Besides the filtered voltages `v1` and `v2`, the formulas can use the time `t` since
+/// the start of the control loop and the duration `dt` of the last cycle in seconds, the
+/// constants of the config and for every axis `pos_<name>`, `prev_<name>` and
+/// `target_<name>` in mm. The formulas are evaluated in the order of the axes, so
+/// `target_<name>` of a later axis is still its previous target.
+#[derive(Clone, Debug)]
+pub struct Formulas {
+    formulas: Vec<AxisTarget>,
+    axes: Vec<String>,
+    variables: Vec<AxisVariables>,
+    constants: BTreeMap<String, f64>,
+    /// Targets of the previous cycle in mm, the positions before the first one.
+    previous: Option<Vec<f64>>,
+}
+
+impl Formulas {
+    pub fn new(config: &Config) -> Result<Self> {
+        let formulas = config.axes.iter().map(AxisTarget::new).collect::<Result<_>>()?;
+        let variables: Vec<_> = config
+            .axes
+            .iter()
+            .map(|axis| AxisVariables {
+                position: format!("pos_{}", axis.name),
+                previous: format!("prev_{}", axis.name),
+                target: format!("target_{}", axis.name),
+            })
+            .collect();
+
+        let reserved = variable_names(config);
+        if let Some(name) = config.constants.keys().find(|name| reserved.contains(name)) {
+            return Err(anyhow!("The constant {} shadows a variable of the formulas", name));
+        }
+
+        Ok(Self {
+            formulas,
+            axes: config.axis_names(),
+            variables,
+            constants: config.constants.clone(),
+            previous: None,
+        })
+    }
+
+    fn context(&self, cycle: &CycleData, previous: &[f64]) -> Result<HashMapContext> {
+        let mut context = HashMapContext::new();
+        for (name, value) in &self.constants {
+            context.set_value(name.clone(), Value::Float(*value))?;
+        }
+        context.set_value("v1".into(), Value::Float(cycle.voltages[0]))?;
+        context.set_value("v2".into(), Value::Float(cycle.voltages[1]))?;
+        context.set_value("t".into(), Value::Float(cycle.time))?;
+        context.set_value("dt".into(), Value::Float(cycle.dt))?;
+
+        for (i, variables) in self.variables.iter().enumerate() {
+            let position = steps_to_mm(cycle.positions[i]);
+            context.set_value(variables.position.clone(), Value::Float(position))?;
+            context.set_value(variables.previous.clone(), Value::Float(previous[i]))?;
+            context.set_value(variables.target.clone(), Value::Float(previous[i]))?;
+        }
+
+        Ok(context)
+    }
+}
+
+impl TargetSource for Formulas {
+    fn get_targets(&mut self, cycle: &CycleData) -> Result<Vec<u32>> {
+        let previous = match self.previous.take() {
+            Some(previous) => previous,
+            None => cycle.positions.iter().map(|&p| steps_to_mm(p)).collect(),
+        };
+        let mut context = self.context(cycle, &previous)?;
+
+        let mut targets = Vec::with_capacity(self.formulas.len());
+        for (i, formula) in self.formulas.iter().enumerate() {
+            let target = match formula {
+                AxisTarget::Formula(node) => node
+                    .eval_number_with_context(&context)
+                    .map_err(|e| {
+                        let axis = Some(self.axes[i].clone());
+                        anyhow!("{}", e).context(ControlError::Formula { axis })
+                    })?,
+                AxisTarget::Calibration(table) => table
+                    .lookup(cycle.voltages[table.voltage])
+                    .map_err(|e| anyhow!("Calibration of axis {}: {}", self.axes[i], e))?,
+            };
+            context.set_value(self.variables[i].target.clone(), Value::Float(target))?;
+            targets.push(target);
+        }
+
+        let steps = targets.iter().map(|&target| mm_to_steps(target)).collect();
+        self.previous = Some(targets);
+
+        Ok(steps)
+    }
+
+    /// Rebuilds the formulas and the tables, the previous targets are kept.
+    fn reload(&mut self, config: &Config) -> Result<()> {
+        let previous = self.previous.take();
+        *self = Formulas::new(config)?;
+        self.previous = previous;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::{CalibrationConfig, Extrapolation, Interpolation};
+
+    const POSITIONS: [u32; 2] = [20000, 40000];
+
+    fn config(formulas: [&str; 2]) -> Config {
+        let mut config = Config::default();
+        config.axes = vec![
+            AxisConfig {
+                formula: formulas[0].into(),
+                ..AxisConfig::new("coax", 1)
+            },
+            AxisConfig {
+                formula: formulas[1].into(),
+                ..AxisConfig::new("cross", 2)
+            },
+        ];
+        config.constants = BTreeMap::from([("gain".into(), 2.)]);
+        config
+    }
+
+    fn cycle(time: f64) -> CycleData {
+        CycleData {
+            voltages: [1., 3.],
+            positions: vec![POSITIONS[0], POSITIONS[1]],
+            is_busy: vec![false; 2],
+            time,
+            dt: 0.5,
+        }
+    }
+
+    #[test]
+    fn test_formulas_variables() {
+        let mut formulas =
+            Formulas::new(&config(["gain * v2 + t", "pos_cross + dt + target_coax"])).unwrap();
+
+        let targets = formulas.get_targets(&cycle(1.)).unwrap();
+        let cross = steps_to_mm(POSITIONS[1]) + 0.5 + 7.;
+        assert_eq!(targets, vec![mm_to_steps(7.), mm_to_steps(cross)]);
+    }
+
+    #[test]
+    fn test_formulas_previous_targets() {
+        // Rate limit of 1 mm per cycle towards the position of the other axis
+        let mut formulas = Formulas::new(&config([
+            "prev_coax + min(1, max(-1, pos_cross - prev_coax))",
+            "target_cross",
+        ]))
+        .unwrap();
+
+        let [coax, cross] = POSITIONS.map(steps_to_mm);
+        let targets = formulas.get_targets(&cycle(0.)).unwrap();
+        assert_eq!(targets, vec![mm_to_steps(coax + 1.), POSITIONS[1]]);
+        let targets = formulas.get_targets(&cycle(0.5)).unwrap();
+        assert_eq!(targets, vec![mm_to_steps(coax + 2.), mm_to_steps(cross)]);
+    }
+
+    #[test]
+    fn test_formulas_errors() {
+        assert!(Formulas::new(&config(["v1 +", "0"])).is_err());
+
+        let mut shadowing = config(["v1", "0"]);
+        shadowing.constants.insert("pos_cross".into(), 1.);
+        assert!(Formulas::new(&shadowing).is_err());
+
+        let mut formulas = Formulas::new(&config(["unknown", "0"])).unwrap();
+        assert!(formulas.get_targets(&cycle(0.)).is_err());
+
+        let mut uncalibrated = config(["v1", "0"]);
+        uncalibrated.axes[1].target_kind = TargetKind::Calibration;
+        assert!(Formulas::new(&uncalibrated).is_err());
+    }
+
+    #[test]
+    fn test_formulas_calibration() {
+        let mut config = config(["target_cross + 1", "0"]);
+        config.axes[1].target_kind = TargetKind::Calibration;
+        config.axes[1].calibration = Some(CalibrationConfig {
+            voltage: 2,
+            path: None,
+            points: vec![[0., 0.], [4., 8.]],
+            interpolation: Interpolation::Linear,
+            extrapolation: Extrapolation::Clamp,
+        });
+        let mut formulas = Formulas::new(&config).unwrap();
+
+        // The coaxial axis still sees the previous target of the cross axis
+        let targets = formulas.get_targets(&cycle(0.)).unwrap();
+        let cross = steps_to_mm(POSITIONS[1]);
+        assert_eq!(targets, vec![mm_to_steps(cross + 1.), mm_to_steps(6.)]);
+    }
+}
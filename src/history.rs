@@ -0,0 +1,218 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::error::{ControlError, ErrorReport, Severity};
+
+/// Number of entries kept, the oldest ones are dropped first.
+pub const HISTORY_CAPACITY: usize = 100;
+
+/// Error or warning of the control. Repeats of an unacknowledged entry are counted
+/// instead of added again.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    /// Unique number of the entry, used to acknowledge it.
+    pub id: u64,
+    pub severity: Severity,
+    /// Code of the `ControlError` category, if the entry belongs to one.
+    pub code: Option<u16>,
+    pub axis: Option<String>,
+    pub message: String,
+    pub first: DateTime<Local>,
+    pub last: DateTime<Local>,
+    pub count: u64,
+    pub acknowledged: bool,
+}
+
+impl HistoryEntry {
+    /// Single line description, e.g. for the OPC UA array.
+    pub fn summary(&self) -> String {
+        let code = self.code.map_or(String::new(), |code| format!(" {}", code));
+        let ack = if self.acknowledged { ", acknowledged" } else { "" };
+        format!(
+            "{} {:?}{}: {} ({}x{})",
+            self.last.format("%Y-%m-%d %H:%M:%S"),
+            self.severity,
+            code,
+            self.message,
+            self.count,
+            ack
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    entries: VecDeque<HistoryEntry>,
+    next_id: u64,
+}
+
+/// Bounded history of the errors and warnings shared between the control loop and the
+/// interfaces. Unlike the error in `SharedState`, it is kept between starts of the control.
+#[derive(Clone, Debug)]
+pub struct ErrorHistory {
+    capacity: usize,
+    inner: Arc<RwLock<Entries>>,
+}
+
+impl ErrorHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Arc::new(RwLock::new(Entries::default())),
+        }
+    }
+
+    /// Records an error which stopped the control.
+    pub fn push_error(&self, error: &anyhow::Error) {
+        let report = ErrorReport::new(error);
+        self.push(report.severity, Some(report.code), report.kind.axis(), &report.message);
+    }
+
+    /// Records a warning, `kind` is the category it belongs to, if any.
+    pub fn push_warning(&self, kind: Option<&ControlError>, message: &str) {
+        self.push_warnings(kind, message, 1);
+    }
+
+    /// Records `count` occurrences of a warning at once, e.g. a summary of an interval.
+    pub fn push_warnings(&self, kind: Option<&ControlError>, message: &str, count: u64) {
+        let code = kind.map(ControlError::code);
+        let axis = kind.and_then(ControlError::axis);
+        self.push_n(Severity::Warning, code, axis, message, count);
+    }
+
+    pub fn push(&self, severity: Severity, code: Option<u16>, axis: Option<&str>, message: &str) {
+        self.push_n(severity, code, axis, message, 1);
+    }
+
+    fn push_n(
+        &self,
+        severity: Severity,
+        code: Option<u16>,
+        axis: Option<&str>,
+        message: &str,
+        count: u64,
+    ) {
+        let now = Local::now();
+        let mut inner = self.inner.write().unwrap();
+
+        let repeat = inner.entries.iter().position(|entry| {
+            !entry.acknowledged
+                && (entry.severity, entry.code, entry.axis.as_deref()) == (severity, code, axis)
+                && entry.message == message
+        });
+        if let Some(entry) = repeat.and_then(|i| inner.entries.remove(i)) {
+            // The most recent entries are the last ones
+            inner.entries.push_back(HistoryEntry {
+                count: entry.count + count,
+                last: now,
+                ..entry
+            });
+            return;
+        }
+
+        if inner.entries.len() >= self.capacity {
+            inner.entries.pop_front();
+        }
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.entries.push_back(HistoryEntry {
+            id,
+            severity,
+            code,
+            axis: axis.map(str::to_string),
+            message: message.to_string(),
+            first: now,
+            last: now,
+            count,
+            acknowledged: false,
+        });
+    }
+
+    /// Entries from the oldest to the most recent one.
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.inner.read().unwrap().entries.iter().cloned().collect()
+    }
+
+    /// Acknowledges the entry `id`, or all entries if `None`.
+    pub fn acknowledge(&self, id: Option<u64>) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        match id {
+            Some(id) => {
+                let entry = inner
+                    .entries
+                    .iter_mut()
+                    .find(|entry| entry.id == id)
+                    .ok_or(anyhow!("Unknown history entry {}", id))?;
+                entry.acknowledged = true;
+            }
+            None => inner.entries.iter_mut().for_each(|entry| entry.acknowledged = true),
+        }
+        Ok(())
+    }
+
+    /// Removes the acknowledged entries, so unseen faults cannot be cleared by accident.
+    pub fn clear(&self) {
+        self.inner.write().unwrap().entries.retain(|entry| !entry.acknowledged);
+    }
+}
+
+impl Default for ErrorHistory {
+    fn default() -> Self {
+        Self::new(HISTORY_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_repeats() {
+        let history = ErrorHistory::new(3);
+        let overrun = "Control cycle overran the cycle time";
+        history.push_warning(None, overrun);
+        history.push_error(&anyhow!("timed out").context(ControlError::ZaberComm {
+            device: Some(1),
+            axis: None,
+        }));
+        history.push_warning(None, overrun);
+
+        let entries = history.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].code, entries[0].severity), (Some(200), Severity::Error));
+        assert_eq!((entries[1].id, entries[1].count), (0, 2));
+        history.push_warnings(None, overrun, 5);
+        assert_eq!(history.entries()[1].count, 7);
+
+        // Acknowledged entries are not counted up anymore
+        history.acknowledge(Some(0)).unwrap();
+        history.push_warning(None, overrun);
+        history.push_warning(None, "Another warning");
+        let entries = history.entries();
+        assert_eq!(entries.iter().map(|e| e.id).collect::<Vec<_>>(), vec![0, 2, 3]);
+        assert!(history.acknowledge(Some(1)).is_err());
+    }
+
+    #[test]
+    fn test_history_clear() {
+        let history = ErrorHistory::new(2);
+        for message in ["first", "second", "third"] {
+            history.push_warning(None, message);
+        }
+        assert_eq!(history.entries()[0].message, "second");
+
+        history.acknowledge(Some(2)).unwrap();
+        history.clear();
+        assert_eq!(history.entries().len(), 1);
+
+        history.acknowledge(None).unwrap();
+        history.clear();
+        assert!(history.entries().is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+use std::{path::Path, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::AxisConfig;
+
+/// Distance in microsteps a device may report from the stored position of an axis
+/// for the position to be trusted without homing.
+pub const POSITION_TOLERANCE: u32 = 10;
+
+/// When the devices are homed at the start of the control.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum HomingPolicy {
+    /// Restore the settings and home every time.
+    #[default]
+    Always,
+    /// Only home devices which are not homed or whose axes are not at the stored positions.
+    WhenUnhomed,
+    /// Never home, devices which are not homed are an error.
+    Never,
+}
+
+impl FromStr for HomingPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Always" => Ok(Self::Always),
+            "WhenUnhomed" => Ok(Self::WhenUnhomed),
+            "Never" => Ok(Self::Never),
+            _ => Err(anyhow!("Unknown homing policy {}", s)),
+        }
+    }
+}
+
+/// Result of checking a device against the stored positions at the start.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceCheck {
+    /// The device reports that it has no reference position.
+    Unhomed,
+    /// The device is homed, but the positions of its axes cannot be trusted.
+    Unverified(String),
+    Verified,
+}
+
+impl HomingPolicy {
+    /// Whether `device` has to be homed, fails if it has to but must not be.
+    pub fn needs_homing(&self, device: u8, check: &DeviceCheck) -> Result<bool> {
+        match (self, check) {
+            (Self::Always, _) => Ok(true),
+            (Self::WhenUnhomed, DeviceCheck::Verified) => Ok(false),
+            (Self::WhenUnhomed, DeviceCheck::Unverified(reason)) => {
+                tracing::info!("Homing device {}: {}", device, reason);
+                Ok(true)
+            }
+            (Self::WhenUnhomed, DeviceCheck::Unhomed) => {
+                tracing::info!("Homing device {}: it is not homed", device);
+                Ok(true)
+            }
+            (Self::Never, DeviceCheck::Unhomed) => Err(anyhow!(
+                "Device {} is not homed, but the homing policy is Never",
+                device
+            )),
+            (Self::Never, DeviceCheck::Unverified(reason)) => {
+                tracing::warn!("Device {} is not homed again: {}", device, reason);
+                Ok(false)
+            }
+            (Self::Never, DeviceCheck::Verified) => Ok(false),
+        }
+    }
+}
+
+/// Last known position of an axis.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredPosition {
+    pub name: String,
+    /// Position in microsteps.
+    pub position: u32,
+    pub homed: bool,
+}
+
+/// Positions of the axes saved when the control stops, so the next start can verify
+/// that the devices were neither power cycled nor moved in between.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PositionStore {
+    #[serde(default)]
+    pub axes: Vec<StoredPosition>,
+}
+
+impl PositionStore {
+    /// Stores the positions of the enabled axes, which are homed while the control runs.
+    pub fn new(axes: &[AxisConfig], positions: &[u32]) -> Self {
+        Self {
+            axes: axes
+                .iter()
+                .zip(positions)
+                .filter(|(axis, _)| axis.enabled)
+                .map(|(axis, &position)| StoredPosition {
+                    name: axis.name.clone(),
+                    position,
+                    homed: true,
+                })
+                .collect(),
+        }
+    }
+
+    /// Reads the positions, a missing file is an empty store.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|e| anyhow!("Invalid positions in {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(anyhow!("Unable to read {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, toml::to_string_pretty(self)?)
+            .map_err(|e| anyhow!("Unable to write {}: {}", path.display(), e))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&StoredPosition> {
+        self.axes.iter().find(|axis| axis.name == name)
+    }
+
+    /// Checks the position an axis reports against the stored one.
+    pub fn verify(&self, name: &str, position: u32) -> Result<(), String> {
+        match self.get(name) {
+            None => Err(format!("no position of axis {} is stored", name)),
+            Some(stored) if !stored.homed => Err(format!("axis {} was not homed", name)),
+            Some(stored) if stored.position.abs_diff(position) > POSITION_TOLERANCE => {
+                Err(format!(
+                    "axis {} is at {} instead of the stored {}",
+                    name, position, stored.position
+                ))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position_store() {
+        let mut axes = vec![AxisConfig::new("coax", 1), AxisConfig::new("cross", 2)];
+        axes[1].enabled = false;
+        let store = PositionStore::new(&axes, &[1000, 0]);
+
+        let content = toml::to_string_pretty(&store).unwrap();
+        assert_eq!(toml::from_str::<PositionStore>(&content).unwrap(), store);
+
+        assert!(store.verify("coax", 1000 + POSITION_TOLERANCE).is_ok());
+        assert!(store.verify("coax", 1000 + POSITION_TOLERANCE + 1).is_err());
+        assert!(store.verify("cross", 0).is_err());
+    }
+
+    #[test]
+    fn test_homing_policy() {
+        let moved = DeviceCheck::Unverified("moved".into());
+
+        assert!(HomingPolicy::Always.needs_homing(1, &DeviceCheck::Verified).unwrap());
+        assert!(!HomingPolicy::WhenUnhomed.needs_homing(1, &DeviceCheck::Verified).unwrap());
+        assert!(HomingPolicy::WhenUnhomed.needs_homing(1, &moved).unwrap());
+        assert!(!HomingPolicy::Never.needs_homing(1, &moved).unwrap());
+        assert!(HomingPolicy::Never.needs_homing(1, &DeviceCheck::Unhomed).is_err());
+    }
+}
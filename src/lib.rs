@@ -1,6 +1,23 @@
+pub mod calibration;
 pub mod control;
+pub mod deadband;
+pub mod error;
+pub mod filter;
+pub mod formula;
+pub mod history;
+pub mod homing;
 pub mod opcua;
+pub mod pid;
+pub mod predictor;
+pub mod ramp;
+pub mod retry;
+pub mod sequence;
 pub mod simulation;
+pub mod timing;
+pub mod transition;
+pub mod units;
 pub mod utils;
+pub mod watchdog;
 pub mod web;
+pub mod wizard;
 pub mod zaber;
@@ -3,10 +3,14 @@ use crossbeam_channel::bounded;
 use std::sync::{Arc, RwLock};
 
 use lus_positioning_control::{
-    control::init,
+    control::{estop_idle, init},
+    error::{ControlError, ErrorReport},
+    history::ErrorHistory,
     opcua::run_opcua,
-    utils::{read_config, write_config, Config, ControlStatus, ExecState, SharedState},
+    sequence::read_sequence,
+    utils::{read_config, write_config, Config, ControlStatus, Estop, ExecState, SharedState},
     web::{run_web_server, WebState},
+    wizard::CalibrationSession,
 };
 
 fn main() {
@@ -14,44 +18,69 @@ fn main() {
 
     let (tx_stop, rx_stop) = bounded::<()>(1);
     let (tx_start, rx_start) = bounded::<()>(1);
+    let (tx_reload, rx_reload) = bounded::<()>(1);
 
-    let target_manual = Arc::new(RwLock::new([0; 2]));
+    let estop = Estop::new();
+    let history = ErrorHistory::default();
 
-    let shared_state = SharedState {
-        target: [0; 2],
-        position: [0; 2],
-        is_busy: [false; 2],
-        control_state: ControlStatus::Stopped,
-        error: None,
-        timestamp: Local::now(),
-        voltage: [0.; 2],
+    // An invalid config is not replaced, so it can be fixed
+    let config = match read_config() {
+        Ok(config) => config,
+        Err(_) if std::path::Path::new("config.toml").exists() => std::process::exit(1),
+        Err(_) => {
+            let config = Config::default();
+            write_config(&config).unwrap();
+            config
+        }
     };
+
+    let target_manual = Arc::new(RwLock::new(vec![0; config.axes.len()]));
+    let shared_state = SharedState::new(&config.axes);
     let state_channel = Arc::new(RwLock::new(shared_state.clone()));
 
-    let config = read_config().unwrap_or_else(|_| {
-        let config = Config::default();
-        write_config(&config).unwrap();
-        config
-    });
+    let sequence = match read_sequence(&config.sequence_path, &config.axis_names()) {
+        Ok(sequence) => sequence,
+        Err(e) => {
+            tracing::warn!("no sequence loaded from {:?}: {}", config.sequence_path, e);
+            Vec::new()
+        }
+    };
+    let sequence = Arc::new(RwLock::new(sequence));
 
     let mut state = ExecState {
         shared: shared_state.clone(),
         config: Arc::new(RwLock::new(config.clone())),
         out_channel: Arc::clone(&state_channel),
         rx_stop: rx_stop.clone(),
+        rx_reload,
+        estop: estop.clone(),
+        history: history.clone(),
         target_manual: Arc::clone(&target_manual),
+        sequence: Arc::clone(&sequence),
     };
 
     let queue_clone = Arc::clone(&state_channel);
     let config_path = state.config.read().unwrap().opcua_config_path.clone();
-    run_opcua(queue_clone, config_path);
+    run_opcua(
+        queue_clone,
+        estop.clone(),
+        history.clone(),
+        Arc::clone(&state.config),
+        tx_stop.clone(),
+        config_path,
+    );
 
     let web_state = WebState {
         zaber_state: state_channel,
         tx_stop_control: tx_stop.clone(),
         tx_start_control: tx_start.clone(),
+        tx_reload_control: tx_reload,
+        estop,
+        history,
         config: state.config.clone(),
         target_manual,
+        sequence,
+        calibration: Arc::new(RwLock::new(CalibrationSession::default())),
     };
     std::thread::spawn(|| run_web_server(web_state));
 
@@ -66,7 +95,30 @@ fn main() {
             *out = state.shared.clone();
         }
         tracing::debug!("control waiting for start");
-        let _ = rx_start.recv();
+        crossbeam_channel::select! {
+            recv(rx_start) -> _ => (),
+            recv(state.estop.receiver()) -> _ => {
+                // The control loop is not running, only the state needs to be published
+                let triggered = state.estop.triggered();
+                if let (None, Some(trigger)) = (&state.shared.estop, &triggered) {
+                    let source = trigger.source.clone();
+                    state.history.push_error(&ControlError::EmergencyStop { source }.into());
+                    // The axes might still move if they did not come to rest at the last stop
+                    let config = state.config.read().unwrap().clone();
+                    if let Err(e) = estop_idle(&config) {
+                        tracing::error!("Failed to stop the axes: {:#}", e);
+                        state.history.push_error(&e);
+                    }
+                }
+                state.shared.estop = triggered;
+                state.shared.control_state = match state.shared.estop {
+                    Some(_) => ControlStatus::EmergencyStopped,
+                    None => ControlStatus::Stopped,
+                };
+                state.shared.timestamp = Local::now();
+                continue;
+            }
+        }
         tracing::debug!("start signal received");
 
         // There might be more signals in channel,
@@ -78,6 +130,13 @@ fn main() {
             let _ = rx_start.try_recv();
         }
 
+        state.shared.estop = state.estop.triggered();
+        if state.shared.estop.is_some() {
+            tracing::warn!("start refused, the emergency stop is latched");
+            state.shared.control_state = ControlStatus::EmergencyStopped;
+            continue;
+        }
+
         state.shared.control_state = ControlStatus::Running;
         state.shared.timestamp = Local::now();
         {
@@ -95,9 +154,14 @@ fn main() {
                 drop(out);
             }
             Err(e) => {
-                tracing::error!("control error: {}", &e);
-                state.shared.control_state = ControlStatus::Error;
-                state.shared.error = Some(e.to_string());
+                tracing::error!("control error: {:#}", &e);
+                state.shared.estop = state.estop.triggered();
+                state.shared.control_state = match state.shared.estop {
+                    Some(_) => ControlStatus::EmergencyStopped,
+                    None => ControlStatus::Error,
+                };
+                state.shared.error = Some(ErrorReport::new(&e));
+                state.history.push_error(&e);
                 state.shared.timestamp = Local::now();
 
                 {
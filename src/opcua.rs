@@ -1,57 +1,102 @@
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 
+use crossbeam_channel::Sender;
+
 use opcua::server::state::ServerState;
+use opcua::server::{callbacks, session::SessionManager};
 use opcua::{server::prelude::*, sync::RwLock};
 
-use crate::utils::StateChannel;
+use crate::error::ControlError;
+use crate::history::ErrorHistory;
+use crate::timing::TimingReport;
+use crate::utils::{set_control_mode, Config, ControlMode, Estop, StateChannel};
 use crate::zaber::steps_to_mm;
 
+/// OPC UA status of a value or method call failing with `error`.
+fn status_code(error: &ControlError) -> StatusCode {
+    match error {
+        ControlError::Adc | ControlError::ZaberComm { .. } => StatusCode::BadCommunicationError,
+        ControlError::ZaberRejected { .. } | ControlError::FollowingError(_) => {
+            StatusCode::BadDeviceFailure
+        }
+        ControlError::Formula { .. } | ControlError::Config { .. } => {
+            StatusCode::BadConfigurationError
+        }
+        ControlError::Limit { .. } => StatusCode::BadOutOfRange,
+        ControlError::EmergencyStop { .. } => StatusCode::BadInvalidState,
+        ControlError::Internal => StatusCode::BadInternalError,
+    }
+}
+
+/// Adds a folder per axis, e.g. `coax-slide`, with its position, busy and enabled flags
+/// and whether its targets are outside of the limits.
+/// The axes are the ones configured at startup.
+/// The general folder holds the status and the last error, whose value carries the
+/// status code of the error.
 fn add_axis_variables(server: &mut Server, ns: u16, zaber: StateChannel) {
     let address_space = server.address_space();
 
-    let node_position_cross = NodeId::new(ns, "position_cross");
-    let node_busy_cross = NodeId::new(ns, "busy_cross");
-    let node_position_coax = NodeId::new(ns, "position_coax");
-    let node_busy_coax = NodeId::new(ns, "busy_coax");
+    let axes = zaber.read().unwrap().axes.clone();
+    let nodes_axes: Vec<(String, [NodeId; 5])> = axes
+        .into_iter()
+        .map(|name| {
+            let nodes = [
+                NodeId::new(ns, format!("position_{}", name)),
+                NodeId::new(ns, format!("busy_{}", name)),
+                NodeId::new(ns, format!("enabled_{}", name)),
+                NodeId::new(ns, format!("out_of_limits_{}", name)),
+                NodeId::new(ns, format!("limit_violations_{}", name)),
+            ];
+            (name, nodes)
+        })
+        .collect();
     let node_status = NodeId::new(ns, "status");
+    let node_error = NodeId::new(ns, "error");
+    let node_error_code = NodeId::new(ns, "error_code");
 
     let root_id = NodeId::objects_folder_id();
 
     {
         let mut address_space = address_space.write();
 
-        let folder_cross_id = address_space
-            .add_folder("cross-slide", "cross-slide", &root_id)
-            .unwrap();
+        for (name, [node_position, node_busy, node_enabled, node_out, node_count]) in &nodes_axes {
+            let folder_name = format!("{}-slide", name);
+            let folder_id = address_space
+                .add_folder(folder_name.as_str(), folder_name.as_str(), &root_id)
+                .unwrap();
 
-        VariableBuilder::new(&node_position_cross, "position", "position [mm]")
-            .value(0.)
-            .data_type(DataTypeId::Double)
-            .organized_by(&folder_cross_id)
-            .insert(&mut address_space);
+            VariableBuilder::new(node_position, "position", "position [mm]")
+                .value(0.)
+                .data_type(DataTypeId::Double)
+                .organized_by(&folder_id)
+                .insert(&mut address_space);
 
-        VariableBuilder::new(&node_busy_cross, "busy", "busy")
-            .data_type(DataTypeId::Boolean)
-            .organized_by(&folder_cross_id)
-            .value(false)
-            .insert(&mut address_space);
+            VariableBuilder::new(node_busy, "busy", "busy")
+                .data_type(DataTypeId::Boolean)
+                .organized_by(&folder_id)
+                .value(false)
+                .insert(&mut address_space);
 
-        let folder_coax_id = address_space
-            .add_folder("coax-slide", "coax-slide", &root_id)
-            .unwrap();
+            VariableBuilder::new(node_enabled, "enabled", "enabled")
+                .data_type(DataTypeId::Boolean)
+                .organized_by(&folder_id)
+                .value(true)
+                .insert(&mut address_space);
 
-        VariableBuilder::new(&node_position_coax, "position", "position [mm]")
-            .value(0.)
-            .data_type(DataTypeId::Double)
-            .organized_by(&folder_coax_id)
-            .insert(&mut address_space);
+            VariableBuilder::new(node_out, "out_of_limits", "target out of limits")
+                .data_type(DataTypeId::Boolean)
+                .organized_by(&folder_id)
+                .value(false)
+                .insert(&mut address_space);
 
-        VariableBuilder::new(&node_busy_coax, "busy", "busy")
-            .data_type(DataTypeId::Boolean)
-            .organized_by(&folder_coax_id)
-            .value(false)
-            .insert(&mut address_space);
+            VariableBuilder::new(node_count, "limit_violations", "limit violations")
+                .data_type(DataTypeId::UInt64)
+                .organized_by(&folder_id)
+                .value(0u64)
+                .insert(&mut address_space);
+        }
 
         let folder_general_id = address_space
             .add_folder("general", "general", &root_id)
@@ -62,6 +107,18 @@ fn add_axis_variables(server: &mut Server, ns: u16, zaber: StateChannel) {
             .data_type(DataTypeId::String)
             .organized_by(&folder_general_id)
             .insert(&mut address_space);
+
+        VariableBuilder::new(&node_error, "error", "error")
+            .value(UAString::from(""))
+            .data_type(DataTypeId::String)
+            .organized_by(&folder_general_id)
+            .insert(&mut address_space);
+
+        VariableBuilder::new(&node_error_code, "error_code", "error code")
+            .value(0u16)
+            .data_type(DataTypeId::UInt16)
+            .organized_by(&folder_general_id)
+            .insert(&mut address_space);
     };
 
     server.add_polling_action(1000, move || {
@@ -72,19 +129,42 @@ fn add_axis_variables(server: &mut Server, ns: u16, zaber: StateChannel) {
         let now = DateTime::now();
 
         let mut address_space = address_space.write();
-        for i in 0..2 {
+        for (name, [node_position, node_busy, node_enabled, node_out, node_count]) in &nodes_axes {
+            // The axes might have been reconfigured since the startup
+            let Some(i) = zaber_state.axes.iter().position(|axis| axis == name) else {
+                continue;
+            };
+
             let _ = address_space.set_variable_value(
-                node_position_coax.clone(),
+                node_position.clone(),
                 steps_to_mm(zaber_state.position[i]),
                 &now,
                 &now,
             );
             let _ = address_space.set_variable_value(
-                node_busy_coax.clone(),
+                node_busy.clone(),
                 zaber_state.is_busy[i],
                 &now,
                 &now,
             );
+            let _ = address_space.set_variable_value(
+                node_enabled.clone(),
+                zaber_state.enabled[i],
+                &now,
+                &now,
+            );
+            let _ = address_space.set_variable_value(
+                node_out.clone(),
+                zaber_state.out_of_limits[i],
+                &now,
+                &now,
+            );
+            let _ = address_space.set_variable_value(
+                node_count.clone(),
+                zaber_state.limit_violations[i],
+                &now,
+                &now,
+            );
         }
 
         let _ = address_space.set_variable_value(
@@ -93,10 +173,295 @@ fn add_axis_variables(server: &mut Server, ns: u16, zaber: StateChannel) {
             &now,
             &now,
         );
+
+        let (message, code, status) = match &zaber_state.error {
+            Some(error) => (error.message.clone(), error.code, status_code(&error.kind)),
+            None => (String::new(), 0, StatusCode::Good),
+        };
+        if let Some(variable) = address_space.find_variable_mut(node_error.clone()) {
+            variable.set_value_direct(UAString::from(message), status, &now, &now);
+        }
+        let _ = address_space.set_variable_value(node_error_code.clone(), code, &now, &now);
     });
 }
 
-pub fn run_opcua(zaber_state: StateChannel, config_path: PathBuf) -> Arc<RwLock<ServerState>> {
+/// Adds a variable per statistic of the control loop timing, e.g. `timing_period_p99`.
+fn add_timing_variables(server: &mut Server, ns: u16, zaber: StateChannel) {
+    let address_space = server.address_space();
+
+    let node_overruns = NodeId::new(ns, "timing_overruns");
+    let nodes_stats: Vec<[NodeId; 4]> = TimingReport::default()
+        .series()
+        .iter()
+        .map(|(name, _)| {
+            ["min", "mean", "max", "p99"]
+                .map(|stat| NodeId::new(ns, format!("timing_{}_{}", name, stat)))
+        })
+        .collect();
+
+    let root_id = NodeId::objects_folder_id();
+
+    {
+        let mut address_space = address_space.write();
+
+        let folder_timing_id = address_space
+            .add_folder("timing", "timing", &root_id)
+            .unwrap();
+
+        VariableBuilder::new(&node_overruns, "overruns", "overruns")
+            .value(0u64)
+            .data_type(DataTypeId::UInt64)
+            .organized_by(&folder_timing_id)
+            .insert(&mut address_space);
+
+        for ((name, _), nodes) in TimingReport::default().series().iter().zip(&nodes_stats) {
+            for (node, stat) in nodes.iter().zip(["min", "mean", "max", "p99"]) {
+                let browse_name = format!("{}_{}", name, stat);
+                let display_name = format!("{} [ms]", browse_name);
+                VariableBuilder::new(node, browse_name.as_str(), display_name.as_str())
+                    .value(0.)
+                    .data_type(DataTypeId::Double)
+                    .organized_by(&folder_timing_id)
+                    .insert(&mut address_space);
+            }
+        }
+    };
+
+    server.add_polling_action(1000, move || {
+        let Ok(zaber_state) = zaber.try_read() else {
+            return;
+        };
+
+        let now = DateTime::now();
+
+        let mut address_space = address_space.write();
+        let timing = &zaber_state.timing;
+        let _ = address_space.set_variable_value(
+            node_overruns.clone(),
+            timing.overruns,
+            &now,
+            &now,
+        );
+        for ((_, stats), nodes) in timing.series().iter().zip(&nodes_stats) {
+            let values = [stats.min, stats.mean, stats.max, stats.p99];
+            for (node, value) in nodes.iter().zip(values) {
+                let _ = address_space.set_variable_value(node.clone(), value, &now, &now);
+            }
+        }
+    });
+}
+
+/// Adds a folder `history` with the entries of the history as an array of strings, from the
+/// oldest to the most recent one, and methods to acknowledge all entries and to remove the
+/// acknowledged ones.
+fn add_history(server: &mut Server, ns: u16, history: ErrorHistory) {
+    let address_space = server.address_space();
+    let node_entries = NodeId::new(ns, "history_entries");
+
+    {
+        let mut address_space = address_space.write();
+
+        let root_id = NodeId::objects_folder_id();
+        let folder_id = address_space
+            .add_folder("history", "history", &root_id)
+            .unwrap();
+
+        VariableBuilder::new(&node_entries, "entries", "entries")
+            .value(Vec::<String>::new())
+            .data_type(DataTypeId::String)
+            .value_rank(1)
+            .organized_by(&folder_id)
+            .insert(&mut address_space);
+
+        MethodBuilder::new(&NodeId::new(ns, "history_acknowledge"), "acknowledge", "Acknowledge")
+            .component_of(folder_id.clone())
+            .callback(Box::new(HistoryMethod::Acknowledge(history.clone())))
+            .insert(&mut address_space);
+
+        MethodBuilder::new(&NodeId::new(ns, "history_clear"), "clear", "Clear Acknowledged")
+            .component_of(folder_id)
+            .callback(Box::new(HistoryMethod::Clear(history.clone())))
+            .insert(&mut address_space);
+    };
+
+    server.add_polling_action(1000, move || {
+        let now = DateTime::now();
+        let entries: Vec<String> = history.entries().iter().map(|e| e.summary()).collect();
+
+        let mut address_space = address_space.write();
+        let _ = address_space.set_variable_value(node_entries.clone(), entries, &now, &now);
+    });
+}
+
+/// OPC UA methods acknowledging all entries of the history and removing the acknowledged ones.
+enum HistoryMethod {
+    Acknowledge(ErrorHistory),
+    Clear(ErrorHistory),
+}
+
+impl callbacks::Method for HistoryMethod {
+    fn call(
+        &mut self,
+        session_id: &NodeId,
+        _session_manager: Arc<RwLock<SessionManager>>,
+        _request: &CallMethodRequest,
+    ) -> Result<CallMethodResult, StatusCode> {
+        let status_code = match self {
+            Self::Acknowledge(history) => {
+                tracing::debug!("History acknowledged by opcua session {}", session_id);
+                match history.acknowledge(None) {
+                    Ok(_) => StatusCode::Good,
+                    Err(_) => StatusCode::BadInvalidState,
+                }
+            }
+            Self::Clear(history) => {
+                tracing::debug!("History cleared by opcua session {}", session_id);
+                history.clear();
+                StatusCode::Good
+            }
+        };
+        Ok(CallMethodResult {
+            status_code,
+            input_argument_results: None,
+            input_argument_diagnostic_infos: None,
+            output_arguments: None,
+        })
+    }
+}
+
+/// OPC UA method triggering the emergency stop.
+struct EstopMethod(Estop);
+
+impl callbacks::Method for EstopMethod {
+    fn call(
+        &mut self,
+        session_id: &NodeId,
+        _session_manager: Arc<RwLock<SessionManager>>,
+        _request: &CallMethodRequest,
+    ) -> Result<CallMethodResult, StatusCode> {
+        self.0.trigger(&format!("opcua session {}", session_id));
+        Ok(CallMethodResult {
+            status_code: StatusCode::Good,
+            input_argument_results: None,
+            input_argument_diagnostic_infos: None,
+            output_arguments: None,
+        })
+    }
+}
+
+/// OPC UA method resetting a latched emergency stop.
+struct EstopResetMethod(Estop);
+
+impl callbacks::Method for EstopResetMethod {
+    fn call(
+        &mut self,
+        _session_id: &NodeId,
+        _session_manager: Arc<RwLock<SessionManager>>,
+        _request: &CallMethodRequest,
+    ) -> Result<CallMethodResult, StatusCode> {
+        let status_code = match self.0.reset() {
+            Ok(_) => StatusCode::Good,
+            Err(_) => StatusCode::BadInvalidState,
+        };
+        Ok(CallMethodResult {
+            status_code,
+            input_argument_results: None,
+            input_argument_diagnostic_infos: None,
+            output_arguments: None,
+        })
+    }
+}
+
+fn add_estop_methods(server: &mut Server, ns: u16, estop: Estop) {
+    let address_space = server.address_space();
+    let mut address_space = address_space.write();
+
+    let root_id = NodeId::objects_folder_id();
+    let folder_id = address_space
+        .add_folder("emergency-stop", "emergency-stop", &root_id)
+        .unwrap();
+
+    MethodBuilder::new(&NodeId::new(ns, "estop"), "estop", "Emergency Stop")
+        .component_of(folder_id.clone())
+        .callback(Box::new(EstopMethod(estop.clone())))
+        .insert(&mut address_space);
+
+    MethodBuilder::new(&NodeId::new(ns, "estop_reset"), "reset", "Reset Emergency Stop")
+        .component_of(folder_id)
+        .callback(Box::new(EstopResetMethod(estop)))
+        .insert(&mut address_space);
+}
+
+/// OPC UA method switching the control mode, rejected if the config is invalid with it.
+struct SetControlModeMethod {
+    config: Arc<std::sync::RwLock<Config>>,
+    tx_stop: Sender<()>,
+}
+
+impl callbacks::Method for SetControlModeMethod {
+    fn call(
+        &mut self,
+        session_id: &NodeId,
+        _session_manager: Arc<RwLock<SessionManager>>,
+        request: &CallMethodRequest,
+    ) -> Result<CallMethodResult, StatusCode> {
+        let Some(Variant::String(mode)) = request.input_arguments.as_ref().and_then(|a| a.first())
+        else {
+            return Err(StatusCode::BadArgumentsMissing);
+        };
+        let Ok(mode) = ControlMode::from_str(mode.as_ref()) else {
+            return Err(StatusCode::BadInvalidArgument);
+        };
+
+        tracing::debug!("Control mode {:?} requested by opcua session {}", mode, session_id);
+        let status_code = match set_control_mode(&self.config, mode) {
+            Ok(_) => {
+                let _ = self.tx_stop.try_send(());
+                StatusCode::Good
+            }
+            Err(e) => {
+                tracing::error!("Control mode not changed: {:#}", e);
+                status_code(&ControlError::from_error(&e))
+            }
+        };
+        Ok(CallMethodResult {
+            status_code,
+            input_argument_results: None,
+            input_argument_diagnostic_infos: None,
+            output_arguments: None,
+        })
+    }
+}
+
+fn add_control_methods(
+    server: &mut Server,
+    ns: u16,
+    config: Arc<std::sync::RwLock<Config>>,
+    tx_stop: Sender<()>,
+) {
+    let address_space = server.address_space();
+    let mut address_space = address_space.write();
+
+    let root_id = NodeId::objects_folder_id();
+    let folder_id = address_space
+        .add_folder("control", "control", &root_id)
+        .unwrap();
+
+    MethodBuilder::new(&NodeId::new(ns, "set_control_mode"), "set_control_mode", "Set Control Mode")
+        .component_of(folder_id)
+        .input_args(&mut address_space, &[("mode", DataTypeId::String).into()])
+        .callback(Box::new(SetControlModeMethod { config, tx_stop }))
+        .insert(&mut address_space);
+}
+
+pub fn run_opcua(
+    zaber_state: StateChannel,
+    estop: Estop,
+    history: ErrorHistory,
+    config: Arc<std::sync::RwLock<Config>>,
+    tx_stop: Sender<()>,
+    config_path: PathBuf,
+) -> Arc<RwLock<ServerState>> {
     tracing::debug!("Start opcua server");
 
     let config: Result<ServerConfig, ()> = ServerConfig::load(&config_path);
@@ -130,6 +495,10 @@ pub fn run_opcua(zaber_state: StateChannel, config_path: PathBuf) -> Arc<RwLock<
     };
 
     add_axis_variables(&mut server, ns, Arc::clone(&zaber_state));
+    add_timing_variables(&mut server, ns, Arc::clone(&zaber_state));
+    add_estop_methods(&mut server, ns, estop);
+    add_history(&mut server, ns, history);
+    add_control_methods(&mut server, ns, config, tx_stop);
 
     let state = server.server_state();
     std::thread::spawn(|| server.run());
@@ -0,0 +1,82 @@
+/// PID controller with output clamping and anti-windup.
+#[derive(Clone, Debug)]
+pub struct Pid {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+    pub output_limit: f64,
+    integral: f64,
+    error_prev: Option<f64>,
+}
+
+impl Pid {
+    pub fn new(kp: f64, ki: f64, kd: f64, output_limit: f64) -> Self {
+        Self {
+            kp,
+            ki,
+            kd,
+            output_limit: output_limit.abs(),
+            integral: 0.,
+            error_prev: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.;
+        self.error_prev = None;
+    }
+
+    /// Computes the output for a time step of `dt` seconds.
+    /// The output is clamped to `[-output_limit, output_limit]`.
+    pub fn update(&mut self, setpoint: f64, measurement: f64, dt: f64) -> f64 {
+        let error = setpoint - measurement;
+        let derivative = match self.error_prev {
+            Some(error_prev) if dt > 0. => (error - error_prev) / dt,
+            _ => 0.,
+        };
+        self.error_prev = Some(error);
+
+        let integral = self.integral + error * dt;
+        let output = self.kp * error + self.ki * integral + self.kd * derivative;
+        let output_clamped = output.clamp(-self.output_limit, self.output_limit);
+
+        // The integral is only accumulated while the output is not saturated,
+        // or if the error already drives the output back out of saturation.
+        if output == output_clamped || error.signum() != output.signum() {
+            self.integral = integral;
+        }
+
+        output_clamped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pid;
+
+    #[test]
+    fn test_pid_proportional() {
+        let mut pid = Pid::new(2., 0., 0., 100.);
+        assert_eq!(pid.update(10., 4., 0.1), 12.);
+        assert_eq!(pid.update(4., 10., 0.1), -12.);
+    }
+
+    #[test]
+    fn test_pid_clamp() {
+        let mut pid = Pid::new(100., 0., 0., 50.);
+        assert_eq!(pid.update(10., 0., 0.1), 50.);
+        assert_eq!(pid.update(0., 10., 0.1), -50.);
+    }
+
+    #[test]
+    fn test_pid_anti_windup() {
+        let mut pid = Pid::new(0., 1., 0., 1.);
+        for _ in 0..100 {
+            assert_eq!(pid.update(10., 0., 1.), 1.);
+        }
+
+        // Without anti-windup the integral would be 1000 and
+        // the output would stay saturated for a long time.
+        assert_eq!(pid.update(0., 1., 1.), -1.);
+    }
+}
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+/// Initial variance of the velocity estimate of the Kalman filter in (microsteps/s)^2.
+const VELOCITY_VARIANCE_INIT: f64 = 1e12;
+
+/// Model used to extrapolate the targets in `ControlMode::Tracking`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum PredictorConfig {
+    /// Velocity from the difference of the last two targets.
+    ConstantVelocity,
+    /// Kalman filter with a constant velocity model, which smoothes the noise
+    /// of the targets. The noise parameters are variances in microsteps.
+    Kalman {
+        process_noise: f64,
+        measurement_noise: f64,
+    },
+}
+
+/// Extrapolates a target by the latency between reading the voltages and the axis moving.
+#[derive(Clone, Debug)]
+pub struct Predictor {
+    config: PredictorConfig,
+    /// Estimated position in microsteps and velocity in microsteps/s.
+    state: Option<[f64; 2]>,
+    covariance: [[f64; 2]; 2],
+}
+
+impl Predictor {
+    pub fn new(config: PredictorConfig) -> Self {
+        Self {
+            config,
+            state: None,
+            covariance: [[0.; 2]; 2],
+        }
+    }
+
+    /// Returns `target` extrapolated by `latency` seconds,
+    /// `dt` is the time in seconds since the previous target.
+    pub fn predict(&mut self, target: u32, dt: f64, latency: f64) -> u32 {
+        let z = target as f64;
+        let [pos, vel] = match (self.config.clone(), self.state) {
+            (PredictorConfig::Kalman { measurement_noise, .. }, None) => {
+                // The velocity is unknown until the second target
+                self.covariance = [[measurement_noise, 0.], [0., VELOCITY_VARIANCE_INIT]];
+                [z, 0.]
+            }
+            (_, None) => [z, 0.],
+            (_, Some(state)) if dt <= 0. => state,
+            (PredictorConfig::ConstantVelocity, Some([pos, _])) => [z, (z - pos) / dt],
+            (
+                PredictorConfig::Kalman {
+                    process_noise,
+                    measurement_noise,
+                },
+                Some(state),
+            ) => self.update_kalman(state, z, dt, process_noise, measurement_noise),
+        };
+        self.state = Some([pos, vel]);
+
+        (pos + vel * latency).round().max(0.) as u32
+    }
+
+    fn update_kalman(&mut self, [pos, vel]: [f64; 2], z: f64, dt: f64, q: f64, r: f64) -> [f64; 2] {
+        // Prediction with the constant velocity model
+        let pos = pos + vel * dt;
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let p00 = p00 + dt * (p10 + p01) + dt * dt * p11 + q * dt.powi(4) / 4.;
+        let p01 = p01 + dt * p11 + q * dt.powi(3) / 2.;
+        let p10 = p10 + dt * p11 + q * dt.powi(3) / 2.;
+        let p11 = p11 + q * dt * dt;
+
+        // Correction with the measured target
+        let s = p00 + r;
+        let [k0, k1] = [p00 / s, p10 / s];
+        let innovation = z - pos;
+        self.covariance = [
+            [(1. - k0) * p00, (1. - k0) * p01],
+            [p10 - k1 * p00, p11 - k1 * p01],
+        ];
+
+        [pos + k0 * innovation, vel + k1 * innovation]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predictor_constant_velocity() {
+        let mut predictor = Predictor::new(PredictorConfig::ConstantVelocity);
+
+        assert_eq!(predictor.predict(1000, 0., 0.05), 1000);
+        assert_eq!(predictor.predict(1100, 0.1, 0.05), 1150);
+        assert_eq!(predictor.predict(1200, 0.1, 0.05), 1250);
+
+        // Not extrapolated below zero
+        assert_eq!(predictor.predict(0, 0.1, 1.), 0);
+    }
+
+    #[test]
+    fn test_predictor_kalman() {
+        let mut predictor = Predictor::new(PredictorConfig::Kalman {
+            process_noise: 1e3,
+            measurement_noise: 1.,
+        });
+
+        // Ramp with 1000 microsteps/s
+        let mut predicted = 0;
+        for i in 0..100 {
+            predicted = predictor.predict(i * 100, 0.1, 0.1);
+        }
+        assert!(predicted.abs_diff(100 * 100) < 20);
+    }
+}
@@ -1,46 +1,174 @@
-use std::{borrow::BorrowMut, cell::RefCell, rc::Rc};
+use std::f64::consts::PI;
 
-use crate::{control::run, utils::ExecState};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 
-pub fn init_ramp(state: &mut ExecState) -> Result<()> {
-    let counter_true = Rc::new(RefCell::new(0));
-    let counter = Rc::new(RefCell::new((0, 0)));
-    let get_target = || get_target_ramp(Rc::clone(&counter_true));
-    let get_pos = || get_pos_ramp(Rc::clone(&counter));
-    let move_parallel = |pos| move_parallel_ramp(Rc::clone(&counter), pos);
-    let move_cross = |pos| move_cross_ramp(Rc::clone(&counter), pos);
+use crate::{
+    control::{CycleData, TargetSource},
+    zaber::mm_to_steps,
+};
 
-    return run(state, get_target, get_pos, move_parallel, move_cross);
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WaveformShape {
+    Sine,
+    Triangle,
+    Square,
+    /// Sine with the frequency rising linearly from `frequency` to `frequency_end` within `duration`.
+    Chirp,
+    /// `offset` until `1 / frequency` seconds passed, `offset + amplitude` afterwards.
+    Step,
 }
 
-fn get_target_ramp(mut counter: Rc<RefCell<u32>>) -> Result<(u32, u32)> {
-    let c = counter.borrow_mut();
-    let mut f: u32 = c.take() + 10;
-    if f > 100 {
-        f = 0;
+/// Signal driving one axis in `ControlMode::Waveform`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WaveformConfig {
+    pub shape: WaveformShape,
+    /// Amplitude in mm.
+    pub amplitude: f64,
+    /// Offset in mm.
+    pub offset: f64,
+    /// Frequency in Hz.
+    pub frequency: f64,
+    /// End frequency of a chirp in Hz.
+    #[serde(default)]
+    pub frequency_end: f64,
+    /// Duration in seconds after which the axis stays at the offset, 0 runs endlessly.
+    /// A chirp needs a duration.
+    #[serde(default)]
+    pub duration: f64,
+}
+
+impl WaveformConfig {
+    /// Returns the value of the signal in mm at `time` seconds after the start.
+    pub fn value(&self, time: f64) -> f64 {
+        if self.duration > 0. && time > self.duration {
+            return self.offset;
+        }
+
+        let phase = self.frequency * time;
+        let signal = match self.shape {
+            WaveformShape::Sine => (2. * PI * phase).sin(),
+            WaveformShape::Triangle => 2. / PI * (2. * PI * phase).sin().asin(),
+            WaveformShape::Square => match phase.fract() < 0.5 {
+                true => 1.,
+                false => -1.,
+            },
+            WaveformShape::Chirp => {
+                let rate = (self.frequency_end - self.frequency) / self.duration;
+                (2. * PI * (phase + rate / 2. * time * time)).sin()
+            }
+            WaveformShape::Step => match self.frequency > 0. && time < 1. / self.frequency {
+                true => 0.,
+                false => 1.,
+            },
+        };
+
+        self.offset + self.amplitude * signal
     }
-    c.replace(f);
+}
 
-    return Ok((f, 0));
+/// Drives the axes with the configured waveforms in `ControlMode::Waveform`.
+/// Axes without a waveform hold the position they had at the start.
+#[derive(Clone, Debug)]
+pub struct Waveform {
+    configs: Vec<Option<WaveformConfig>>,
+    hold: Vec<Option<u32>>,
+    time: f64,
 }
 
-fn get_pos_ramp(mut counter: Rc<RefCell<(u32, u32)>>) -> Result<(u32, u32, bool, bool)> {
-    let c = counter.borrow_mut();
-    let f: (u32, u32) = c.take();
-    return Ok((f.0, f.1, false, false));
+impl Waveform {
+    /// Takes the waveforms of the axes in the order of their indices.
+    pub fn new(configs: Vec<Option<WaveformConfig>>) -> Result<Self> {
+        if configs.iter().all(|c| c.is_none()) {
+            return Err(anyhow!("No waveform configured for any axis"));
+        }
+
+        Ok(Self {
+            hold: vec![None; configs.len()],
+            configs,
+            time: 0.,
+        })
+    }
 }
 
-fn move_parallel_ramp(mut counter: Rc<RefCell<(u32, u32)>>, pos: u32) -> Result<()> {
-    let c = counter.borrow_mut();
-    let f: (u32, u32) = c.take();
-    c.replace((pos, f.1));
-    return Ok(());
+impl TargetSource for Waveform {
+    fn get_targets(&mut self, cycle: &CycleData) -> Result<Vec<u32>> {
+        self.time += cycle.dt;
+        let time = self.time;
+
+        Ok(self
+            .configs
+            .iter()
+            .zip(self.hold.iter_mut())
+            .enumerate()
+            .map(|(i, (config, hold))| match config {
+                Some(config) => mm_to_steps(config.value(time)),
+                None => *hold.get_or_insert(cycle.positions[i]),
+            })
+            .collect())
+    }
 }
 
-fn move_cross_ramp(mut counter: Rc<RefCell<(u32, u32)>>, pos: u32) -> Result<()> {
-    let c = counter.borrow_mut();
-    let f: (u32, u32) = c.take();
-    c.replace((f.0, pos));
-    return Ok(());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(shape: WaveformShape) -> WaveformConfig {
+        WaveformConfig {
+            shape,
+            amplitude: 2.,
+            offset: 10.,
+            frequency: 1.,
+            frequency_end: 3.,
+            duration: 4.,
+        }
+    }
+
+    #[test]
+    fn test_waveform_values() {
+        let eps = 1e-9;
+
+        let sine = config(WaveformShape::Sine);
+        assert!((sine.value(0.) - 10.).abs() < eps);
+        assert!((sine.value(0.25) - 12.).abs() < eps);
+        assert!((sine.value(0.75) - 8.).abs() < eps);
+
+        let triangle = config(WaveformShape::Triangle);
+        assert!((triangle.value(0.125) - 11.).abs() < eps);
+        assert!((triangle.value(0.25) - 12.).abs() < eps);
+
+        let square = config(WaveformShape::Square);
+        assert_eq!(square.value(0.1), 12.);
+        assert_eq!(square.value(0.6), 8.);
+
+        let step = config(WaveformShape::Step);
+        assert_eq!(step.value(0.5), 10.);
+        assert_eq!(step.value(1.5), 12.);
+
+        let chirp = config(WaveformShape::Chirp);
+        assert!((chirp.value(0.) - 10.).abs() < eps);
+
+        // After the duration the offset is kept
+        assert_eq!(sine.value(5.), 10.);
+        assert_eq!(step.value(5.), 10.);
+    }
+
+    #[test]
+    fn test_waveform_hold() {
+        let mut waveform =
+            Waveform::new(vec![Some(config(WaveformShape::Square)), None]).unwrap();
+        let mut cycle = CycleData {
+            voltages: [0.; 2],
+            positions: vec![100, 200],
+            is_busy: vec![false; 2],
+            time: 0.,
+            dt: 0.1,
+        };
+
+        assert_eq!(waveform.get_targets(&cycle).unwrap(), vec![mm_to_steps(12.), 200]);
+        cycle.positions = vec![300, 400];
+        assert_eq!(waveform.get_targets(&cycle).unwrap(), vec![mm_to_steps(12.), 200]);
+
+        assert!(Waveform::new(vec![None, None]).is_err());
+    }
 }
@@ -0,0 +1,194 @@
+use std::{io, num::ParseIntError, time::Duration};
+
+use anyhow::Result;
+use zproto::error::AsciiError;
+
+use crate::{utils::Config, zaber::InvalidReply};
+
+/// Classes of errors of the Zaber communication, which are retried differently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// No reply was received in time.
+    Timeout,
+    /// A reply was received but could not be parsed or did not belong to the command.
+    MalformedReply,
+    /// Any other error, which is not retried.
+    Other,
+}
+
+/// Classifies `error` by the types of its causes, e.g. rejected commands are `Other`.
+pub fn classify(error: &anyhow::Error) -> ErrorClass {
+    let timeout = |e: &io::Error| match e.kind() {
+        io::ErrorKind::TimedOut => ErrorClass::Timeout,
+        _ => ErrorClass::Other,
+    };
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return timeout(e);
+        }
+        // All other errors of the protocol are replies which could not be parsed, had a
+        // wrong checksum or did not belong to the command
+        if let Some(e) = cause.downcast_ref::<AsciiError>() {
+            return match e {
+                AsciiError::Io(e) => timeout(e),
+                _ => ErrorClass::MalformedReply,
+            };
+        }
+        if cause.is::<ParseIntError>() || cause.is::<InvalidReply>() {
+            return ErrorClass::MalformedReply;
+        }
+    }
+    ErrorClass::Other
+}
+
+/// How often and with which back-off an error class is retried.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryClass {
+    pub count: u32,
+    /// Wait time before the first retry, it is doubled for every further retry.
+    pub backoff: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub timeout: RetryClass,
+    pub malformed: RetryClass,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            timeout: RetryClass {
+                count: config.retry_timeout_count,
+                backoff: config.retry_timeout_backoff_ms,
+            },
+            malformed: RetryClass {
+                count: config.retry_malformed_count,
+                backoff: config.retry_malformed_backoff_ms,
+            },
+        }
+    }
+
+    /// Calls `func` until it succeeds or the retries of the error class are exhausted.
+    /// `recovered` is incremented if `func` succeeded after a retry.
+    pub fn run<T>(&self, recovered: &mut u64, mut func: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut retries_timeout = 0;
+        let mut retries_malformed = 0;
+        loop {
+            let error = match func() {
+                Ok(val) => {
+                    if retries_timeout + retries_malformed > 0 {
+                        *recovered += 1;
+                    }
+                    return Ok(val);
+                }
+                Err(e) => e,
+            };
+
+            let class = classify(&error);
+            let (retry, retries) = match class {
+                ErrorClass::Timeout => (&self.timeout, &mut retries_timeout),
+                ErrorClass::MalformedReply => (&self.malformed, &mut retries_malformed),
+                ErrorClass::Other => return Err(error),
+            };
+
+            if *retries >= retry.count {
+                return Err(error.context(format!(
+                    "{:?} error persisted after {} retries",
+                    class, retry.count
+                )));
+            }
+
+            tracing::warn!("retrying after {:?} error: {:#}", class, error);
+            std::thread::sleep(retry.backoff * 2u32.pow(*retries));
+            *retries += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zaber::Rejected;
+    use anyhow::anyhow;
+
+    fn policy() -> RetryPolicy {
+        let retry = RetryClass {
+            count: 2,
+            backoff: Duration::ZERO,
+        };
+        RetryPolicy {
+            timeout: retry,
+            malformed: retry,
+        }
+    }
+
+    fn timeout() -> anyhow::Error {
+        io::Error::new(io::ErrorKind::TimedOut, "Simulated timeout error").into()
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(&timeout()), ErrorClass::Timeout);
+        assert_eq!(
+            classify(&"x".parse::<u32>().unwrap_err().into()),
+            ErrorClass::MalformedReply
+        );
+        assert_eq!(
+            classify(&anyhow::Error::new(InvalidReply("No position returned".into()))),
+            ErrorClass::MalformedReply
+        );
+        let error = AsciiError::Io(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        assert_eq!(classify(&anyhow::Error::new(error)), ErrorClass::Timeout);
+
+        // Messages are not classified, even if they sound like a malformed reply
+        assert_eq!(classify(&anyhow!("invalid or unexpected")), ErrorClass::Other);
+        let rejected = Rejected {
+            reason: "BADDATA".into(),
+        };
+        assert_eq!(classify(&anyhow::Error::new(rejected)), ErrorClass::Other);
+    }
+
+    #[test]
+    fn test_retry_recovered() {
+        let mut recovered = 0;
+        let mut calls = 0;
+        let result = policy().run(&mut recovered, || {
+            calls += 1;
+            match calls {
+                1 | 2 => Err(timeout()),
+                _ => Ok(calls),
+            }
+        });
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(recovered, 1);
+    }
+
+    #[test]
+    fn test_retry_exhausted() {
+        let mut recovered = 0;
+        let mut calls = 0;
+        let result: Result<()> = policy().run(&mut recovered, || {
+            calls += 1;
+            Err(timeout())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+        assert_eq!(recovered, 0);
+    }
+
+    #[test]
+    fn test_retry_other() {
+        let mut recovered = 0;
+        let mut calls = 0;
+        let result: Result<()> = policy().run(&mut recovered, || {
+            calls += 1;
+            Err(anyhow!("command rejected"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -3,11 +3,15 @@ const MAX_POS = 201574; // microsteps
 var globals = {
     /** @type {?WebSocket} */
     socket: null,
-    /** @type {'Tracking' | 'Manual'} */
+    /** @type {'Tracking' | 'Manual' | 'ClosedLoop' | 'Sequence' | 'Waveform'} */
     controlMode: 'Tracking',
     /** @type {?string} */
     errorMessage: null,
     stopTriggered: false,
+    /** @type {string[]} Names of the configured axes */
+    axes: [],
+    /** Whether the sliders were set to the targets since entering `Manual` */
+    manualSynced: false,
 };
 
 
@@ -27,24 +31,9 @@ function handleClickSaveConfig() {
         return;
     }
 
+    // The values are sent in the units of the labels, the server converts them
     let data = Object.fromEntries(new FormData($form));
-    for (let [key, val] of Object.entries(data)) {
-        if (['limit_max_coax', 'limit_min_coax', 'limit_min_cross', 'limit_max_cross'].includes(key)) {
-            val = mm2steps(val);
-        }
-
-        if (['accel_cross', 'accel_coax'].includes(key)) {
-            val = accel2steps(val);
-        }
-
-        if (['maxspeed_cross', 'maxspeed_coax'].includes(key)) {
-            val = vel2steps(val);
-        }
-
-        data[key] = val;
-    }
     data['control_mode'] = globals.controlMode;
-    data['mock_zaber'] = false;
 
     fetch('/config', {
         method: 'POST',
@@ -55,21 +44,38 @@ function handleClickSaveConfig() {
     })
         .then(x => {
             if (x.ok) {
-                alert('New config loaded');
-                loadConfig();
-                return;
+                return x.json().then(update => {
+                    let message = 'New config loaded';
+                    if (update['immediate'].length > 0) {
+                        message += '\nApplied immediately: ' + update['immediate'].join(', ');
+                    }
+                    if (update['next_start'].length > 0) {
+                        message += '\nApplied at the next start: ' + update['next_start'].join(', ');
+                    }
+                    alert(message);
+                    loadConfig();
+                    return null;
+                });
             }
 
             return x.text();
         })
         .then(x => {
+            if (x == null) {
+                return;
+            }
             if (!x.includes(':')) {
                 alert('Error while loading new config:\n' + x);
                 return;
             }
 
-            const [name, msg] = x.split(':');
-            let $inp = document.querySelector(`[name=${name}]`);
+            const name = x.slice(0, x.indexOf(':'));
+            const msg = x.slice(x.indexOf(':') + 1);
+            let $inp = document.querySelector(`[name="${name}"]`);
+            if ($inp == null) {
+                alert('Error while loading new config:\n' + x);
+                return;
+            }
             $inp.classList.add('invalid');
             $inp.onchange = () => {
                 $inp.classList.remove('invalid');
@@ -80,8 +86,9 @@ function handleClickSaveConfig() {
 }
 
 function handleClickStart() {
-    document.querySelector('#inp-pos-target-coax').value = steps2mm(document.querySelector('#inp-pos-coax').value);
-    document.querySelector('#inp-pos-target-cross').value = steps2mm(document.querySelector('#inp-pos-cross').value);
+    for (const axis of globals.axes) {
+        document.querySelector(`#inp-pos-target-${axis}`).value = steps2mm(document.querySelector(`#inp-pos-${axis}`).value);
+    }
     fetch('/start', {
         method: 'POST',
     }).then(() => {
@@ -100,6 +107,72 @@ function handleClickStop() {
     });
 }
 
+function handleClickEstop() {
+    // The WebSocket is already connected, so it is the fastest way to the server
+    if (globals.socket != null && globals.socket.readyState === WebSocket.OPEN) {
+        globals.socket.send('estop');
+        return;
+    }
+
+    fetch('/estop', {
+        method: 'POST',
+    });
+}
+
+function handleClickEstopReset() {
+    fetch('/estop/reset', {
+        method: 'POST',
+    })
+        .then(x => x.ok ? null : x.text())
+        .then(x => {
+            if (x) {
+                alert('Error while resetting the emergency stop:\n' + x);
+            }
+        });
+}
+
+/**
+ * Lists the errors and warnings of the history, the most recent one first.
+ * @param {{id: number, severity: string, code: ?number, message: string, last: string, count: number, acknowledged: boolean}[]} entries
+ */
+function renderHistory(entries) {
+    const $list = document.querySelector('#list-history');
+    $list.innerHTML = '';
+    for (const entry of [...entries].reverse()) {
+        const $entry = document.createElement('div');
+        const time = new Date(entry.last).toLocaleString();
+        const code = entry.code != null ? ` [${entry.code}]` : '';
+        $entry.textContent = `${time} ${entry.severity}${code}: ${entry.message} (${entry.count}x)`;
+        if (!entry.acknowledged) {
+            const $btn = document.createElement('button');
+            $btn.className = 'slim';
+            $btn.textContent = 'Acknowledge';
+            $btn.onclick = () => postHistory(`acknowledge/${entry.id}`);
+            $entry.append(' ', $btn);
+        }
+        $list.append($entry);
+    }
+}
+
+function loadHistory() {
+    fetch('/history')
+        .then(x => x.json())
+        .then(renderHistory);
+}
+
+function postHistory(path) {
+    fetch('/history/' + path, {
+        method: 'POST',
+    })
+        .then(async x => {
+            if (!x.ok) {
+                alert('Error while changing the history:\n' + await x.text());
+                return;
+            }
+            renderHistory(await x.json());
+        });
+}
+
 function handleMousedownSliderPos(slider) {
     document.querySelector(`#inp-pos-target-${slider}`).classList.add('working');
 }
@@ -118,41 +191,190 @@ function handleChangeTarget(axis) {
 
 function sendTargetPosition() {
     console.assert(globals.socket != null, 'Websocket not initialized');
-    const posCoax = document.querySelector('#inp-pos-coax').value
-    const posCross = document.querySelector('#inp-pos-cross').value
-    globals.socket.send(posCoax + ' ' + posCross);
+    const positions = globals.axes.map(axis => document.querySelector(`#inp-pos-${axis}`).value);
+    globals.socket.send(positions.join(' '));
+}
+
+/**
+ * Creates the sliders and the config fields of the axes, if they changed.
+ * @param {{name: string}[]} axes
+ */
+function renderAxes(axes) {
+    const names = axes.map(axis => axis.name);
+    if (names.join(' ') === globals.axes.join(' ')) {
+        return;
+    }
+    globals.axes = names;
+
+    const $contAxes = document.querySelector('#cont-axes');
+    const $contAxesConfig = document.querySelector('#cont-axes-config');
+    $contAxes.replaceChildren();
+    $contAxesConfig.replaceChildren();
+
+    names.forEach((name, i) => {
+        const $slider = document.querySelector('#tmpl-axis-slider').content.cloneNode(true);
+        $slider.querySelector('.lbl-axis').textContent = name;
+        for (const cls of ['inp-pos', 'inp-pos-min', 'inp-pos-max', 'inp-pos-target', 'inp-pos-actual']) {
+            $slider.querySelector('.' + cls).id = `${cls}-${name}`;
+        }
+
+        const $inpTarget = $slider.querySelector('.inp-pos-target');
+        const $inpSlider = $slider.querySelector('.inp-pos');
+        $inpSlider.addEventListener('input', (e) => {
+            $inpTarget.value = steps2mm(e.currentTarget.value);
+        });
+        $inpSlider.addEventListener('mousedown', () => handleMousedownSliderPos(name));
+        $inpSlider.addEventListener('mouseup', () => handleMouseupSliderPos(name));
+        $inpTarget.addEventListener('change', function () {
+            handleChangeTarget.bind(this)(name);
+        });
+        $contAxes.appendChild($slider);
+
+        const $config = document.querySelector('#tmpl-axis-config').content.cloneNode(true);
+        $config.querySelector('legend').textContent = name;
+        for (const $inp of $config.querySelectorAll('[data-field]')) {
+            $inp.name = `axes[${i}].${$inp.dataset.field}`;
+        }
+        $contAxesConfig.appendChild($config);
+    });
+
+    const $selAxis = document.querySelector('#sel-calibration-axis');
+    $selAxis.replaceChildren();
+    names.forEach((name, i) => {
+        const $option = document.createElement('option');
+        $option.value = i;
+        $option.textContent = name;
+        $selAxis.appendChild($option);
+    });
+}
+
+/**
+ * Shows the captured points of the calibration, with the residuals of a fit if given.
+ * @param {{axis: number, voltage: number, points: number[][]}} session
+ * @param {?number[]} residuals
+ */
+function renderCalibration(session, residuals = null) {
+    const $tbody = document.querySelector('#tbl-calibration tbody');
+    $tbody.replaceChildren();
+    session.points.forEach(([voltage, position], i) => {
+        const $row = document.createElement('tr');
+        for (const val of [voltage.toFixed(4), position.toFixed(3), residuals?.[i]?.toFixed(4) ?? '-']) {
+            const $cell = document.createElement('td');
+            $cell.textContent = val;
+            $row.appendChild($cell);
+        }
+        const $btn = document.createElement('button');
+        $btn.className = 'slim';
+        $btn.textContent = 'Remove';
+        $btn.onclick = () => handleClickRemoveCalibrationPoint(i);
+        const $cell = document.createElement('td');
+        $cell.appendChild($btn);
+        $row.appendChild($cell);
+        $tbody.appendChild($row);
+    });
+    if (residuals == null) {
+        document.querySelector('#inp-calibration-formula').value = '';
+        document.querySelector('#inp-calibration-residual').value = '';
+    }
+}
+
+/**
+ * Sends a calibration request and shows the error if it fails.
+ * @param {string} path
+ * @param {?Object} data
+ * @returns {Promise<?Object>}
+ */
+function postCalibration(path, data = null) {
+    return fetch('/calibration/' + path, {
+        method: 'POST',
+        body: data ? new URLSearchParams(data) : null,
+        headers: {
+            "Content-Type": "application/x-www-form-urlencoded",
+        },
+    })
+        .then(async x => {
+            if (!x.ok) {
+                alert('Calibration failed:\n' + await x.text());
+                return null;
+            }
+            return x.headers.get('content-type')?.includes('json') ? x.json() : null;
+        });
+}
+
+function handleClickCapture() {
+    postCalibration('capture', {
+        axis: document.querySelector('#sel-calibration-axis').value,
+        voltage: document.querySelector('#sel-calibration-voltage').value,
+    }).then(x => x && renderCalibration(x));
+}
+
+function handleClickRemoveCalibrationPoint(i) {
+    postCalibration('remove/' + i).then(x => x && renderCalibration(x));
+}
+
+function handleClickClearCalibration() {
+    postCalibration('clear').then(() => loadCalibration());
+}
+
+function handleClickPreviewCalibration() {
+    const model = document.querySelector('#sel-calibration-model').value;
+    postCalibration('fit', { model }).then(fit => {
+        if (fit == null) {
+            return;
+        }
+        fetch('/calibration')
+            .then(x => x.json())
+            .then(session => renderCalibration(session, fit['residuals']));
+        document.querySelector('#inp-calibration-formula').value = fit['formula'] ?? 'Lookup table';
+        document.querySelector('#inp-calibration-residual').value =
+            fit['rms'].toFixed(4) + ' / ' + fit['max_residual'].toFixed(4);
+    });
+}
+
+function handleClickApplyCalibration() {
+    const model = document.querySelector('#sel-calibration-model').value;
+    postCalibration('apply', { model }).then(fit => {
+        if (fit != null) {
+            alert('Calibration written to the config');
+            loadConfig();
+        }
+    });
+}
+
+function loadCalibration() {
+    fetch('/calibration')
+        .then(x => x.json())
+        .then(x => renderCalibration(x));
 }
 
 function loadConfig() {
     fetch('/config')
         .then(x => x.json())
         .then(x => {
-            document.querySelector('#inp-pos-min-coax').value = steps2mm(x['limit_min_coax']);
-            document.querySelector('#inp-pos-max-coax').value = steps2mm(x['limit_max_coax']);
-            document.querySelector('#inp-pos-coax').min = x['limit_min_coax'];
-            document.querySelector('#inp-pos-coax').max = x['limit_max_coax'];
-
-            document.querySelector('#inp-pos-min-cross').value = steps2mm(x['limit_min_cross']);
-            document.querySelector('#inp-pos-max-cross').value = steps2mm(x['limit_max_cross']);
-            document.querySelector('#inp-pos-cross').min = x['limit_min_cross'];
-            document.querySelector('#inp-pos-cross').max = x['limit_max_cross'];
-
-            for (let [key, val] of Object.entries(x)) {
-                if (['limit_max_coax', 'limit_min_coax', 'limit_min_cross', 'limit_max_cross'].includes(key)) {
-                    val = steps2mm(val);
-                }
+            renderAxes(x['axes']);
 
-                if (['accel_cross', 'accel_coax'].includes(key)) {
-                    val = steps2accel(val);
-                }
+            let entries = Object.entries(x).filter(([key, _]) => key !== 'axes');
+            x['axes'].forEach((axis, i) => {
+                document.querySelector(`#inp-pos-min-${axis.name}`).value = axis['limit_min'];
+                document.querySelector(`#inp-pos-max-${axis.name}`).value = axis['limit_max'];
+                document.querySelector(`#inp-pos-${axis.name}`).min = mm2steps(axis['limit_min']);
+                document.querySelector(`#inp-pos-${axis.name}`).max = mm2steps(axis['limit_max']);
 
-                if (['maxspeed_cross', 'maxspeed_coax'].includes(key)) {
-                    val = steps2vel(val);
+                for (const [field, val] of Object.entries(axis)) {
+                    entries.push([`axes[${i}].${field}`, val]);
+                }
+                // Nested settings like the calibration are named like `axes[0].calibration.path`
+                for (const [sub, val] of Object.entries(axis['calibration'] ?? {})) {
+                    entries.push([`axes[${i}].calibration.${sub}`, val]);
                 }
+            });
 
+            for (const [key, val] of entries) {
                 const $inp = document.querySelector(`[name="${key}"]`);
-                if ($inp != null) {
-                    $inp.value = val;
+                if ($inp != null && $inp.type === 'checkbox') {
+                    $inp.checked = val;
+                } else if ($inp != null) {
+                    $inp.value = val ?? '';
                 }
             }
 
@@ -174,15 +396,21 @@ function handleClickChangeMode() {
     const mode = document.querySelector('[name=control_mode]').value;
     fetch('/mode/' + mode, {
         method: 'POST',
-    });
-
-    loadConfig();
+    })
+        .then(x => x.ok ? null : x.text())
+        .then(x => {
+            if (x) {
+                alert('Error while changing the control mode:\n' + x);
+            }
+            loadConfig();
+        });
 }
 
 function connectWebsocket() {
     globals.socket = new WebSocket(`ws://${IP_ADDR}:${PORT}/ws`);
     let $btnStart = document.querySelector('#btn-start');
     let $btnStop = document.querySelector('#btn-stop');
+    let $btnEstopReset = document.querySelector('#btn-estop-reset');
 
     if (globals.errorMessage != null) {
         resetError();
@@ -193,46 +421,66 @@ function connectWebsocket() {
         const state = data['control_state'];
         document.querySelector('#control_state').value = state;
 
-        if (data['busy_coax']) {
-            document.querySelector('#inp-pos-actual-coax').classList.add('working');
-        } else {
-            document.querySelector('#inp-pos-actual-coax').classList.remove('working');
-        }
-        if (data['busy_cross']) {
-            document.querySelector('#inp-pos-actual-cross').classList.add('working');
-        } else {
-            document.querySelector('#inp-pos-actual-cross').classList.remove('working');
-        }
+        globals.axes.forEach((axis, i) => {
+            document.querySelector(`#inp-pos-actual-${axis}`).classList.toggle('working', data['is_busy'][i] ?? false);
+
+            const $target = document.querySelector(`#inp-pos-target-${axis}`);
+            $target.classList.toggle('invalid', data['out_of_limits'][i] ?? false);
+            $target.title = `Targets outside of the limits: ${data['limit_violations'][i] ?? 0}`;
+        });
+        $btnEstopReset.hidden = state !== 'EmergencyStopped';
         switch (state) {
             case 'Running':
                 $btnStart.hidden = true;
                 $btnStop.hidden = false;
                 document.querySelector('#inp-voltage1').value = data['voltage'][0];
                 document.querySelector('#inp-voltage2').value = data['voltage'][1];
-                document.querySelector('#inp-pos-actual-coax').value = steps2mm(data['position'][0]);
-                document.querySelector('#inp-pos-actual-cross').value = steps2mm(data['position'][1]);
-        
-                if (globals.controlMode === 'Tracking') {
-                    document.querySelector('#inp-pos-coax').disabled = true;
-                    document.querySelector('#inp-pos-cross').disabled = true;
-                    document.querySelector('#inp-pos-target-coax').disabled = true;
-                    document.querySelector('#inp-pos-target-cross').disabled = true;
-                    document.querySelector('#inp-pos-target-coax').value = steps2mm(data['target'][0]);
-                    document.querySelector('#inp-pos-target-cross').value = steps2mm(data['target'][1]);
-                } else {
-                    document.querySelector('#inp-pos-coax').disabled = false;
-                    document.querySelector('#inp-pos-cross').disabled = false;
-                    document.querySelector('#inp-pos-target-coax').disabled = false;
-                    document.querySelector('#inp-pos-target-cross').disabled = false;
-                }
+                document.querySelector('#inp-sequence-step').value = data['sequence_step'] ?? '-';
+                document.querySelector('#inp-cycle-time').value =
+                    `${data['timing']['period']['mean'].toFixed(1)} / ${data['timing']['period']['p99'].toFixed(1)}`;
+                document.querySelector('#inp-overruns').value = data['timing']['overruns'];
+                globals.axes.forEach((axis, i) => {
+                    const enabled = data['enabled'][i] ?? false;
+                    document.querySelector(`#inp-pos-actual-${axis}`).value =
+                        enabled ? steps2mm(data['position'][i]) : 'disabled';
+
+                    if (globals.controlMode !== 'Manual') {
+                        document.querySelector(`#inp-pos-${axis}`).disabled = true;
+                        document.querySelector(`#inp-pos-${axis}`).value = data['target'][i];
+                        document.querySelector(`#inp-pos-target-${axis}`).disabled = true;
+                        document.querySelector(`#inp-pos-target-${axis}`).value = steps2mm(data['target'][i]);
+                    } else {
+                        // The control starts at the positions, the sliders must not send stale targets
+                        if (!globals.manualSynced) {
+                            document.querySelector(`#inp-pos-${axis}`).value = data['target'][i];
+                            document.querySelector(`#inp-pos-target-${axis}`).value = steps2mm(data['target'][i]);
+                        }
+                        document.querySelector(`#inp-pos-${axis}`).disabled = !enabled;
+                        document.querySelector(`#inp-pos-target-${axis}`).disabled = !enabled;
+                    }
+                });
+                globals.manualSynced = globals.controlMode === 'Manual';
+                break;
+            case 'EmergencyStopped':
+                $btnStart.hidden = true;
+                $btnStop.hidden = true;
+                globals.manualSynced = false;
+                initInputs('Stopped');
+                document.querySelector('#control_state').value = `E-Stop (${data['estop']?.['source'] ?? '-'})`;
                 break;
             case 'Error':
-                if(globals.errorMessage !== data['error']) {
-                    globals.errorMessage = data['error'];
+                // The error carries a code and the message with all of its causes
+                const errorMessage = data['error'] ? `[${data['error']['code']}] ${data['error']['message']}` : null;
+                if(globals.errorMessage !== errorMessage) {
+                    globals.errorMessage = errorMessage;
                     document.querySelector('#btn-show-error').style.visibility = 'visible';
                     alert(globals.errorMessage);
+                    if (document.querySelector('#cont-history').open) {
+                        loadHistory();
+                    }
                 }
             default:
+                globals.manualSynced = false;
                 $btnStart.hidden = false;
                 $btnStop.hidden = true;
                 initInputs('Stopped');
@@ -263,16 +511,17 @@ function connectWebsocket() {
  */
 function initInputs(state) {
     document.querySelector('#control_state').value = state;
-    document.querySelector('#inp-pos-target-coax').value = '-';
-    document.querySelector('#inp-pos-target-cross').value = '-';
-    document.querySelector('#inp-pos-actual-coax').value = '-';
-    document.querySelector('#inp-pos-actual-cross').value = '-';
     document.querySelector('#inp-voltage1').value = '-';
     document.querySelector('#inp-voltage2').value = '-';
-    document.querySelector('#inp-pos-coax').disabled = true;
-    document.querySelector('#inp-pos-cross').disabled = true;
-    document.querySelector('#inp-pos-target-coax').disabled = true;
-    document.querySelector('#inp-pos-target-cross').disabled = true;
+    document.querySelector('#inp-sequence-step').value = '-';
+    document.querySelector('#inp-cycle-time').value = '-';
+    document.querySelector('#inp-overruns').value = '-';
+    for (const axis of globals.axes) {
+        document.querySelector(`#inp-pos-target-${axis}`).value = '-';
+        document.querySelector(`#inp-pos-actual-${axis}`).value = '-';
+        document.querySelector(`#inp-pos-${axis}`).disabled = true;
+        document.querySelector(`#inp-pos-target-${axis}`).disabled = true;
+    }
 }
 
 function steps2mm(steps) {
@@ -283,36 +532,9 @@ function mm2steps(millis) {
     return Math.round(millis * 1000. / MICROSTEP_SIZE);
 }
 
-function steps2vel(steps) {
-    return steps * MICROSTEP_SIZE / 1.6384 / 1000;
-}
-
-function vel2steps(accel) {
-    return Math.round(accel * 1000 * 1.6384 / MICROSTEP_SIZE);
-}
-
-function steps2accel(steps) {
-    return steps * MICROSTEP_SIZE * 10 / 1.6384;
-}
-
-function accel2steps(accel) {
-    return Math.round(accel * 1.6384 / MICROSTEP_SIZE / 10);
-}
-
 
 document.addEventListener('DOMContentLoaded', () => {
-    const $inpTargetCoax = document.querySelector('#inp-pos-target-coax');
-    const $sliderTargetCoax = document.querySelector('#inp-pos-coax');
-    $sliderTargetCoax.addEventListener('input', (e) => {
-        $inpTargetCoax.value = steps2mm(e.currentTarget.value);
-    })
-
-    const $inpTargetCross = document.querySelector('#inp-pos-target-cross');
-    const $sliderTargetCross = document.querySelector('#inp-pos-cross');
-    $sliderTargetCross.addEventListener('input', (e) => {
-        $inpTargetCross.value = steps2mm(e.currentTarget.value);
-    })
-
     initInputs('Stopped');
     loadConfig();
+    loadCalibration();
 });
@@ -0,0 +1,330 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    control::{CycleData, TargetSource},
+    utils::SharedState,
+    zaber::{mm_to_steps, vel_to_steps},
+};
+
+/// Single step of a sequence.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Waypoint {
+    /// Targets in mm by the names of the axes,
+    /// axes without a target keep the one of the previous waypoint.
+    #[serde(flatten)]
+    pub targets: BTreeMap<String, f64>,
+    /// Time in seconds to wait after the axes reached the targets.
+    #[serde(default)]
+    pub dwell: f64,
+    /// Max. speed in mm/s to move to this waypoint with, the configured one if not set.
+    #[serde(default)]
+    pub speed: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct SequenceFile {
+    waypoints: Vec<Waypoint>,
+}
+
+/// Reads the waypoints from a CSV file if the extension is `.csv`, otherwise from a TOML file
+/// with a `[[waypoints]]` table per step. `axes` are the names of the configured axes.
+pub fn read_sequence(path: &Path, axes: &[String]) -> Result<Vec<Waypoint>> {
+    let content = std::fs::read_to_string(path)?;
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("csv") => parse_csv(&content, axes),
+        _ => Ok(toml::from_str::<SequenceFile>(&content)?.waypoints),
+    }
+}
+
+/// Parses lines of `<target of each axis>,dwell[,speed]`, the targets are in the order of `axes`.
+/// A header in the first line names the columns instead, e.g. `cross,dwell,speed`.
+/// Empty targets keep the previous ones and lines starting with `#` are skipped.
+pub fn parse_csv(content: &str, axes: &[String]) -> Result<Vec<Waypoint>> {
+    let mut columns: Vec<String> = axes
+        .iter()
+        .cloned()
+        .chain(["dwell".into(), "speed".into()])
+        .collect();
+    let mut waypoints = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if i == 0 && fields[0].parse::<f64>().is_err() {
+            let is_known =
+                |f: &str| ["dwell", "speed"].contains(&f) || axes.iter().any(|a| a == f);
+            if let Some(field) = fields.iter().find(|f| !is_known(f)) {
+                return Err(anyhow!("line 1: unknown column '{}'", field));
+            }
+            if !fields.contains(&"dwell") {
+                return Err(anyhow!("line 1: missing column 'dwell'"));
+            }
+            columns = fields.iter().map(|f| f.to_string()).collect();
+            continue;
+        }
+
+        // Only a trailing speed column is optional
+        let required = match columns.last().map(|c| c.as_str()) {
+            Some("speed") => columns.len() - 1,
+            _ => columns.len(),
+        };
+        if fields.len() < required || fields.len() > columns.len() {
+            return Err(anyhow!(
+                "line {}: expected {} or {} values, got {}",
+                i + 1,
+                required,
+                columns.len(),
+                fields.len()
+            ));
+        }
+
+        let parse = |field: &str| {
+            field
+                .parse::<f64>()
+                .map_err(|e| anyhow!("line {}: invalid value '{}': {}", i + 1, field, e))
+        };
+
+        let mut waypoint = Waypoint {
+            targets: BTreeMap::new(),
+            dwell: 0.,
+            speed: None,
+        };
+        for (column, &field) in columns.iter().zip(&fields) {
+            match column.as_str() {
+                "dwell" => waypoint.dwell = parse(field)?,
+                "speed" if !field.is_empty() => waypoint.speed = Some(parse(field)?),
+                "speed" => (),
+                _ if !field.is_empty() => {
+                    waypoint.targets.insert(column.clone(), parse(field)?);
+                }
+                _ => (),
+            }
+        }
+        waypoints.push(waypoint);
+    }
+
+    Ok(waypoints)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Phase {
+    /// The targets of the first step have not been commanded yet.
+    Start,
+    /// Waiting for the axes to reach the targets.
+    Moving,
+    /// Waiting for the dwell time, contains the elapsed time in seconds.
+    Dwelling(f64),
+    Finished,
+}
+
+/// Plays the waypoints one after the other in `ControlMode::Sequence`.
+#[derive(Clone, Debug)]
+pub struct SequencePlayer {
+    waypoints: Vec<Waypoint>,
+    /// Names of the axes in the order of their indices.
+    axes: Vec<String>,
+    /// The last target of each axis, axes without any target yet hold their position.
+    targets: Vec<Option<u32>>,
+    /// Number of times the sequence is played, 0 plays it endlessly.
+    repetitions: u32,
+    step: usize,
+    repetition: u32,
+    phase: Phase,
+}
+
+impl SequencePlayer {
+    pub fn new(waypoints: Vec<Waypoint>, axes: Vec<String>, repetitions: u32) -> Result<Self> {
+        if waypoints.is_empty() {
+            return Err(anyhow!("The sequence does not contain any waypoints"));
+        }
+        for (i, waypoint) in waypoints.iter().enumerate() {
+            if let Some(name) = waypoint.targets.keys().find(|name| !axes.contains(name)) {
+                return Err(anyhow!("Waypoint {}: unknown axis '{}'", i + 1, name));
+            }
+        }
+
+        Ok(Self {
+            waypoints,
+            targets: vec![None; axes.len()],
+            axes,
+            repetitions,
+            step: 0,
+            repetition: 0,
+            phase: Phase::Start,
+        })
+    }
+
+    /// Index of the active waypoint, `None` after the last repetition finished.
+    pub fn step(&self) -> Option<usize> {
+        match self.phase {
+            Phase::Finished => None,
+            _ => Some(self.step),
+        }
+    }
+
+    fn advance(&mut self) {
+        self.step += 1;
+        self.phase = Phase::Moving;
+        if self.step < self.waypoints.len() {
+            return;
+        }
+
+        self.repetition += 1;
+        if self.repetitions != 0 && self.repetition >= self.repetitions {
+            self.step = self.waypoints.len() - 1;
+            self.phase = Phase::Finished;
+        } else {
+            self.step = 0;
+        }
+    }
+}
+
+impl TargetSource for SequencePlayer {
+    fn get_targets(&mut self, cycle: &CycleData) -> Result<Vec<u32>> {
+        // The busy flags are read before the targets are commanded,
+        // so they are only meaningful from the cycle after a new step was started.
+        match self.phase {
+            Phase::Start => self.phase = Phase::Moving,
+            Phase::Moving => {
+                if !cycle.is_busy.contains(&true) {
+                    self.phase = Phase::Dwelling(0.);
+                }
+            }
+            Phase::Dwelling(elapsed) => {
+                let elapsed = elapsed + cycle.dt;
+                if elapsed >= self.waypoints[self.step].dwell {
+                    self.advance();
+                } else {
+                    self.phase = Phase::Dwelling(elapsed);
+                }
+            }
+            Phase::Finished => (),
+        }
+
+        let waypoint = &self.waypoints[self.step];
+        for (i, name) in self.axes.iter().enumerate() {
+            if let Some(&target) = waypoint.targets.get(name) {
+                self.targets[i] = Some(mm_to_steps(target));
+            }
+        }
+
+        Ok(self
+            .targets
+            .iter_mut()
+            .zip(&cycle.positions)
+            .map(|(target, &position)| *target.get_or_insert(position))
+            .collect())
+    }
+
+    fn maxspeeds(&self) -> Vec<Option<u32>> {
+        vec![self.waypoints[self.step].speed.map(vel_to_steps); self.axes.len()]
+    }
+
+    fn publish(&self, shared: &mut SharedState) {
+        shared.sequence_step = self.step();
+        shared.sequence_repetition = self.repetition;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn axes() -> Vec<String> {
+        vec!["coax".into(), "cross".into()]
+    }
+
+    fn waypoint(targets: &[(&str, f64)], dwell: f64, speed: Option<f64>) -> Waypoint {
+        Waypoint {
+            targets: targets
+                .iter()
+                .map(|(name, target)| (name.to_string(), *target))
+                .collect(),
+            dwell,
+            speed,
+        }
+    }
+
+    fn cycle(is_busy: bool) -> CycleData {
+        CycleData {
+            voltages: [0.; 2],
+            positions: vec![0, 300],
+            is_busy: vec![is_busy, false],
+            time: 0.,
+            dt: 0.5,
+        }
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let waypoints = parse_csv(
+            "coax,cross,dwell,speed\n10,5,2\n# comment\n20, 0, 0.5, 3\n",
+            &axes(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            waypoints,
+            vec![
+                waypoint(&[("coax", 10.), ("cross", 5.)], 2., None),
+                waypoint(&[("coax", 20.), ("cross", 0.)], 0.5, Some(3.)),
+            ]
+        );
+
+        // Without a header the targets are in the order of the axes
+        let waypoints = parse_csv("10,,2\n", &axes()).unwrap();
+        assert_eq!(waypoints, vec![waypoint(&[("coax", 10.)], 2., None)]);
+
+        let waypoints = parse_csv("dwell,cross\n1,7\n", &axes()).unwrap();
+        assert_eq!(waypoints, vec![waypoint(&[("cross", 7.)], 1., None)]);
+
+        assert!(parse_csv("10,5\n", &axes()).is_err());
+        assert!(parse_csv("10,5,x\n", &axes()).is_err());
+        assert!(parse_csv("focus,dwell\n1,2\n", &axes()).is_err());
+    }
+
+    #[test]
+    fn test_sequence_player() {
+        let waypoints = vec![
+            waypoint(&[("coax", 10.)], 1., None),
+            waypoint(&[("coax", 20.)], 1., None),
+        ];
+        let mut player = SequencePlayer::new(waypoints, axes(), 1).unwrap();
+        // The cross axis has no target and holds its position
+        let targets_first = vec![mm_to_steps(10.), 300];
+        let targets_second = vec![mm_to_steps(20.), 300];
+
+        assert_eq!(player.get_targets(&cycle(false)).unwrap(), targets_first);
+        assert_eq!(player.get_targets(&cycle(true)).unwrap(), targets_first);
+
+        // Dwelling for one second
+        assert_eq!(player.get_targets(&cycle(false)).unwrap(), targets_first);
+        assert_eq!(player.get_targets(&cycle(false)).unwrap(), targets_first);
+        assert_eq!(player.step(), Some(0));
+        assert_eq!(player.get_targets(&cycle(false)).unwrap(), targets_second);
+        assert_eq!(player.step(), Some(1));
+
+        assert_eq!(player.get_targets(&cycle(false)).unwrap(), targets_second);
+        assert_eq!(player.get_targets(&cycle(false)).unwrap(), targets_second);
+        assert_eq!(player.get_targets(&cycle(false)).unwrap(), targets_second);
+        assert_eq!(player.step(), None);
+    }
+
+    #[test]
+    fn test_sequence_player_empty() {
+        assert!(SequencePlayer::new(Vec::new(), axes(), 0).is_err());
+    }
+
+    #[test]
+    fn test_sequence_player_unknown_axis() {
+        let waypoints = vec![waypoint(&[("focus", 1.)], 0., None)];
+        assert!(SequencePlayer::new(waypoints, axes(), 0).is_err());
+    }
+}
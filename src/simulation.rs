@@ -9,65 +9,109 @@ use crate::zaber::{MAX_POS, MAX_SPEED};
 
 #[derive(Debug)]
 pub struct Simulator {
-    pub pos: [[u32; 2]; 2],
-    pub offset: Option<u32>,
-    pub busy: [[bool; 2]; 2],
-    pub vel: [[u32; 2]; 2],
+    pub pos: Vec<[u32; 2]>,
+    /// Offset between the axes of each device, set once its lockstep group is set up.
+    pub offset: Vec<Option<u32>>,
+    pub busy: Vec<[bool; 2]>,
+    pub vel: Vec<[u32; 2]>,
+    pub maxspeed: Vec<[u32; 2]>,
     pub time: DateTime<Local>,
-    pub target: [[u32; 2]; 2],
-    pub limit: [[[u32; 2]; 2]; 2],
+    pub target: Vec<[u32; 2]>,
+    pub limit: Vec<[[u32; 2]; 2]>,
+    /// Whether each device has a reference position, set by `home`.
+    pub homed: Vec<bool>,
     pub ignored_read_timeout: Option<std::time::Duration>,
     pub buffer: io::Cursor<Vec<u8>>,
 }
 
+/// Reply of an accepted command, `axis` is `None` for replies of the whole device.
+fn reply_ok(device: usize, axis: Option<usize>) -> String {
+    format!("@{:02} {} OK BUSY -- 0\r\n", device + 1, axis.map_or(0, |a| a + 1))
+}
+
+fn reply_rejected(device: usize, axis: Option<usize>) -> String {
+    format!("@{:02} {} RJ BUSY WR BADDATA\r\n", device + 1, axis.map_or(0, |a| a + 1))
+}
+
+/// Axes addressed by a command, both axes of the device if no axis is given.
+fn axes(axis: Option<usize>) -> Vec<usize> {
+    match axis {
+        Some(a) => vec![a],
+        None => vec![0, 1],
+    }
+}
+
+/// Strips the `lockstep <group>` prefix of commands to a lockstep group,
+/// the setup and the info of the group are not commands to the group.
+fn strip_lockstep(command: &str) -> Option<&str> {
+    let (group, command) = command.strip_prefix("lockstep ")?.split_once(' ')?;
+    group.parse::<u32>().ok()?;
+    match command.starts_with("setup") || command == "info" {
+        true => None,
+        false => Some(command),
+    }
+}
+
 impl Simulator {
-    pub fn new() -> Self {
+    /// Simulates `devices` daisy-chained devices with two axes each, numbered from 1.
+    pub fn new(devices: usize) -> Self {
         Simulator {
-            pos: [[0; 2], [0; 2]],
-            offset: None,
-            busy: [[false; 2], [false; 2]],
+            pos: vec![[0; 2]; devices],
+            offset: vec![None; devices],
+            busy: vec![[false; 2]; devices],
             time: Local::now(),
-            target: [[0; 2], [0; 2]],
-            limit: [[[0, MAX_POS], [0, MAX_POS]], [[0, MAX_POS], [0, MAX_POS]]],
-            vel: [[MAX_SPEED; 2], [MAX_SPEED; 2]],
+            target: vec![[0; 2]; devices],
+            limit: vec![[[0, MAX_POS]; 2]; devices],
+            homed: vec![false; devices],
+            vel: vec![[MAX_SPEED; 2]; devices],
+            maxspeed: vec![[MAX_SPEED; 2]; devices],
             ignored_read_timeout: None,
             buffer: io::Cursor::new(Vec::new()),
         }
     }
 
     pub fn step(&mut self, time_step: Duration) {
-        self.pos[0][0] = move_axis(self.pos[0][0], self.target[0][0], self.vel[0][0], time_step);
-        self.pos[0][1] = move_axis(self.pos[0][1], self.target[0][1], self.vel[0][1], time_step);
-        self.pos[1][0] = move_axis(self.pos[1][0], self.target[1][0], self.vel[1][0], time_step);
-
-        for d in 0..2 {
+        for d in 0..self.pos.len() {
             for a in 0..2 {
+                self.pos[d][a] =
+                    move_axis(self.pos[d][a], self.target[d][a], self.vel[d][a], time_step);
                 self.busy[d][a] = self.target[d][a] != self.pos[d][a];
             }
         }
         self.time = self.time + time_step;
     }
 
-    pub fn get_pos(&mut self) {
-        assert!(self.offset.is_some());
+    /// Devices addressed by a command, all of them for a broadcast.
+    fn devices(&self, device: Option<usize>) -> Vec<usize> {
+        match device {
+            Some(d) => vec![d],
+            None => (0..self.pos.len()).collect(),
+        }
+    }
 
-        let mut msg = self.get_pos_axis(0, 0);
-        msg += &self.get_pos_axis(1, 0);
+    pub fn get_pos(&mut self, device: Option<usize>, axis: Option<usize>) {
+        let mut msg = String::new();
+        for d in self.devices(device) {
+            msg += &self.get_pos_axis(d, axis);
+        }
 
         write!(self.buffer, "{}", msg).unwrap();
     }
 
-    fn get_pos_axis(&self, device: usize, axis: usize) -> String {
-        let busy = match self.busy[device][axis] {
+    /// Reply with the position of `axis`, the one of the first axis for the whole device.
+    fn get_pos_axis(&self, device: usize, axis: Option<usize>) -> String {
+        let a = axis.unwrap_or(0);
+        let busy = match self.busy[device][a] {
             true => "BUSY",
             false => "IDLE",
         };
 
         format!(
-            "@0{} 0 OK {} -- {}\r\n",
+            "@{:02} {} OK {} -- {}\r\n",
             device + 1,
+            axis.map_or(0, |a| a + 1),
             busy,
-            self.pos[device][axis]
+            self.pos[device][a]
         )
     }
 
@@ -77,42 +121,55 @@ impl Simulator {
         }
 
         self.target[device][axis] = target;
+        self.vel[device][axis] = self.maxspeed[device][axis];
         return true;
     }
 
-    pub fn move_abs(&mut self, device: Option<usize>, axis: Option<usize>, target: u32) {
-        assert!(self.offset.is_some());
-
-        let msg: String = match device {
-            Some(d) => match axis {
-                Some(a) => {
-                    let mut msg = format!("@0{} {} RJ BUSY WR BADDATA\r\n", d + 1, a + 1);
-                    if self.move_abs_axis(d, a, target) {
-                        msg = format!("@0{} {} OK BUSY -- 0\r\n", d + 1, a + 1);
-                    }
-                    msg
-                }
-                None => {
-                    let mut msg = format!("@0{} 0 RJ BUSY WR BADDATA\r\n", d + 1);
-                    if self.move_abs_axis(d, 0, target) && self.move_abs_axis(d, 1, target) {
-                        msg = format!("@0{} 0 OK BUSY -- 0\r\n", d + 1);
-                    }
-                    msg
-                }
-            },
-            None => {
-                let mut msg_dev1 = format!("@01 0 RJ BUSY WR BADDATA\r\n");
-                if self.move_abs_axis(0, 0, target) && self.move_abs_axis(0, 1, target) {
-                    msg_dev1 = format!("@01 0 OK BUSY -- 0\r\n");
-                }
+    fn move_vel_axis(&mut self, device: usize, axis: usize, vel: i32) {
+        self.vel[device][axis] = vel.unsigned_abs().min(self.maxspeed[device][axis]);
+        self.target[device][axis] = match vel {
+            ..0 => self.limit[device][axis][0],
+            0 => self.pos[device][axis],
+            1.. => self.limit[device][axis][1],
+        };
+    }
 
-                let mut msg_dev2 = format!("@02 0 RJ BUSY WR BADDATA\r\n");
-                if self.move_abs_axis(1, 0, target) {
-                    msg_dev2 = format!("@02 0 OK BUSY -- 0\r\n");
-                }
-                msg_dev1 + &msg_dev2
+    pub fn move_vel(&mut self, device: Option<usize>, axis: Option<usize>, vel: i32) {
+        let mut msg = String::new();
+        for d in self.devices(device) {
+            for a in axes(axis) {
+                self.move_vel_axis(d, a, vel);
             }
-        };
+            msg += &reply_ok(d, axis);
+        }
+
+        write!(self.buffer, "{}", msg).unwrap();
+    }
+
+    /// Stops the axes by targeting their current positions.
+    pub fn stop(&mut self, device: Option<usize>, axis: Option<usize>) {
+        let mut msg = String::new();
+        for d in self.devices(device) {
+            for a in axes(axis) {
+                self.target[d][a] = self.pos[d][a];
+            }
+            msg += &reply_ok(d, axis);
+        }
+
+        write!(self.buffer, "{}", msg).unwrap();
+    }
+
+    pub fn move_abs(&mut self, device: Option<usize>, axis: Option<usize>, target: u32) {
+        let mut msg = String::new();
+        for d in self.devices(device) {
+            let accepted = axes(axis)
+                .into_iter()
+                .all(|a| self.move_abs_axis(d, a, target));
+            msg += &match accepted {
+                true => reply_ok(d, axis),
+                false => reply_rejected(d, axis),
+            };
+        }
 
         write!(self.buffer, "{}", msg).unwrap();
     }
@@ -129,119 +186,97 @@ impl Simulator {
             idx = 1;
         }
 
-        let msg: String = match device {
-            Some(d) => match axis {
-                Some(a) => {
-                    let mut msg = format!("@0{} {} RJ BUSY WR BADDATA\r\n", d + 1, a + 1);
-                    if limit <= MAX_POS {
-                        self.limit[d][a][idx] = limit;
-                        msg = format!("@0{} {} OK BUSY -- 0\r\n", d + 1, a + 1);
-                    }
-                    msg
-                }
-                None => {
-                    let mut msg = format!("@0{} 0 RJ BUSY WR BADDATA\r\n", d + 1);
-                    if limit <= MAX_POS {
-                        self.limit[d][0][idx] = limit;
-                        self.limit[d][1][idx] = limit;
-                        msg = format!("@0{} 0 OK BUSY -- 0\r\n", d + 1);
-                    }
-                    msg
-                }
-            },
-            None => {
-                let mut msg_dev1 = format!("@01 0 RJ BUSY WR BADDATA\r\n");
-                if limit <= MAX_POS {
-                    self.limit[0][0][idx] = limit;
-                    self.limit[0][1][idx] = limit;
-                    msg_dev1 = format!("@01 0 OK BUSY -- 0\r\n");
-                }
+        let mut msg = String::new();
+        for d in self.devices(device) {
+            if limit > MAX_POS {
+                msg += &reply_rejected(d, axis);
+                continue;
+            }
 
-                let mut msg_dev2 = format!("@02 0 RJ BUSY WR BADDATA\r\n");
-                if limit <= MAX_POS {
-                    self.limit[1][0][idx] = limit;
-                    self.limit[1][1][idx] = limit;
-                    msg_dev2 = format!("@02 0 OK BUSY -- 0\r\n");
-                }
-                msg_dev1 + &msg_dev2
+            for a in axes(axis) {
+                self.limit[d][a][idx] = limit;
             }
-        };
+            msg += &reply_ok(d, axis);
+        }
 
         write!(self.buffer, "{}", msg,).unwrap();
     }
 
-    fn home_axis(&mut self, device: usize, axis: usize) -> String {
-        self.target[device][axis] = 0;
-
-        let status = match self.busy[device][axis] {
-            true => "BUSY",
-            false => "IDLE",
-        };
+    pub fn home(&mut self, device: Option<usize>) {
+        let mut msg = String::new();
+        for d in self.devices(device) {
+            for a in 0..2 {
+                self.target[d][a] = 0;
+            }
+            self.homed[d] = true;
+            msg += &reply_ok(d, None);
+        }
 
-        format!("@0{} {} OK {} -- 0\r\n", device + 1, axis + 1, status,)
+        write!(self.buffer, "{}", msg).unwrap();
     }
 
-    pub fn home(&mut self) {
-        let mut msg = self.home_axis(0, 0);
-        msg += &self.home_axis(0, 1);
-        msg += &self.home_axis(1, 0);
+    pub fn system_restore(&mut self, device: Option<usize>) {
+        let restored = Self::new(self.pos.len());
+        let mut msg = String::new();
+        for d in self.devices(device) {
+            self.pos[d] = restored.pos[d];
+            self.offset[d] = None;
+            self.busy[d] = restored.busy[d];
+            self.vel[d] = restored.vel[d];
+            self.maxspeed[d] = restored.maxspeed[d];
+            self.target[d] = restored.target[d];
+            self.limit[d] = restored.limit[d];
+            msg += &reply_ok(d, None);
+        }
 
         write!(self.buffer, "{}", msg).unwrap();
     }
 
-    pub fn system_restore(&mut self) {
-        *self = Self::new();
-        write!(self.buffer, "@01 0 OK BUSY -- 0\r\n@02 0 OK BUSY -- 0\r\n").unwrap();
-    }
-
     pub fn is_empty(&self) -> bool {
         self.buffer.position() >= self.buffer.get_ref().len() as u64
     }
 
     pub fn move_rel(&mut self, device: Option<usize>, axis: Option<usize>, target: i32) {
-        assert!(self.offset.is_some());
-
-        let msg: String = match device {
-            Some(d) => match axis {
-                Some(a) => {
-                    let mut msg = format!("@0{} {} RJ BUSY WR BADDATA\r\n", d + 1, a + 1);
-                    if self.move_abs_axis(d, a, (self.target[d][a] as i32 + target) as u32) {
-                        msg = format!("@0{} {} OK BUSY -- 0\r\n", d + 1, a + 1);
-                    }
-                    msg
-                }
-                None => {
-                    let mut msg = format!("@0{} 0 RJ BUSY WR BADDATA\r\n", d + 1);
-                    if self.move_abs_axis(d, 0, (self.target[d][0] as i32 + target) as u32)
-                        && self.move_abs_axis(d, 1, (self.target[d][1] as i32 + target) as u32)
-                    {
-                        msg = format!("@0{} 0 OK BUSY -- 0\r\n", d + 1);
-                    }
-                    msg
-                }
-            },
-            None => {
-                let mut msg_dev1 = format!("@01 0 RJ BUSY WR BADDATA\r\n");
-                if self.move_abs_axis(0, 0, (self.target[0][0] as i32 + target) as u32)
-                    && self.move_abs_axis(0, 1, (self.target[0][1] as i32 + target) as u32)
-                {
-                    msg_dev1 = format!("@01 0 OK BUSY -- 0\r\n");
-                }
-
-                let mut msg_dev2 = format!("@02 0 RJ BUSY WR BADDATA\r\n");
-                if self.move_abs_axis(1, 0, (self.target[1][0] as i32 + target) as u32) {
-                    msg_dev2 = format!("@02 0 OK BUSY -- 0\r\n");
-                }
-                msg_dev1 + &msg_dev2
-            }
-        };
+        let mut msg = String::new();
+        for d in self.devices(device) {
+            let accepted = axes(axis).into_iter().all(|a| {
+                self.move_abs_axis(d, a, (self.target[d][a] as i32 + target) as u32)
+            });
+            msg += &match accepted {
+                true => reply_ok(d, axis),
+                false => reply_rejected(d, axis),
+            };
+        }
 
         write!(self.buffer, "{}", msg).unwrap();
     }
 
-    fn lockstep_enable(&mut self) {
-        self.offset = Some(self.pos[0][1] - self.pos[0][0]);
-        write!(self.buffer, "@01 0 OK BUSY -- 0\r\n").unwrap();
+    fn lockstep_enable(&mut self, device: Option<usize>) {
+        let d = device.unwrap();
+        self.offset[d] = Some(self.pos[d][1].abs_diff(self.pos[d][0]));
+        write!(self.buffer, "{}", reply_ok(d, None)).unwrap();
+    }
+
+    /// Replies with the axes of the lockstep group and their offset, or `disabled`.
+    fn lockstep_info(&mut self, device: Option<usize>) {
+        let d = device.unwrap();
+        let info = match self.offset[d] {
+            Some(offset) => format!("1 2 {} 0", offset),
+            None => "disabled".into(),
+        };
+        write!(self.buffer, "@{:02} 0 OK IDLE -- {}\r\n", d + 1, info).unwrap();
+    }
+
+    /// Replies with the warning flags, only `WR` for a missing reference position.
+    fn warnings(&mut self, device: Option<usize>) {
+        let mut msg = String::new();
+        for d in self.devices(device) {
+            msg += &match self.homed[d] {
+                true => format!("@{:02} 0 OK IDLE -- 00\r\n", d + 1),
+                false => format!("@{:02} 0 OK IDLE WR 01 WR\r\n", d + 1),
+            };
+        }
+        write!(self.buffer, "{}", msg).unwrap();
     }
 
     fn poll(&mut self, device: Option<usize>) {
@@ -251,16 +286,17 @@ impl Simulator {
             false => "IDLE",
         };
 
-        let msg = format!("@0{} 0 OK {} -- 0\r\n", device + 1, busy,);
+        let msg = format!("@{:02} 0 OK {} -- 0\r\n", device + 1, busy,);
         write!(self.buffer, "{}", msg).unwrap();
     }
 
-    fn set_maxspeed(&mut self, device: Option<usize>, vel: u32) {
+    fn set_maxspeed(&mut self, device: Option<usize>, axis: Option<usize>, vel: u32) {
         let device = device.unwrap();
-        self.vel[device][0] = vel;
-        self.vel[device][1] = vel;
-        let msg = format!("@0{} 0 OK BUSY -- 0\r\n", device + 1);
-        write!(self.buffer, "{}", msg).unwrap();
+        for a in axes(axis) {
+            self.vel[device][a] = vel;
+            self.maxspeed[device][a] = vel;
+        }
+        write!(self.buffer, "{}", reply_ok(device, axis)).unwrap();
     }
 }
 
@@ -321,33 +357,52 @@ impl io::Write for Simulator {
 
         let command = command.split(":").next().unwrap();
         let command = command.trim();
+        // Commands to a lockstep group move both axes of the device
+        let command = match strip_lockstep(command) {
+            Some(command) => {
+                let d = device.expect("lockstep commands are sent to a device");
+                assert!(
+                    self.offset[d].is_some(),
+                    "lockstep group of device {} is not set up",
+                    d + 1
+                );
+                command
+            }
+            None => command,
+        };
 
         match &command[..] {
             "" => self.poll(device),
-            "get pos" => self.get_pos(),
-            "home" => self.home(),
+            "get pos" => self.get_pos(device, axis),
+            "home" => self.home(device),
+            "warnings" => self.warnings(device),
             "set comm.alert 0" => {
-                write!(self.buffer, "@01 0 OK BUSY -- 0\r\n@02 0 OK BUSY -- 0\r\n").unwrap()
+                let mut msg = String::new();
+                for d in self.devices(device) {
+                    msg += &reply_ok(d, None);
+                }
+                write!(self.buffer, "{}", msg).unwrap()
+            }
+            s if s.starts_with("lockstep ") && s.ends_with(" setup enable 1 2") => {
+                self.lockstep_enable(device)
             }
-            "lockstep 1 setup enable 1 2" => self.lockstep_enable(),
-            s if s.starts_with("set accel ") => write!(
-                self.buffer,
-                "{}",
-                format!("@0{} 0 OK BUSY -- 0\r\n", device.unwrap() + 1)
-            )
-            .unwrap(),
-            s if s.starts_with("lockstep 1 move abs") => {
-                self.move_abs(device, axis, command[20..].parse().unwrap())
+            s if s.starts_with("lockstep ") && s.ends_with(" info") => self.lockstep_info(device),
+            "stop" | "estop" => self.stop(device, axis),
+            s if s.starts_with("set accel ") => {
+                write!(self.buffer, "{}", reply_ok(device.unwrap(), axis)).unwrap()
             }
-            s if s.starts_with("system restore") => self.system_restore(),
+            s if s.starts_with("system restore") => self.system_restore(device),
             s if s.starts_with("move abs") => {
                 self.move_abs(device, axis, command[9..].parse().unwrap())
             }
+            s if s.starts_with("move vel") => {
+                self.move_vel(device, axis, command[9..].parse().unwrap())
+            }
             s if s.starts_with("move rel") => {
                 self.move_rel(device, axis, command[9..].parse().unwrap());
             }
             s if s.starts_with("set maxspeed") => {
-                self.set_maxspeed(device, command[13..].parse().unwrap())
+                self.set_maxspeed(device, axis, command[13..].parse().unwrap())
             }
             s if s.starts_with("set limit.max") => {
                 self.set_limit(device, axis, command[14..].parse().unwrap(), true)
@@ -427,11 +482,11 @@ mod tests {
 
     #[test]
     fn test_sim_move_abs() {
-        let mut sim = Simulator::new();
-        sim.offset = Some(0);
-        sim.pos = [[2000, 2000], [100, 0]];
-        sim.target = [[2000, 2000], [100, 0]];
-        sim.busy = [[false, false], [true, false]];
+        let mut sim = Simulator::new(2);
+        sim.offset = vec![Some(0), None];
+        sim.pos = vec![[2000, 2000], [100, 0]];
+        sim.target = vec![[2000, 2000], [100, 0]];
+        sim.busy = vec![[false, false], [true, false]];
 
         let mut opt = OpenGeneralOptions::new();
         opt.checksums(false);
@@ -460,11 +515,11 @@ mod tests {
 
     #[test]
     fn test_sim_move_rel() {
-        let mut sim = Simulator::new();
-        sim.offset = Some(0);
-        sim.pos = [[2000, 2000], [100, 0]];
-        sim.target = [[2000, 2000], [100, 0]];
-        sim.busy = [[false, false], [true, false]];
+        let mut sim = Simulator::new(2);
+        sim.offset = vec![Some(0), None];
+        sim.pos = vec![[2000, 2000], [100, 0]];
+        sim.target = vec![[2000, 2000], [100, 0]];
+        sim.busy = vec![[false, false], [true, false]];
 
         let mut opt = OpenGeneralOptions::new();
         opt.checksums(false);
@@ -493,11 +548,11 @@ mod tests {
 
     #[test]
     fn test_sim_get_pos() {
-        let mut sim = Simulator::new();
-        sim.offset = Some(0);
-        sim.pos = [[2000, 2000], [100, 0]];
-        sim.target = [[2000, 2000], [100, 0]];
-        sim.busy = [[false, false], [true, false]];
+        let mut sim = Simulator::new(2);
+        sim.offset = vec![Some(0), None];
+        sim.pos = vec![[2000, 2000], [100, 0]];
+        sim.target = vec![[2000, 2000], [100, 0]];
+        sim.busy = vec![[false, false], [true, false]];
 
         let mut opt = OpenGeneralOptions::new();
         opt.checksums(false);
@@ -520,12 +575,66 @@ mod tests {
         assert_eq!(resp[1].data(), "100");
     }
 
+    #[test]
+    fn test_sim_move_vel() {
+        let mut sim = Simulator::new(2);
+        sim.offset = vec![Some(0), None];
+        sim.pos = vec![[2000, 2000], [100, 0]];
+        sim.target = vec![[2000, 2000], [100, 0]];
+
+        let mut opt = OpenGeneralOptions::new();
+        opt.checksums(false);
+        opt.message_ids(false);
+        let mut port = opt.open(sim);
+        let _ = port
+            .command_reply((1, "lockstep 1 move vel -500"))
+            .unwrap()
+            .flag_ok()
+            .unwrap();
+
+        assert_eq!(port.backend().target[0][0], 0);
+        assert_eq!(port.backend().vel[0][0], 500);
+
+        let _ = port
+            .command_reply((2, "move vel 0"))
+            .unwrap()
+            .flag_ok()
+            .unwrap();
+
+        assert_eq!(port.backend().target[1][0], port.backend().pos[1][0]);
+    }
+
+    #[test]
+    fn test_sim_stop() {
+        let mut sim = Simulator::new(2);
+        sim.offset = vec![Some(0), None];
+        sim.pos = vec![[2000, 2000], [100, 0]];
+        sim.target = vec![[5000, 5000], [3000, 0]];
+
+        let mut opt = OpenGeneralOptions::new();
+        opt.checksums(false);
+        opt.message_ids(false);
+        let mut port = opt.open(sim);
+        let _ = port
+            .command_reply((1, "lockstep 1 stop"))
+            .unwrap()
+            .flag_ok()
+            .unwrap();
+
+        assert_eq!(port.backend().target[0][0], port.backend().pos[0][0]);
+        assert_eq!(port.backend().target[1][0], 3000);
+
+        let _ = port.command_reply((2, "stop")).unwrap().flag_ok().unwrap();
+
+        assert_eq!(port.backend().target[1][0], port.backend().pos[1][0]);
+    }
+
     #[test]
     fn test_sim_set_limit() {
-        let mut sim = Simulator::new();
-        sim.offset = Some(0);
-        sim.pos = [[2000, 2000], [100, 0]];
-        sim.target = [[2000, 2000], [100, 0]];
+        let mut sim = Simulator::new(2);
+        sim.offset = vec![Some(0), None];
+        sim.pos = vec![[2000, 2000], [100, 0]];
+        sim.target = vec![[2000, 2000], [100, 0]];
 
         let mut opt = OpenGeneralOptions::new();
         opt.checksums(false);
@@ -546,4 +655,35 @@ mod tests {
         assert_eq!(port.backend().limit[0][0][1], 2500);
         assert_eq!(port.backend().limit[0][1][0], 50);
     }
+
+    #[test]
+    fn test_sim_axis_address() {
+        let mut opt = OpenGeneralOptions::new();
+        opt.checksums(false);
+        opt.message_ids(false);
+        let mut port = opt.open(Simulator::new(3));
+
+        let resp = port
+            .command_reply((3, 2, "move abs 3000"))
+            .unwrap()
+            .flag_ok()
+            .unwrap();
+
+        assert_eq!(resp.target().device(), 3);
+        assert_eq!(resp.target().axis(), 2);
+        assert_eq!(port.backend().target[2], [0, 3000]);
+
+        let _ = port
+            .command_reply((3, "lockstep 2 setup enable 1 2"))
+            .unwrap()
+            .flag_ok()
+            .unwrap();
+        let _ = port
+            .command_reply((3, "lockstep 2 move abs 500"))
+            .unwrap()
+            .flag_ok()
+            .unwrap();
+
+        assert_eq!(port.backend().target[2], [500, 500]);
+    }
 }
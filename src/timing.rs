@@ -0,0 +1,246 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Number of cycles the statistics are computed over.
+const WINDOW: usize = 256;
+
+/// Interval the report is updated and the overruns are summarized with, sorting the
+/// windows for the percentiles every cycle would take too long.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Statistics of a duration in milliseconds.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+    pub p99: f64,
+}
+
+/// Rolling window of the durations of the last cycles.
+#[derive(Clone, Debug, Default)]
+pub struct Samples {
+    values: VecDeque<f64>,
+}
+
+impl Samples {
+    pub fn push(&mut self, duration: Duration) {
+        if self.values.len() == WINDOW {
+            self.values.pop_front();
+        }
+        self.values.push_back(duration.as_secs_f64() * 1000.);
+    }
+
+    /// Mean of the durations in milliseconds, 0 without any samples.
+    pub fn mean(&self) -> f64 {
+        match self.values.is_empty() {
+            true => 0.,
+            false => self.values.iter().sum::<f64>() / self.values.len() as f64,
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        if self.values.is_empty() {
+            return Stats::default();
+        }
+
+        let mut sorted: Vec<f64> = self.values.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        let p99 = ((sorted.len() as f64 * 0.99).ceil() as usize).clamp(1, sorted.len()) - 1;
+
+        Stats {
+            min: sorted[0],
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            max: sorted[sorted.len() - 1],
+            p99: sorted[p99],
+        }
+    }
+}
+
+/// Timing statistics of the control loop as published in the shared state.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TimingReport {
+    /// Time between the starts of two cycles.
+    pub period: Stats,
+    /// Deviation of the period from the configured cycle time.
+    pub jitter: Stats,
+    pub adc: Stats,
+    pub get_pos: Stats,
+    /// Filtering of the voltages and evaluation of the targets.
+    pub formula: Stats,
+    pub moves: Stats,
+    /// Number of cycles which did not finish before the next deadline.
+    pub overruns: u64,
+}
+
+impl TimingReport {
+    /// All statistics with their names.
+    pub fn series(&self) -> [(&'static str, &Stats); 6] {
+        [
+            ("period", &self.period),
+            ("jitter", &self.jitter),
+            ("adc", &self.adc),
+            ("get_pos", &self.get_pos),
+            ("formula", &self.formula),
+            ("moves", &self.moves),
+        ]
+    }
+}
+
+/// Collects the durations of the phases of the control cycles.
+#[derive(Clone, Debug, Default)]
+pub struct CycleTimer {
+    pub cycle_time: Duration,
+    pub period: Samples,
+    pub jitter: Samples,
+    pub adc: Samples,
+    pub get_pos: Samples,
+    pub formula: Samples,
+    pub moves: Samples,
+    pub overruns: u64,
+    /// Overruns since the last summary.
+    overruns_pending: u64,
+    report_last: Option<Instant>,
+    summary_last: Option<Instant>,
+}
+
+/// Whether `REPORT_INTERVAL` passed since `last`, which is then set to `now`.
+fn is_due(last: &mut Option<Instant>, now: Instant) -> bool {
+    match last {
+        Some(last) if now.duration_since(*last) < REPORT_INTERVAL => false,
+        _ => {
+            *last = Some(now);
+            true
+        }
+    }
+}
+
+impl CycleTimer {
+    pub fn new(cycle_time: Duration) -> Self {
+        Self {
+            cycle_time,
+            ..Default::default()
+        }
+    }
+
+    pub fn push_period(&mut self, period: Duration) {
+        self.period.push(period);
+        self.jitter.push(match period > self.cycle_time {
+            true => period - self.cycle_time,
+            false => self.cycle_time - period,
+        });
+    }
+
+    /// Mean time in seconds from reading the voltages until the moves are sent.
+    pub fn latency(&self) -> f64 {
+        (self.adc.mean() + self.get_pos.mean() + self.formula.mean() + self.moves.mean()) / 1000.
+    }
+
+    pub fn push_overrun(&mut self) {
+        self.overruns += 1;
+        self.overruns_pending += 1;
+    }
+
+    /// Returns the report at most once per `REPORT_INTERVAL`.
+    pub fn report_due(&mut self, now: Instant) -> Option<TimingReport> {
+        is_due(&mut self.report_last, now).then(|| self.report())
+    }
+
+    /// Returns the number of overruns since the last summary, at most once per
+    /// `REPORT_INTERVAL` and only if there were any.
+    pub fn overruns_due(&mut self, now: Instant) -> Option<u64> {
+        if self.overruns_pending == 0 || !is_due(&mut self.summary_last, now) {
+            return None;
+        }
+        Some(std::mem::take(&mut self.overruns_pending))
+    }
+
+    pub fn report(&self) -> TimingReport {
+        TimingReport {
+            period: self.period.stats(),
+            jitter: self.jitter.stats(),
+            adc: self.adc.stats(),
+            get_pos: self.get_pos.stats(),
+            formula: self.formula.stats(),
+            moves: self.moves.stats(),
+            overruns: self.overruns,
+        }
+    }
+}
+
+/// Returns the deadline of the next cycle following `deadline`.
+/// Cycles whose deadline already passed at `now` are skipped instead of being caught up,
+/// the second value is whether the cycle overran.
+pub fn next_deadline(deadline: Instant, cycle_time: Duration, now: Instant) -> (Instant, bool) {
+    let next = deadline + cycle_time;
+    if next > now {
+        return (next, false);
+    }
+    if cycle_time.is_zero() {
+        return (now, true);
+    }
+
+    let missed = ((now - next).as_secs_f64() / cycle_time.as_secs_f64()).floor() as u32 + 1;
+    (next + cycle_time * missed, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut samples = Samples::default();
+        assert_eq!(samples.stats(), Stats::default());
+
+        for ms in 1..=100 {
+            samples.push(Duration::from_millis(ms));
+        }
+
+        let eps = 1e-9;
+        let stats = samples.stats();
+        assert!((stats.min - 1.).abs() < eps);
+        assert!((stats.max - 100.).abs() < eps);
+        assert!((stats.mean - 50.5).abs() < eps);
+        assert!((stats.p99 - 99.).abs() < eps);
+    }
+
+    #[test]
+    fn test_report_interval() {
+        let start = Instant::now();
+        let mut timer = CycleTimer::new(Duration::from_millis(1));
+        assert!(timer.report_due(start).is_some());
+        assert!(timer.report_due(start + Duration::from_millis(10)).is_none());
+        assert!(timer.report_due(start + REPORT_INTERVAL).is_some());
+
+        // The overruns of an interval are summarized in one count
+        assert_eq!(timer.overruns_due(start), None);
+        for _ in 0..3 {
+            timer.push_overrun();
+        }
+        assert_eq!(timer.overruns_due(start), Some(3));
+        timer.push_overrun();
+        assert_eq!(timer.overruns_due(start + Duration::from_millis(10)), None);
+        assert_eq!(timer.overruns_due(start + REPORT_INTERVAL), Some(1));
+        assert_eq!(timer.overruns, 4);
+    }
+
+    #[test]
+    fn test_next_deadline() {
+        let start = Instant::now();
+        let cycle_time = Duration::from_millis(10);
+
+        let (next, overrun) = next_deadline(start, cycle_time, start + Duration::from_millis(3));
+        assert_eq!(next, start + cycle_time);
+        assert!(!overrun);
+
+        // Two deadlines passed, the next one is kept on the grid
+        let (next, overrun) = next_deadline(start, cycle_time, start + Duration::from_millis(25));
+        assert_eq!(next, start + Duration::from_millis(30));
+        assert!(overrun);
+    }
+}
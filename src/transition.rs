@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use crate::zaber::mm_to_steps;
+
+/// Blends the targets from the positions of the axes at the start of the control loop
+/// to the ones of the target source, so that switching the control mode does not make
+/// the stage jump to a distant target at full speed.
+///
+/// Within `duration`, the targets move from the start positions to the targets of the
+/// source with a linearly rising weight. With a `speed`, the targets additionally
+/// change by at most that speed. Once all axes reached the targets of the source, they
+/// are passed through unchanged.
+#[derive(Clone, Debug)]
+pub struct Transition {
+    /// Duration of the blend in seconds, 0 does not blend.
+    pub duration: f64,
+    /// Max. speed of the targets during the transition in microsteps/s, 0 does not limit it.
+    pub speed: f64,
+    /// Positions at the start in microsteps.
+    start: Option<Vec<f64>>,
+    /// Targets of the previous cycle in microsteps.
+    previous: Vec<f64>,
+    time: f64,
+    finished: bool,
+}
+
+impl Transition {
+    /// Creates a transition from the config, `speed` is given in mm/s.
+    pub fn new(duration: Duration, speed: f64) -> Self {
+        Self {
+            duration: duration.as_secs_f64(),
+            speed: mm_to_steps(speed.max(0.)) as f64,
+            start: None,
+            previous: Vec::new(),
+            time: 0.,
+            finished: duration.is_zero() && speed <= 0.,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns the targets to send to the axes for the `targets` of the source.
+    pub fn apply(&mut self, targets: &[u32], positions: &[u32], dt: f64) -> Vec<u32> {
+        if self.finished {
+            return targets.to_vec();
+        }
+
+        let start = self
+            .start
+            .get_or_insert_with(|| positions.iter().map(|&p| p as f64).collect());
+        if self.previous.is_empty() {
+            self.previous = start.clone();
+        }
+        self.time += dt;
+
+        let weight = match self.duration > 0. {
+            true => (self.time / self.duration).min(1.),
+            false => 1.,
+        };
+        let step = self.speed * dt;
+
+        let mut reached = weight >= 1.;
+        for ((previous, &from), &target) in
+            self.previous.iter_mut().zip(start.iter()).zip(targets)
+        {
+            let target = target as f64;
+            let blended = from + weight * (target - from);
+            *previous = match self.speed > 0. {
+                true => *previous + (blended - *previous).max(-step).min(step),
+                false => blended,
+            };
+            reached &= (*previous - target).abs() < 1.;
+        }
+
+        if reached {
+            self.finished = true;
+            tracing::info!("Transition to the targets finished after {:.3} s", self.time);
+            return targets.to_vec();
+        }
+
+        self.previous.iter().map(|&target| target.round() as u32).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transition_duration() {
+        let mut transition = Transition::new(Duration::from_secs(2), 0.);
+
+        // The first cycle stays at the position
+        assert_eq!(transition.apply(&[1000, 500], &[0, 500], 0.), vec![0, 500]);
+        assert_eq!(transition.apply(&[1000, 500], &[0, 500], 1.), vec![500, 500]);
+        // The target of the source may move during the transition
+        assert_eq!(transition.apply(&[2000, 500], &[500, 500], 0.5), vec![1500, 500]);
+        assert!(!transition.is_finished());
+
+        assert_eq!(transition.apply(&[2000, 500], &[1500, 500], 0.5), vec![2000, 500]);
+        assert!(transition.is_finished());
+        assert_eq!(transition.apply(&[3000, 0], &[2000, 500], 0.5), vec![3000, 0]);
+    }
+
+    #[test]
+    fn test_transition_speed() {
+        // 1000 microsteps/s
+        let mut transition = Transition::new(Duration::ZERO, 0.49609375);
+
+        assert_eq!(transition.apply(&[2500, 0], &[0, 100], 0.), vec![0, 100]);
+        assert_eq!(transition.apply(&[2500, 0], &[0, 100], 1.), vec![1000, 0]);
+        assert_eq!(transition.apply(&[2500, 0], &[1000, 0], 1.), vec![2000, 0]);
+        assert!(!transition.is_finished());
+
+        assert_eq!(transition.apply(&[2500, 0], &[2000, 0], 1.), vec![2500, 0]);
+        assert!(transition.is_finished());
+    }
+
+    #[test]
+    fn test_transition_disabled() {
+        let mut transition = Transition::new(Duration::ZERO, 0.);
+        assert!(transition.is_finished());
+        assert_eq!(transition.apply(&[2500, 0], &[0, 100], 0.), vec![2500, 0]);
+    }
+}
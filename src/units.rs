@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::zaber::MICROSTEP_SIZE;
+
+/// Physical quantity of a setting, which decides the accepted units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantity {
+    /// Stored in mm.
+    Length,
+    /// Stored in mm/s.
+    Velocity,
+    /// Stored in mm/s².
+    Acceleration,
+}
+
+impl Quantity {
+    /// Accepted units and their factors to the stored unit, the first one is the default.
+    fn units(&self) -> &'static [(&'static str, f64)] {
+        const STEP: f64 = MICROSTEP_SIZE / 1000.;
+        match self {
+            Self::Length => &[("mm", 1.), ("um", 1e-3), ("µm", 1e-3), ("steps", STEP)],
+            Self::Velocity => &[
+                ("mm/s", 1.),
+                ("um/s", 1e-3),
+                ("µm/s", 1e-3),
+                ("steps/s", STEP),
+            ],
+            Self::Acceleration => &[
+                ("mm/s^2", 1.),
+                ("mm/s²", 1.),
+                ("um/s^2", 1e-3),
+                ("µm/s^2", 1e-3),
+                ("µm/s²", 1e-3),
+                ("steps/s^2", STEP),
+                ("steps/s²", STEP),
+            ],
+        }
+    }
+}
+
+/// Parses a number with an optional unit, e.g. `12.5`, `1.25e1 mm` or `25200 steps`,
+/// and returns it in the stored unit of the quantity.
+pub fn parse(s: &str, quantity: Quantity) -> Result<f64> {
+    let s = s.trim();
+    // The unit follows the longest prefix which is a number, an exponent like in
+    // `1e-3` belongs to the number
+    let (value, unit) = s
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain([s.len()])
+        .rev()
+        .find_map(|i| Some((s[..i].trim().parse::<f64>().ok()?, s[i..].trim())))
+        .ok_or(anyhow!("Invalid number '{}'", s))?;
+    let units = quantity.units();
+    let factor = match unit {
+        "" => 1.,
+        _ => units
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .map(|(_, factor)| *factor)
+            .ok_or(anyhow!(
+                "Unknown unit '{}', available are: {}",
+                unit,
+                units.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+            ))?,
+    };
+
+    Ok(value * factor)
+}
+
+/// A number in the stored unit or a string with a unit.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Number(f64),
+    Text(String),
+}
+
+fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    quantity: Quantity,
+) -> Result<f64, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Number(value) => Ok(value),
+        Value::Text(text) => parse(&text, quantity).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Deserializes a length in mm, for `#[serde(deserialize_with = "units::length")]`.
+pub fn length<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    deserialize(deserializer, Quantity::Length)
+}
+
+/// Deserializes a velocity in mm/s.
+pub fn velocity<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    deserialize(deserializer, Quantity::Velocity)
+}
+
+/// Deserializes an acceleration in mm/s².
+pub fn acceleration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    deserialize(deserializer, Quantity::Acceleration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("12.5", Quantity::Length).unwrap(), 12.5);
+        assert_eq!(parse(" 12.5 mm ", Quantity::Length).unwrap(), 12.5);
+        assert_eq!(parse("500µm", Quantity::Length).unwrap(), 0.5);
+        assert_eq!(parse("2000 steps", Quantity::Length).unwrap(), 0.9921875);
+        assert_eq!(parse("3 mm/s", Quantity::Velocity).unwrap(), 3.);
+        assert_eq!(parse("4 mm/s²", Quantity::Acceleration).unwrap(), 4.);
+        assert_eq!(parse("1e-3", Quantity::Length).unwrap(), 1e-3);
+        assert_eq!(parse("2.5e1 mm", Quantity::Length).unwrap(), 25.);
+        assert_eq!(parse("-5E2um", Quantity::Length).unwrap(), -0.5);
+
+        assert!(parse("3 mm/s", Quantity::Length).is_err());
+        assert!(parse("mm", Quantity::Length).is_err());
+        assert!(parse("1e", Quantity::Length).is_err());
+        assert!(parse("1e-", Quantity::Length).is_err());
+    }
+
+    #[test]
+    fn test_deserialize() {
+        #[derive(Deserialize)]
+        struct Axis {
+            #[serde(deserialize_with = "length")]
+            limit_max: f64,
+            #[serde(deserialize_with = "velocity")]
+            maxspeed: f64,
+        }
+
+        let axis: Axis = toml::from_str("limit_max = 20\nmaxspeed = \"500 um/s\"").unwrap();
+        assert_eq!((axis.limit_max, axis.maxspeed), (20., 0.5));
+        assert!(toml::from_str::<Axis>("limit_max = \"20 s\"\nmaxspeed = 1").is_err());
+    }
+}
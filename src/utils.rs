@@ -1,26 +1,119 @@
 use anyhow::{anyhow, Result};
 use std::{
+    collections::BTreeMap,
     fmt::Display,
     io::Write,
     path::PathBuf,
+    str::FromStr,
     sync::{Arc, RwLock},
     time::Duration,
 };
 
 use chrono::{DateTime, Local};
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 
-use crate::zaber::{MAX_POS, MAX_SPEED};
+use crate::{
+    calibration::{CalibrationConfig, CalibrationTable},
+    control::{BACKENDS, VOLTAGE_SOURCES},
+    error::ErrorReport,
+    filter::FilterConfig,
+    formula::{check_formula, variable_names},
+    history::ErrorHistory,
+    homing::HomingPolicy,
+    predictor::PredictorConfig,
+    ramp::{WaveformConfig, WaveformShape},
+    sequence::Waypoint,
+    timing::TimingReport,
+    units,
+    zaber::{
+        accel_to_steps, distance_to_steps, steps_to_accel, steps_to_mm, steps_to_vel,
+        vel_to_steps, MAX_POS, MAX_SPEED,
+    },
+};
 
 pub type StateChannel = Arc<RwLock<SharedState>>;
 pub type StopChannel = Receiver<()>;
 
+/// Version of the config format written by `write_config`. Configs without a version are
+/// version 1, which configured the axes in Zaber units, see `migrate_config`.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Name, device and lockstep group of the two fixed axes of version 1, whose settings
+/// were named like `limit_max_coax`.
+const LEGACY_AXES: [(&str, u8, Option<u8>); 2] = [("coax", 1, Some(1)), ("cross", 2, None)];
+const LEGACY_FIELDS: [&str; 6] =
+    ["limit_max", "limit_min", "maxspeed", "accel", "offset", "formula"];
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ControlMode {
     Tracking,
     Manual,
+    ClosedLoop,
+    Sequence,
+    Waveform,
+}
+
+impl FromStr for ControlMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Tracking" => Ok(Self::Tracking),
+            "Manual" => Ok(Self::Manual),
+            "ClosedLoop" => Ok(Self::ClosedLoop),
+            "Sequence" => Ok(Self::Sequence),
+            "Waveform" => Ok(Self::Waveform),
+            _ => Err(anyhow!("Unknown control mode {}", s)),
+        }
+    }
+}
+
+/// How the target of an axis is computed in `ControlMode::Tracking` and `ControlMode::ClosedLoop`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TargetKind {
+    #[default]
+    Formula,
+    /// Lookup of a voltage in the calibration table of the axis.
+    Calibration,
+}
+
+impl FromStr for TargetKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Formula" => Ok(Self::Formula),
+            "Calibration" => Ok(Self::Calibration),
+            _ => Err(anyhow!("Unknown target kind {}", s)),
+        }
+    }
+}
+
+/// What happens to a target outside of the limits of the axis.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LimitPolicy {
+    /// Move to the nearest limit.
+    Clamp,
+    /// Keep the last target within the limits.
+    #[default]
+    Hold,
+    /// Stop the control loop with an error.
+    Fault,
+}
+
+impl FromStr for LimitPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Clamp" => Ok(Self::Clamp),
+            "Hold" => Ok(Self::Hold),
+            "Fault" => Ok(Self::Fault),
+            _ => Err(anyhow!("Unknown limit policy {}", s)),
+        }
+    }
 }
 
 fn default_serial_device() -> String {
@@ -31,73 +124,259 @@ fn default_opcua_config_path() -> PathBuf {
     "opcua_config.conf".into()
 }
 
-fn default_limit_max_coax() -> u32 {
-    MAX_POS
+fn default_limit_max() -> f64 {
+    steps_to_mm(MAX_POS)
 }
 
-fn default_limit_min_coax() -> u32 {
-    0
+fn default_limit_min() -> f64 {
+    0.
 }
 
-fn default_maxspeed_coax() -> u32 {
-    MAX_SPEED
+fn default_maxspeed() -> f64 {
+    steps_to_vel(MAX_SPEED)
 }
 
-fn default_accel_coax() -> u32 {
-    50
+fn default_accel() -> f64 {
+    steps_to_accel(50)
 }
 
 fn default_control_mode() -> ControlMode {
     ControlMode::Manual
 }
 
-fn default_offset_coax() -> i32 {
-    0
+fn default_backend() -> String {
+    "zaber".into()
 }
 
-fn default_limit_max_cross() -> u32 {
-    MAX_POS
+fn default_voltage_source() -> String {
+    "adc".into()
 }
 
-fn default_limit_min_cross() -> u32 {
-    0
+fn default_formula_coax() -> String {
+    "64 - (64 - 17) / (2 - 0.12) * (v1 - 0.12)".into()
 }
 
-fn default_maxspeed_cross() -> u32 {
-    MAX_SPEED
+fn default_formula() -> String {
+    "0".into()
 }
 
-fn default_accel_cross() -> u32 {
-    50
+fn default_config_version() -> u32 {
+    CONFIG_VERSION
 }
 
-fn default_mock_zaber() -> bool {
-    false
+fn default_axes() -> Vec<AxisConfig> {
+    vec![
+        AxisConfig {
+            lockstep: Some(1),
+            formula: default_formula_coax(),
+            ..AxisConfig::new("coax", 1)
+        },
+        AxisConfig::new("cross", 2),
+    ]
 }
 
-fn default_mock_adc() -> bool {
-    false
+fn default_web_port() -> u32 {
+    8085
 }
 
-fn default_formula_coax() -> String {
-    "64 - (64 - 17) / (2 - 0.12) * (v1 - 0.12)".into()
+fn default_kp() -> f64 {
+    10.
 }
 
-fn default_formula_cross() -> String {
-    "0".into()
+fn default_ki() -> f64 {
+    0.
 }
 
-fn default_web_port() -> u32 {
-    8085
+fn default_kd() -> f64 {
+    0.
 }
 
 fn default_cycle_time_ms() -> Duration {
     Duration::from_millis(500)
 }
 
+fn default_deadband() -> f64 {
+    0.
+}
+
+fn default_hysteresis() -> f64 {
+    0.
+}
+
+fn default_sequence_path() -> PathBuf {
+    "sequence.toml".into()
+}
+
+fn default_sequence_repetitions() -> u32 {
+    1
+}
+
+fn default_retry_count() -> u32 {
+    2
+}
+
+fn default_retry_timeout_backoff_ms() -> Duration {
+    Duration::from_millis(5)
+}
+
+fn default_retry_malformed_backoff_ms() -> Duration {
+    Duration::from_millis(1)
+}
+
+fn default_watchdog_enabled() -> bool {
+    true
+}
+
+fn default_watchdog_tolerance() -> f64 {
+    0.5
+}
+
+fn default_watchdog_timeout_ms() -> Duration {
+    Duration::from_millis(5000)
+}
+
+fn default_watchdog_stop() -> bool {
+    true
+}
+
+fn default_stop_timeout_ms() -> Duration {
+    Duration::from_millis(5000)
+}
+
+fn default_predictor_latency_ms() -> Duration {
+    Duration::ZERO
+}
+
+fn default_transition_time_ms() -> Duration {
+    Duration::from_millis(1000)
+}
+
+fn default_transition_speed() -> f64 {
+    5.
+}
+
+fn default_position_store_path() -> PathBuf {
+    "positions.toml".into()
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_filters() -> Vec<FilterConfig> {
+    Vec::new()
+}
+
+/// A motion axis and the address of the Zaber device moving it.
+///
+/// The physical units are converted with the microstep size and the velocity factor of
+/// the Zaber LSM stages, see `zaber::MICROSTEP_SIZE`. They are the same for all axes and
+/// not read from the devices, so settings in mm are only correct for devices with this
+/// resolution; other devices should be configured in `steps`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AxisConfig {
+    /// Unique name used by the interfaces and the sequences.
+    pub name: String,
+    /// Number of the device in the daisy chain.
+    pub device: u8,
+    /// Number of the axis on the device, 0 addresses all axes of the device.
+    #[serde(default)]
+    pub axis: u8,
+    /// Lockstep group the axes of the device are moved with, e.g. the two drives of the
+    /// coaxial axis. The axis is then addressed through the group.
+    #[serde(default)]
+    pub lockstep: Option<u8>,
+    /// A disabled axis is neither homed, configured nor moved.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Limits of the axis in mm, e.g. `12.5` or `"25200 steps"`.
+    #[serde(default = "default_limit_min", deserialize_with = "units::length")]
+    pub limit_min: f64,
+    #[serde(default = "default_limit_max", deserialize_with = "units::length")]
+    pub limit_max: f64,
+    #[serde(default)]
+    pub limit_policy: LimitPolicy,
+    /// Max. speed in mm/s, e.g. `20` or `"20 mm/s"`.
+    #[serde(default = "default_maxspeed", deserialize_with = "units::velocity")]
+    pub maxspeed: f64,
+    /// Acceleration in mm/s², e.g. `500` or `"500 mm/s^2"`.
+    #[serde(default = "default_accel", deserialize_with = "units::acceleration")]
+    pub accel: f64,
+    /// Distance in mm the second axis of a lockstep group is moved by before the
+    /// group is set up, negative values move the first axis.
+    #[serde(default, deserialize_with = "units::length")]
+    pub offset: f64,
+    /// Formula of the target in mm in `ControlMode::Tracking` and `ControlMode::ClosedLoop`.
+    #[serde(default = "default_formula")]
+    pub formula: String,
+    #[serde(default)]
+    pub target_kind: TargetKind,
+    /// Table used instead of the formula if `target_kind` is `TargetKind::Calibration`.
+    #[serde(default)]
+    pub calibration: Option<CalibrationConfig>,
+    /// PID gains for `ControlMode::ClosedLoop`, the controller output is the
+    /// velocity in Zaber units, the error is measured in microsteps.
+    #[serde(default = "default_kp")]
+    pub kp: f64,
+    #[serde(default = "default_ki")]
+    pub ki: f64,
+    #[serde(default = "default_kd")]
+    pub kd: f64,
+    /// Half-width of the band around the last commanded target in mm,
+    /// within which target changes do not cause a new move.
+    #[serde(default = "default_deadband", deserialize_with = "units::length")]
+    pub deadband: f64,
+    /// Reduction of the deadband in mm while the target is moving.
+    #[serde(default = "default_hysteresis", deserialize_with = "units::length")]
+    pub hysteresis: f64,
+    /// Signal in `ControlMode::Waveform`, the axis is held if not set.
+    #[serde(default)]
+    pub waveform: Option<WaveformConfig>,
+}
+
+impl AxisConfig {
+    pub fn new(name: &str, device: u8) -> Self {
+        Self {
+            name: name.into(),
+            device,
+            axis: 0,
+            lockstep: None,
+            enabled: default_enabled(),
+            limit_min: default_limit_min(),
+            limit_max: default_limit_max(),
+            limit_policy: LimitPolicy::Hold,
+            maxspeed: default_maxspeed(),
+            accel: default_accel(),
+            offset: 0.,
+            formula: default_formula(),
+            target_kind: TargetKind::Formula,
+            calibration: None,
+            kp: default_kp(),
+            ki: default_ki(),
+            kd: default_kd(),
+            deadband: default_deadband(),
+            hysteresis: default_hysteresis(),
+            waveform: None,
+        }
+    }
+
+    /// Limits in microsteps, as sent to the device.
+    pub fn limits_steps(&self) -> [u32; 2] {
+        [self.limit_min, self.limit_max].map(|limit| distance_to_steps(limit).max(0) as u32)
+    }
+
+    /// Max. speed in Zaber velocity units.
+    pub fn maxspeed_steps(&self) -> u32 {
+        vel_to_steps(self.maxspeed)
+    }
+}
+
 #[serde_as]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// See `CONFIG_VERSION`, older configs are migrated when they are read.
+    #[serde(default = "default_config_version")]
+    pub config_version: u32,
     #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
     #[serde(default = "default_cycle_time_ms")]
     pub cycle_time_ms: Duration,
@@ -107,66 +386,450 @@ pub struct Config {
     pub opcua_config_path: PathBuf,
     #[serde(default = "default_control_mode")]
     pub control_mode: ControlMode,
-    #[serde(default = "default_limit_max_coax")]
-    pub limit_max_coax: u32,
-    #[serde(default = "default_limit_min_coax")]
-    pub limit_min_coax: u32,
-    #[serde(default = "default_maxspeed_coax")]
-    pub maxspeed_coax: u32,
-    #[serde(default = "default_accel_coax")]
-    pub accel_coax: u32,
-    #[serde(default = "default_offset_coax")]
-    pub offset_coax: i32,
-    #[serde(default = "default_limit_max_cross")]
-    pub limit_max_cross: u32,
-    #[serde(default = "default_limit_min_cross")]
-    pub limit_min_cross: u32,
-    #[serde(default = "default_maxspeed_cross")]
-    pub maxspeed_cross: u32,
-    #[serde(default = "default_accel_cross")]
-    pub accel_cross: u32,
-    #[serde(default = "default_mock_zaber")]
-    pub mock_zaber: bool,
-    #[serde(default = "default_mock_adc")]
-    pub mock_adc: bool,
-    #[serde(default = "default_formula_coax")]
-    pub formula_coax: String,
-    #[serde(default = "default_formula_cross")]
-    pub formula_cross: String,
+    #[serde(default = "default_backend")]
+    pub backend: String,
+    #[serde(default = "default_voltage_source")]
+    pub voltage_source: String,
     #[serde(default = "default_web_port")]
     pub web_port: u32,
+    /// The axes in the order of their indices.
+    #[serde(default = "default_axes")]
+    pub axes: Vec<AxisConfig>,
+    /// Named constants which can be used in the formulas of the axes.
+    #[serde(default)]
+    pub constants: BTreeMap<String, f64>,
+    /// Filters applied to the voltage `v1` before the formulas are evaluated.
+    #[serde(default = "default_filters")]
+    pub filters_v1: Vec<FilterConfig>,
+    /// Filters applied to the voltage `v2` before the formulas are evaluated.
+    #[serde(default = "default_filters")]
+    pub filters_v2: Vec<FilterConfig>,
+    /// TOML or CSV file the waypoints of `ControlMode::Sequence` are loaded from at startup.
+    #[serde(default = "default_sequence_path")]
+    pub sequence_path: PathBuf,
+    /// Number of times the sequence is played, 0 plays it endlessly.
+    #[serde(default = "default_sequence_repetitions")]
+    pub sequence_repetitions: u32,
+    /// Number of retries of a Zaber command without reply.
+    #[serde(default = "default_retry_count")]
+    pub retry_timeout_count: u32,
+    /// Wait time before the first retry after a timeout, doubled for every further retry.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(default = "default_retry_timeout_backoff_ms")]
+    pub retry_timeout_backoff_ms: Duration,
+    /// Number of retries of a Zaber command with a malformed reply.
+    #[serde(default = "default_retry_count")]
+    pub retry_malformed_count: u32,
+    /// Wait time before the first retry after a malformed reply, doubled for every further retry.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(default = "default_retry_malformed_backoff_ms")]
+    pub retry_malformed_backoff_ms: Duration,
+    /// Whether the control loop faults if an axis lags behind its target for too long.
+    #[serde(default = "default_watchdog_enabled")]
+    pub watchdog_enabled: bool,
+    /// Distance in mm an axis may lag behind its commanded target.
+    #[serde(default = "default_watchdog_tolerance", deserialize_with = "units::length")]
+    pub watchdog_tolerance: f64,
+    /// Time an axis may lag behind its target by more than the tolerance
+    /// without getting closer to it.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(default = "default_watchdog_timeout_ms")]
+    pub watchdog_timeout_ms: Duration,
+    /// Whether all axes are stopped and the control loop faults on a following error,
+    /// otherwise the error is only recorded as a warning.
+    #[serde(default = "default_watchdog_stop")]
+    pub watchdog_stop: bool,
+    /// Time to wait for the axes to come to rest after the control loop exited.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(default = "default_stop_timeout_ms")]
+    pub stop_timeout_ms: Duration,
+    /// Extrapolates the targets in `ControlMode::Tracking` to compensate the latency.
+    #[serde(default)]
+    pub predictor: Option<PredictorConfig>,
+    /// Latency the targets are extrapolated by, 0 uses the measured duration of the cycles.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(default = "default_predictor_latency_ms")]
+    pub predictor_latency_ms: Duration,
+    /// Time the targets blend from the positions to the formulas at the start of
+    /// `ControlMode::Tracking` and `ControlMode::ClosedLoop`, 0 does not blend.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    #[serde(default = "default_transition_time_ms")]
+    pub transition_time_ms: Duration,
+    /// Max. speed of the targets in mm/s until they reached the formulas, 0 does not limit it.
+    #[serde(default = "default_transition_speed", deserialize_with = "units::velocity")]
+    pub transition_speed: f64,
+    #[serde(default)]
+    pub homing_policy: HomingPolicy,
+    /// File the positions of the axes are saved to when the control stops, used to
+    /// skip homing at the next start with `HomingPolicy::WhenUnhomed`.
+    #[serde(default = "default_position_store_path")]
+    pub position_store_path: PathBuf,
 }
 
 impl Config {
     pub fn default() -> Self {
         Self {
+            config_version: CONFIG_VERSION,
             cycle_time_ms: default_cycle_time_ms(),
             serial_device: default_serial_device(),
             opcua_config_path: default_opcua_config_path(),
             control_mode: default_control_mode(),
-            limit_max_coax: default_limit_max_coax(),
-            limit_min_coax: default_limit_min_coax(),
-            limit_max_cross: default_limit_max_cross(),
-            limit_min_cross: default_limit_min_cross(),
-            accel_coax: default_accel_coax(),
-            accel_cross: default_accel_cross(),
-            maxspeed_cross: default_maxspeed_coax(),
-            maxspeed_coax: default_maxspeed_coax(),
-            offset_coax: default_offset_coax(),
-            mock_zaber: default_mock_zaber(),
-            mock_adc: default_mock_adc(),
-            formula_coax: default_formula_coax(),
-            formula_cross: default_formula_cross(),
+            backend: default_backend(),
+            voltage_source: default_voltage_source(),
             web_port: default_web_port(),
+            axes: default_axes(),
+            constants: BTreeMap::new(),
+            filters_v1: default_filters(),
+            filters_v2: default_filters(),
+            sequence_path: default_sequence_path(),
+            sequence_repetitions: default_sequence_repetitions(),
+            retry_timeout_count: default_retry_count(),
+            retry_timeout_backoff_ms: default_retry_timeout_backoff_ms(),
+            retry_malformed_count: default_retry_count(),
+            retry_malformed_backoff_ms: default_retry_malformed_backoff_ms(),
+            watchdog_enabled: default_watchdog_enabled(),
+            watchdog_tolerance: default_watchdog_tolerance(),
+            watchdog_timeout_ms: default_watchdog_timeout_ms(),
+            watchdog_stop: default_watchdog_stop(),
+            stop_timeout_ms: default_stop_timeout_ms(),
+            predictor: None,
+            predictor_latency_ms: default_predictor_latency_ms(),
+            transition_time_ms: default_transition_time_ms(),
+            transition_speed: default_transition_speed(),
+            homing_policy: HomingPolicy::Always,
+            position_store_path: default_position_store_path(),
+        }
+    }
+
+    /// Names of the axes in the order of their indices.
+    pub fn axis_names(&self) -> Vec<String> {
+        self.axes.iter().map(|axis| axis.name.clone()).collect()
+    }
+
+    /// Lists the settings which differ in `new`, split by whether the running control loop
+    /// can pick them up. Limits can only be narrowed while running, as the limits of the
+    /// devices are set when the control starts.
+    pub fn changes(&self, new: &Config) -> ConfigChanges {
+        let mut changes = ConfigChanges::default();
+        let mut check = |name: String, changed: bool, hot: bool| match (changed, hot) {
+            (false, _) => (),
+            (true, true) => changes.hot.push(name),
+            (true, false) => changes.cold.push(name),
+        };
+
+        check("cycle_time_ms".into(), self.cycle_time_ms != new.cycle_time_ms, false);
+        check("serial_device".into(), self.serial_device != new.serial_device, false);
+        check(
+            "opcua_config_path".into(),
+            self.opcua_config_path != new.opcua_config_path,
+            false,
+        );
+        check("control_mode".into(), self.control_mode != new.control_mode, false);
+        check("backend".into(), self.backend != new.backend, false);
+        check("voltage_source".into(), self.voltage_source != new.voltage_source, false);
+        check("web_port".into(), self.web_port != new.web_port, false);
+        check("constants".into(), self.constants != new.constants, true);
+        check("filters_v1".into(), self.filters_v1 != new.filters_v1, true);
+        check("filters_v2".into(), self.filters_v2 != new.filters_v2, true);
+        check("sequence_path".into(), self.sequence_path != new.sequence_path, false);
+        check(
+            "sequence_repetitions".into(),
+            self.sequence_repetitions != new.sequence_repetitions,
+            false,
+        );
+        let retry = |c: &Config| {
+            (
+                c.retry_timeout_count,
+                c.retry_timeout_backoff_ms,
+                c.retry_malformed_count,
+                c.retry_malformed_backoff_ms,
+            )
+        };
+        check("retry".into(), retry(self) != retry(new), true);
+        let watchdog = |c: &Config| {
+            (
+                c.watchdog_enabled,
+                c.watchdog_tolerance,
+                c.watchdog_timeout_ms,
+                c.watchdog_stop,
+            )
+        };
+        check("watchdog".into(), watchdog(self) != watchdog(new), true);
+        check("stop_timeout_ms".into(), self.stop_timeout_ms != new.stop_timeout_ms, false);
+        check("predictor".into(), self.predictor != new.predictor, true);
+        check(
+            "predictor_latency_ms".into(),
+            self.predictor_latency_ms != new.predictor_latency_ms,
+            true,
+        );
+        let transition = |c: &Config| (c.transition_time_ms, c.transition_speed);
+        check("transition".into(), transition(self) != transition(new), true);
+        check("homing_policy".into(), self.homing_policy != new.homing_policy, false);
+        check(
+            "position_store_path".into(),
+            self.position_store_path != new.position_store_path,
+            false,
+        );
+
+        if self.axis_names() != new.axis_names() {
+            check("axes".into(), true, false);
+            return changes;
+        }
+        for (i, (a, b)) in self.axes.iter().zip(&new.axes).enumerate() {
+            let mut check_axis = |field: &str, changed: bool, hot: bool| {
+                check(format!("axes[{}].{}", i, field), changed, hot)
+            };
+            let address = |axis: &AxisConfig| (axis.device, axis.axis, axis.lockstep);
+            check_axis("address", address(a) != address(b), false);
+            check_axis("offset", a.offset != b.offset, false);
+            check_axis("enabled", a.enabled != b.enabled, false);
+            check_axis("accel", a.accel != b.accel, false);
+            check_axis("waveform", a.waveform != b.waveform, false);
+            check_axis("limit_min", a.limit_min != b.limit_min, b.limit_min >= a.limit_min);
+            check_axis("limit_max", a.limit_max != b.limit_max, b.limit_max <= a.limit_max);
+            check_axis("limit_policy", a.limit_policy != b.limit_policy, true);
+            check_axis("maxspeed", a.maxspeed != b.maxspeed, true);
+            check_axis("formula", a.formula != b.formula, true);
+            check_axis("target_kind", a.target_kind != b.target_kind, true);
+            check_axis("calibration", a.calibration != b.calibration, true);
+            check_axis("kp", a.kp != b.kp, true);
+            check_axis("ki", a.ki != b.ki, true);
+            check_axis("kd", a.kd != b.kd, true);
+            check_axis("deadband", a.deadband != b.deadband, true);
+            check_axis("hysteresis", a.hysteresis != b.hysteresis, true);
+        }
+
+        changes
+    }
+
+    /// Checks the ranges and the consistency of the settings and compiles the formulas
+    /// and calibration tables. Every invalid setting is reported, named like the fields
+    /// of the web form, e.g. `axes[0].limit_min`.
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut errors = Vec::new();
+        let mut check = |field: String, valid: bool, message: &str| {
+            if !valid {
+                errors.push(ConfigError {
+                    field,
+                    message: message.into(),
+                });
+            }
+        };
+        let non_negative = |x: f64| x.is_finite() && x >= 0.;
+
+        check("cycle_time_ms".into(), !self.cycle_time_ms.is_zero(), "Must be greater than 0");
+        check(
+            "backend".into(),
+            BACKENDS.iter().any(|(name, _)| *name == self.backend),
+            &format!("Unknown backend {}", self.backend),
+        );
+        check(
+            "voltage_source".into(),
+            VOLTAGE_SOURCES.iter().any(|(name, _)| *name == self.voltage_source),
+            &format!("Unknown voltage source {}", self.voltage_source),
+        );
+        check(
+            "web_port".into(),
+            (1..=65535).contains(&self.web_port),
+            "Must be between 1 and 65535",
+        );
+        check("axes".into(), !self.axes.is_empty(), "At least one axis is required");
+
+        let variables = variable_names(self);
+        for (name, value) in &self.constants {
+            let field = format!("constants.{}", name);
+            check(field.clone(), value.is_finite(), "Must be a finite number");
+            check(field, !variables.contains(name), "Shadows a variable of the formulas");
+        }
+        for (key, filters) in [("filters_v1", &self.filters_v1), ("filters_v2", &self.filters_v2)] {
+            for (k, filter) in filters.iter().enumerate() {
+                let valid = match *filter {
+                    FilterConfig::MovingAverage { window } | FilterConfig::Median { window } => {
+                        window > 0
+                    }
+                    FilterConfig::LowPass { cutoff_hz } => cutoff_hz.is_finite() && cutoff_hz > 0.,
+                    FilterConfig::OutlierRejection {
+                        window,
+                        max_deviation,
+                    } => window > 0 && non_negative(max_deviation),
+                };
+                check(
+                    format!("{}[{}]", key, k),
+                    valid,
+                    "Windows and frequencies must be greater than 0",
+                );
+            }
+        }
+        if let Some(PredictorConfig::Kalman {
+            process_noise,
+            measurement_noise,
+        }) = self.predictor
+        {
+            check(
+                "predictor".into(),
+                non_negative(process_noise)
+                    && measurement_noise.is_finite()
+                    && measurement_noise > 0.,
+                "The noise must not be negative and the measurement noise not 0",
+            );
+        }
+        check(
+            "watchdog_tolerance".into(),
+            non_negative(self.watchdog_tolerance),
+            "Must not be negative",
+        );
+        check(
+            "watchdog_timeout_ms".into(),
+            !self.watchdog_enabled || !self.watchdog_timeout_ms.is_zero(),
+            "Must be greater than 0",
+        );
+        check(
+            "transition_speed".into(),
+            non_negative(self.transition_speed),
+            "Must not be negative",
+        );
+
+        for (i, axis) in self.axes.iter().enumerate() {
+            let field = |name: &str| format!("axes[{}].{}", i, name);
+
+            check(
+                field("name"),
+                !axis.name.is_empty()
+                    && axis.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "Must only contain letters, digits and underscores",
+            );
+            check(
+                field("name"),
+                self.axes[..i].iter().all(|other| other.name != axis.name),
+                &format!("The name {} is used by another axis", axis.name),
+            );
+            check(field("device"), axis.device > 0, "Must be greater than 0");
+            let address = |axis: &AxisConfig| (axis.device, axis.axis);
+            check(
+                field("device"),
+                !axis.enabled
+                    || self.axes[..i]
+                        .iter()
+                        .all(|other| !other.enabled || address(other) != address(axis)),
+                "The device and axis are used by another axis",
+            );
+            let [_, limit_max] = axis.limits_steps();
+            check(
+                field("limit_max"),
+                axis.limit_max.is_finite() && limit_max <= MAX_POS,
+                &format!("Must not be greater than {:.4} mm", steps_to_mm(MAX_POS)),
+            );
+            check(
+                field("limit_min"),
+                non_negative(axis.limit_min) && axis.limit_min <= axis.limit_max,
+                "Must not be negative or greater than limit_max",
+            );
+            check(
+                field("maxspeed"),
+                axis.maxspeed.is_finite() && (1..=MAX_SPEED).contains(&axis.maxspeed_steps()),
+                &format!("Must be greater than 0 and at most {:.3} mm/s", steps_to_vel(MAX_SPEED)),
+            );
+            check(
+                field("accel"),
+                axis.accel.is_finite() && accel_to_steps(axis.accel) > 0,
+                "Must be greater than 0",
+            );
+            check(field("offset"), axis.offset.is_finite(), "Must be a finite number");
+            for (name, gain) in [("kp", axis.kp), ("ki", axis.ki), ("kd", axis.kd)] {
+                check(field(name), gain.is_finite(), "Must be a finite number");
+            }
+            check(field("deadband"), non_negative(axis.deadband), "Must not be negative");
+            check(
+                field("hysteresis"),
+                non_negative(axis.hysteresis) && axis.hysteresis <= axis.deadband,
+                "Must not be negative or greater than the deadband",
+            );
+
+            match (&axis.target_kind, &axis.calibration) {
+                (TargetKind::Formula, _) => {
+                    if let Err(e) = check_formula(&axis.formula, self) {
+                        check(field("formula"), false, &e.to_string());
+                    }
+                }
+                (TargetKind::Calibration, Some(calibration)) => {
+                    if let Err(e) = CalibrationTable::new(calibration) {
+                        check(field("calibration"), false, &e.to_string());
+                    }
+                }
+                (TargetKind::Calibration, None) => {
+                    check(field("target_kind"), false, "No calibration table configured")
+                }
+            }
+
+            if let Some(waveform) = &axis.waveform {
+                check(
+                    field("waveform"),
+                    waveform.amplitude.is_finite()
+                        && waveform.offset.is_finite()
+                        && non_negative(waveform.frequency)
+                        && non_negative(waveform.frequency_end)
+                        && non_negative(waveform.duration),
+                    "The frequencies and the duration must not be negative",
+                );
+                // The frequency of a chirp rises within the duration
+                if waveform.shape == WaveformShape::Chirp {
+                    check(
+                        field("waveform.duration"),
+                        waveform.duration > 0.,
+                        "Must be greater than 0 for a chirp",
+                    );
+                }
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigErrors(errors)),
         }
     }
 }
 
+/// Invalid setting of a config.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigError {
+    /// Name of the setting like the field of the web form, e.g. `axes[0].limit_min`.
+    pub field: String,
+    pub message: String,
+}
+
+/// All invalid settings of a config, displayed as one `field: message` per line.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines: Vec<String> = self
+            .0
+            .iter()
+            .map(|error| format!("{}: {}", error.field, error.message))
+            .collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// Settings which differ between two configs, named like the fields of the web form.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ConfigChanges {
+    /// Settings the running control loop picks up in its next cycle.
+    pub hot: Vec<String>,
+    /// Settings which only take effect when the control is started.
+    pub cold: Vec<String>,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum ControlStatus {
     Stopped,
     Running,
     Error,
+    /// Latched until the emergency stop is reset.
+    EmergencyStopped,
 }
 
 impl Display for ControlStatus {
@@ -175,28 +838,192 @@ impl Display for ControlStatus {
             Self::Running => "Running",
             Self::Stopped => "Stopped",
             Self::Error => "Error",
+            Self::EmergencyStopped => "Emergency Stopped",
         };
         write!(f, "{}", text)
     }
 }
 
+/// State of the control published to the interfaces, the per-axis values are in the
+/// order of the configured axes.
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct SharedState {
-    pub target: [u32; 2],
-    pub position: [u32; 2],
+    /// Names of the axes.
+    pub axes: Vec<String>,
+    /// The targets after the prediction, which are sent to the axes.
+    pub target: Vec<u32>,
+    /// The targets as returned by the formulas or the active mode.
+    pub target_raw: Vec<u32>,
+    pub position: Vec<u32>,
+    /// The filtered voltages the targets are computed from.
     pub voltage: [f64; 2],
-    pub is_busy: [bool; 2],
+    /// The voltages as read from the voltage source.
+    pub voltage_raw: [f64; 2],
+    pub is_busy: Vec<bool>,
+    /// Axes which are disabled in the config are reported as idle at position 0.
+    pub enabled: Vec<bool>,
+    /// Number of moves sent to the axes.
+    pub moves_issued: Vec<u64>,
+    /// Number of target changes which did not cause a move due to the deadband.
+    pub moves_suppressed: Vec<u64>,
+    /// Index of the active waypoint in `ControlMode::Sequence`.
+    pub sequence_step: Option<usize>,
+    /// Number of finished repetitions in `ControlMode::Sequence`.
+    pub sequence_repetition: u32,
+    /// Number of Zaber commands which succeeded after a retry.
+    pub errors_recovered: u64,
+    /// Whether the target of the last cycle was outside of the limits.
+    pub out_of_limits: Vec<bool>,
+    /// Number of cycles with a target outside of the limits.
+    pub limit_violations: Vec<u64>,
+    /// Distance between the commanded targets and the positions in microsteps.
+    pub following_error: Vec<u32>,
+    /// Whether the axes came to rest after the control loop exited, `None` while it runs.
+    pub at_rest: Option<bool>,
+    /// Set while the emergency stop is latched.
+    pub estop: Option<EstopTrigger>,
+    pub timing: TimingReport,
     pub control_state: ControlStatus,
-    pub error: Option<String>,
+    pub error: Option<ErrorReport>,
     pub timestamp: DateTime<Local>,
 }
 
+impl SharedState {
+    pub fn new(axes: &[AxisConfig]) -> Self {
+        let mut state = Self {
+            axes: Vec::new(),
+            target: Vec::new(),
+            target_raw: Vec::new(),
+            position: Vec::new(),
+            voltage: [0.; 2],
+            voltage_raw: [0.; 2],
+            is_busy: Vec::new(),
+            enabled: Vec::new(),
+            moves_issued: Vec::new(),
+            moves_suppressed: Vec::new(),
+            out_of_limits: Vec::new(),
+            limit_violations: Vec::new(),
+            sequence_step: None,
+            sequence_repetition: 0,
+            errors_recovered: 0,
+            following_error: Vec::new(),
+            at_rest: None,
+            estop: None,
+            timing: TimingReport::default(),
+            control_state: ControlStatus::Stopped,
+            error: None,
+            timestamp: Local::now(),
+        };
+        state.set_axes(axes);
+        state
+    }
+
+    /// Sizes the per-axis values for `axes`, the values of the first axes are kept.
+    pub fn set_axes(&mut self, axes: &[AxisConfig]) {
+        let n = axes.len();
+        self.axes = axes.iter().map(|axis| axis.name.clone()).collect();
+        self.enabled = axes.iter().map(|axis| axis.enabled).collect();
+        self.target.resize(n, 0);
+        self.target_raw.resize(n, 0);
+        self.position.resize(n, 0);
+        self.is_busy.resize(n, false);
+        self.moves_issued.resize(n, 0);
+        self.moves_suppressed.resize(n, 0);
+        self.out_of_limits.resize(n, false);
+        self.limit_violations.resize(n, 0);
+        self.following_error.resize(n, 0);
+    }
+}
+
+impl Default for SharedState {
+    fn default() -> Self {
+        Self::new(&default_axes())
+    }
+}
+
+/// Who triggered an emergency stop and when.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EstopTrigger {
+    pub source: String,
+    pub timestamp: DateTime<Local>,
+}
+
+/// Latched emergency stop shared between the control loop and the interfaces.
+/// Once triggered, the control loop can only be started again after a reset.
+#[derive(Clone, Debug)]
+pub struct Estop {
+    latch: Arc<RwLock<Option<EstopTrigger>>>,
+    tx: Sender<()>,
+    rx: Receiver<()>,
+}
+
+impl Estop {
+    pub fn new() -> Self {
+        let (tx, rx) = unbounded();
+        Self {
+            latch: Arc::new(RwLock::new(None)),
+            tx,
+            rx,
+        }
+    }
+
+    /// Latches the emergency stop and wakes up the control loop.
+    /// Only the first trigger is recorded until the next reset.
+    pub fn trigger(&self, source: &str) {
+        tracing::warn!("Emergency stop triggered by {}", source);
+        let mut latch = self.latch.write().unwrap();
+        if latch.is_none() {
+            *latch = Some(EstopTrigger {
+                source: source.to_string(),
+                timestamp: Local::now(),
+            });
+        }
+        // The channel is unbounded, so the signal can not be dropped
+        let _ = self.tx.send(());
+    }
+
+    pub fn reset(&self) -> Result<()> {
+        let mut latch = self.latch.write().unwrap();
+        if latch.is_none() {
+            return Err(anyhow!("The emergency stop is not triggered"));
+        }
+
+        tracing::info!("Emergency stop reset");
+        *latch = None;
+        while self.rx.try_recv().is_ok() {}
+        // Lets the control thread publish the new state
+        let _ = self.tx.send(());
+        Ok(())
+    }
+
+    pub fn triggered(&self) -> Option<EstopTrigger> {
+        self.latch.read().unwrap().clone()
+    }
+
+    /// Receives a signal every time the emergency stop is triggered or reset.
+    pub fn receiver(&self) -> &Receiver<()> {
+        &self.rx
+    }
+}
+
+impl Default for Estop {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug)]
 pub struct ExecState {
     pub shared: SharedState,
     pub out_channel: StateChannel,
     pub rx_stop: StopChannel,
-    pub target_manual: Arc<RwLock<[u32; 2]>>,
+    /// Signals that the settings which can be changed while running were changed.
+    pub rx_reload: Receiver<()>,
+    pub estop: Estop,
+    pub history: ErrorHistory,
+    /// Targets of the axes in `ControlMode::Manual`, axes without a target keep their position.
+    pub target_manual: Arc<RwLock<Vec<u32>>>,
+    pub sequence: Arc<RwLock<Vec<Waypoint>>>,
     pub config: Arc<RwLock<Config>>,
 }
 
@@ -205,9 +1032,13 @@ pub fn read_config() -> Result<Config> {
         Ok(config) => {
             tracing::debug!("`config.toml` successfully read");
 
-            match toml::from_str(&config) {
+            match parse_config(&config) {
                 Ok(config) => {
                     tracing::debug!("`config.toml` successfully parsed");
+                    if let Err(e) = config.validate() {
+                        tracing::error!("invalid `config.toml`:\n{}", e);
+                        return Err(e.into());
+                    }
                     Ok(config)
                 }
                 Err(e) => {
@@ -223,6 +1054,157 @@ pub fn read_config() -> Result<Config> {
     }
 }
 
+/// Parses a config, older versions are migrated first.
+pub fn parse_config(s: &str) -> Result<Config> {
+    let mut table: toml::Table = toml::from_str(s)?;
+    for note in migrate_config(&mut table)? {
+        tracing::warn!("migrated `config.toml`: {}", note);
+    }
+    Ok(toml::Value::Table(table).try_into()?)
+}
+
+/// Migrates a config of version 1 in place. The settings of the two fixed axes, e.g.
+/// `limit_max_coax`, are moved into `axes` and the settings of the axes are converted from
+/// Zaber units. The flags `mock_zaber` and `mock_adc` are replaced by the names of the
+/// backend and the voltage source. Returns a note for every migrated setting.
+pub fn migrate_config(table: &mut toml::Table) -> Result<Vec<String>, ConfigErrors> {
+    let error = |field: &str, message: String| {
+        ConfigErrors(vec![ConfigError {
+            field: field.into(),
+            message,
+        }])
+    };
+    match table.get("config_version").map(toml::Value::as_integer) {
+        None | Some(Some(1)) => (),
+        Some(Some(version)) if version == CONFIG_VERSION as i64 => return Ok(Vec::new()),
+        Some(_) => {
+            return Err(error(
+                "config_version",
+                format!("Unsupported version, expected 1 or {}", CONFIG_VERSION),
+            ))
+        }
+    }
+
+    let mut notes = Vec::new();
+    let legacy_key = |field: &str, name: &str| format!("{}_{}", field, name);
+    let legacy: Vec<String> = LEGACY_AXES
+        .iter()
+        .flat_map(|(name, ..)| LEGACY_FIELDS.iter().map(|field| legacy_key(field, name)))
+        .filter(|key| table.contains_key(key))
+        .collect();
+    if !legacy.is_empty() && table.contains_key("axes") {
+        return Err(ConfigErrors(
+            legacy
+                .into_iter()
+                .map(|key| ConfigError {
+                    field: key,
+                    message: "Replaced by the settings in axes, remove it".into(),
+                })
+                .collect(),
+        ));
+    }
+    if !legacy.is_empty() {
+        let mut axes = Vec::new();
+        for (i, (name, device, lockstep)) in LEGACY_AXES.iter().enumerate() {
+            let mut axis = toml::Table::new();
+            axis.insert("name".into(), (*name).into());
+            axis.insert("device".into(), i64::from(*device).into());
+            if let Some(group) = lockstep {
+                axis.insert("lockstep".into(), i64::from(*group).into());
+            }
+            if *name == "coax" {
+                axis.insert("formula".into(), default_formula_coax().into());
+            }
+            for field in LEGACY_FIELDS {
+                if let Some(value) = table.remove(&legacy_key(field, name)) {
+                    let key = legacy_key(field, name);
+                    notes.push(format!("{} moved to axes[{}].{}", key, i, field));
+                    axis.insert(field.into(), value);
+                }
+            }
+            axes.push(toml::Value::Table(axis));
+        }
+        table.insert("axes".into(), toml::Value::Array(axes));
+    }
+
+    let mut errors = Vec::new();
+    let legacy_sources = [
+        ("mock_zaber", "backend", "simulator", "zaber"),
+        ("mock_adc", "voltage_source", "mock", "adc"),
+    ];
+    for (key, field, mock, real) in legacy_sources {
+        let Some(value) = table.remove(key) else {
+            continue;
+        };
+        let message = match (value.as_bool(), table.contains_key(field)) {
+            (_, true) => format!("Replaced by {}, remove it", field),
+            (None, false) => "Expected a boolean".into(),
+            (Some(is_mock), false) => {
+                let name = if is_mock { mock } else { real };
+                table.insert(field.into(), name.into());
+                notes.push(format!("{} replaced by {} = \"{}\"", key, field, name));
+                continue;
+            }
+        };
+        errors.push(ConfigError {
+            field: key.into(),
+            message,
+        });
+    }
+
+    let axes = table.get_mut("axes").and_then(toml::Value::as_array_mut);
+    for (i, axis) in axes.into_iter().flatten().enumerate() {
+        let Some(axis) = axis.as_table_mut() else {
+            continue;
+        };
+        for field in ["limit_min", "limit_max", "maxspeed", "accel", "offset"] {
+            let Some(value) = axis.get_mut(field) else {
+                continue;
+            };
+            // Values with a unit were already written with physical units
+            let steps = match value {
+                toml::Value::String(_) => continue,
+                toml::Value::Integer(steps) => *steps,
+                _ => {
+                    errors.push(ConfigError {
+                        field: format!("axes[{}].{}", i, field),
+                        message: format!(
+                            "Expected an integer in Zaber units, set config_version = {} if \
+                             the value is in mm",
+                            CONFIG_VERSION
+                        ),
+                    });
+                    continue;
+                }
+            };
+            *value = match field {
+                "maxspeed" => (steps as f64 * steps_to_vel(1)).into(),
+                "accel" => (steps as f64 * steps_to_accel(1)).into(),
+                _ => format!("{} steps", steps).into(),
+            };
+            notes.push(format!("axes[{}].{} converted from Zaber units", i, field));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ConfigErrors(errors));
+    }
+
+    table.insert("config_version".into(), i64::from(CONFIG_VERSION).into());
+    Ok(notes)
+}
+
+/// Switches the control mode if the config is valid with it and writes the config.
+/// The control loop picks up the mode when it is stopped.
+pub fn set_control_mode(config: &RwLock<Config>, mode: ControlMode) -> Result<()> {
+    let mut config_new = config.read().unwrap().clone();
+    config_new.control_mode = mode.clone();
+    config_new.validate()?;
+
+    write_config(&config_new)?;
+    config.write().unwrap().control_mode = mode;
+    Ok(())
+}
+
 pub fn write_config(config_new: &Config) -> Result<()> {
     return match toml::to_string_pretty(&config_new) {
         Ok(config) => {
@@ -254,3 +1236,135 @@ pub fn write_config(config_new: &Config) -> Result<()> {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_changes() {
+        let config = Config::default();
+        assert_eq!(config.changes(&config), ConfigChanges::default());
+
+        let mut new = config.clone();
+        new.serial_device = "/dev/ttyUSB0".into();
+        new.filters_v1 = vec![FilterConfig::Median { window: 3 }];
+        new.axes[0].formula = "v1".into();
+        new.axes[0].limit_max -= 10.;
+        new.axes[1].limit_min += 10.;
+        new.axes[1].accel += 1.;
+        let changes = config.changes(&new);
+        assert_eq!(
+            changes.hot,
+            vec!["filters_v1", "axes[0].limit_max", "axes[0].formula", "axes[1].limit_min"]
+        );
+        assert_eq!(changes.cold, vec!["serial_device", "axes[1].accel"]);
+
+        // Widening the limits needs the devices to be set up again
+        let changes = new.changes(&config);
+        assert_eq!(
+            changes.cold,
+            vec!["serial_device", "axes[0].limit_max", "axes[1].accel", "axes[1].limit_min"]
+        );
+
+        new.axes.pop();
+        assert!(config.changes(&new).cold.contains(&"axes".to_string()));
+    }
+
+    #[test]
+    fn test_config_validate() {
+        let mut config = Config::default();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.cycle_time_ms = Duration::ZERO;
+        config.axes[0].limit_min = 50.;
+        config.axes[0].limit_max = 40.;
+        config.axes[1].limit_max = 101.;
+        config.axes[1].formula = "v3 * 2".into();
+        config.constants.insert("t".into(), 1.);
+        let errors = config.validate().unwrap_err();
+        let fields: Vec<&str> = errors.0.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "cycle_time_ms",
+                "constants.t",
+                "axes[0].limit_min",
+                "axes[1].limit_max",
+                "axes[1].formula"
+            ]
+        );
+
+        let mut config = Config::default();
+        config.axes[1].name = "coax".into();
+        config.axes[1].formula = "gain * v2".into();
+        config.constants.insert("gain".into(), 2.);
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.to_string(), "axes[1].name: The name coax is used by another axis");
+
+        let mut config = Config::default();
+        config.axes[0].waveform = Some(WaveformConfig {
+            shape: WaveformShape::Chirp,
+            amplitude: 1.,
+            offset: 0.,
+            frequency: 1.,
+            frequency_end: 5.,
+            duration: 0.,
+        });
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.0[0].field, "axes[0].waveform.duration");
+    }
+
+    #[test]
+    fn test_migrate_config() {
+        let legacy = "
+            limit_max_coax = 201574
+            maxspeed_coax = 153600
+            accel_coax = 205
+            offset_coax = -100
+            formula_cross = \"10\"
+        ";
+        let config = parse_config(legacy).unwrap();
+        assert_eq!(config.config_version, CONFIG_VERSION);
+        let [coax, cross] = &config.axes[..] else {
+            panic!("expected two axes, got {:?}", config.axes);
+        };
+        assert_eq!((coax.device, coax.lockstep), (1, Some(1)));
+        assert_eq!(coax.limits_steps(), [0, MAX_POS]);
+        assert_eq!(coax.maxspeed_steps(), MAX_SPEED);
+        assert_eq!(accel_to_steps(coax.accel), 205);
+        assert_eq!(distance_to_steps(coax.offset), -100);
+        assert_eq!(coax.formula, default_formula_coax());
+        assert_eq!((cross.device, cross.formula.as_str()), (2, "10"));
+
+        // The axes of version 1 are in Zaber units, values with units are kept
+        let config = parse_config(
+            "[[axes]]\nname = \"x\"\ndevice = 1\nmaxspeed = 153600\nlimit_max = \"20 mm\"",
+        )
+        .unwrap();
+        assert_eq!(config.axes[0].maxspeed_steps(), MAX_SPEED);
+        assert_eq!(config.axes[0].limit_max, 20.);
+        let config =
+            parse_config("config_version = 2\n[[axes]]\nname = \"x\"\ndevice = 1\nmaxspeed = 10")
+                .unwrap();
+        assert_eq!(config.axes[0].maxspeed, 10.);
+
+        // The mock flags select the backend and the voltage source by name
+        let config = parse_config("mock_zaber = true\nmock_adc = false").unwrap();
+        assert_eq!((config.backend.as_str(), config.voltage_source.as_str()), ("simulator", "adc"));
+        let error = parse_config("mock_zaber = true\nbackend = \"zaber\"").unwrap_err();
+        let errors = error.downcast_ref::<ConfigErrors>().unwrap();
+        assert_eq!(errors.0[0].field, "mock_zaber");
+
+        // Ambiguous values are rejected instead of guessed
+        let error = parse_config("[[axes]]\nname = \"x\"\ndevice = 1\naccel = 620.73").unwrap_err();
+        let errors = error.downcast_ref::<ConfigErrors>().unwrap();
+        assert_eq!(errors.0[0].field, "axes[0].accel");
+        let error =
+            parse_config("formula_coax = \"v1\"\n[[axes]]\nname = \"x\"\ndevice = 1").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "formula_coax: Replaced by the settings in axes, remove it"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::{fmt::Display, time::Duration};
+
+use serde::Serialize;
+
+use crate::zaber::steps_to_mm;
+
+/// Fault raised if an axis lags behind its commanded target for too long.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FollowingError {
+    pub axis: String,
+    /// Distance to the target in mm.
+    pub lag: f64,
+    /// Time in seconds the axis has been lagging.
+    pub duration: f64,
+}
+
+impl Display for FollowingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Following error on axis {}: {:.3} mm behind the target for {:.1} s, the axis might be blocked",
+            self.axis, self.lag, self.duration
+        )
+    }
+}
+
+impl std::error::Error for FollowingError {}
+
+/// Detects stalled or blocked axes by tracking how long they lag behind their targets
+/// without getting closer to them. Slow moves which make progress are not faults.
+#[derive(Clone, Debug)]
+pub struct Watchdog {
+    /// Allowed distance to the target in microsteps.
+    pub tolerance: u32,
+    pub timeout: Duration,
+    /// Whether all axes are stopped when a following error is detected.
+    pub stop: bool,
+    /// Names of the axes, used in the error.
+    axes: Vec<String>,
+    lag_time: Vec<f64>,
+    positions_last: Vec<Option<u32>>,
+    /// Distance to the target in the previous cycle.
+    lags_last: Vec<Option<u32>>,
+}
+
+impl Watchdog {
+    pub fn new(axes: Vec<String>, tolerance: u32, timeout: Duration, stop: bool) -> Self {
+        Self {
+            tolerance,
+            timeout,
+            stop,
+            lag_time: vec![0.; axes.len()],
+            positions_last: vec![None; axes.len()],
+            lags_last: vec![None; axes.len()],
+            axes,
+        }
+    }
+
+    /// Updates the lag times with the measurements of a cycle `dt` seconds after the previous one.
+    /// Axes without a commanded target are not checked.
+    pub fn check(
+        &mut self,
+        targets: &[Option<u32>],
+        positions: &[u32],
+        dt: f64,
+    ) -> Result<(), FollowingError> {
+        for i in 0..self.axes.len() {
+            let is_moving = self.positions_last[i].is_some_and(|p| p != positions[i]);
+            self.positions_last[i] = Some(positions[i]);
+
+            let Some(target) = targets[i] else {
+                self.lag_time[i] = 0.;
+                self.lags_last[i] = None;
+                continue;
+            };
+
+            let lag = target.abs_diff(positions[i]);
+            let is_converging = is_moving && self.lags_last[i].is_some_and(|last| lag < last);
+            self.lags_last[i] = Some(lag);
+            if lag > self.tolerance && !is_converging {
+                self.lag_time[i] += dt;
+            } else {
+                self.lag_time[i] = 0.;
+            }
+
+            if self.lag_time[i] > self.timeout.as_secs_f64() {
+                let duration = self.lag_time[i];
+                // A lag which persists is reported again after another timeout
+                self.lag_time[i] = 0.;
+                return Err(FollowingError {
+                    axis: self.axes[i].clone(),
+                    lag: steps_to_mm(lag),
+                    duration,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watchdog() -> Watchdog {
+        Watchdog::new(
+            vec!["coax".into(), "cross".into()],
+            10,
+            Duration::from_secs(1),
+            true,
+        )
+    }
+
+    #[test]
+    fn test_watchdog_blocked() {
+        let mut watchdog = watchdog();
+        let targets = [Some(1000), None];
+
+        for _ in 0..3 {
+            assert!(watchdog.check(&targets, &[500, 0], 0.3).is_ok());
+        }
+
+        let error = watchdog.check(&targets, &[500, 0], 0.3).unwrap_err();
+        assert_eq!(error.axis, "coax");
+        assert_eq!(error.lag, steps_to_mm(500));
+
+        // The lag time starts over after a report
+        for _ in 0..3 {
+            assert!(watchdog.check(&targets, &[500, 0], 0.3).is_ok());
+        }
+        assert!(watchdog.check(&targets, &[500, 0], 0.3).is_err());
+    }
+
+    #[test]
+    fn test_watchdog_reached() {
+        let mut watchdog = watchdog();
+        let targets = [Some(1000), Some(1000)];
+
+        for _ in 0..3 {
+            assert!(watchdog.check(&targets, &[500, 500], 0.3).is_ok());
+        }
+
+        // Reaching the target within the tolerance resets the lag time
+        assert!(watchdog.check(&targets, &[995, 1000], 0.3).is_ok());
+        for _ in 0..3 {
+            assert!(watchdog.check(&targets, &[500, 500], 0.3).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_watchdog_slow_move() {
+        let mut watchdog = watchdog();
+        let targets = [Some(10000), None];
+
+        // A busy axis which keeps getting closer is not lagging, however long it takes
+        for step in 0..20 {
+            assert!(watchdog.check(&targets, &[step * 100, 0], 0.3).is_ok());
+        }
+
+        // Standing still or moving away counts as lagging
+        for position in [1900, 1800, 1700] {
+            assert!(watchdog.check(&targets, &[position, 0], 0.3).is_ok());
+        }
+        assert!(watchdog.check(&targets, &[1600, 0], 0.3).is_err());
+    }
+}
@@ -1,5 +1,8 @@
 use std::{
     collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    str::FromStr,
     sync::{Arc, RwLock},
     time::Duration,
 };
@@ -12,13 +15,26 @@ use axum::{
     Form, Json, Router,
 };
 use axum::extract::{
-        self, ws::{Message, WebSocket}, State, WebSocketUpgrade
+        self, ws::{Message, WebSocket}, ConnectInfo, State, WebSocketUpgrade
     };
 use crossbeam_channel::Sender;
 use futures::{SinkExt, StreamExt};
+use serde::Serialize;
 use serde_json;
 
-use crate::utils::{self, write_config, Config, ControlMode, ControlStatus, SharedState};
+use crate::{
+    calibration::{CalibrationConfig, CalibrationTable},
+    error::ControlError,
+    history::{ErrorHistory, HistoryEntry},
+    sequence::Waypoint,
+    timing::TimingReport,
+    units::{self, Quantity},
+    utils::{
+        self, set_control_mode, write_config, AxisConfig, Config, ControlMode, ControlStatus, Estop,
+        SharedState, TargetKind,
+    },
+    wizard::{CalibrationSession, Fit, FitModel},
+};
 
 const STYLE: &str = include_str!("style.css");
 const SCRIPT: &str = include_str!("script.js");
@@ -29,8 +45,13 @@ pub struct WebState {
     pub zaber_state: Arc<RwLock<SharedState>>,
     pub tx_start_control: Sender<()>,
     pub tx_stop_control: Sender<()>,
-    pub target_manual: Arc<RwLock<[u32; 2]>>,
+    pub tx_reload_control: Sender<()>,
+    pub estop: Estop,
+    pub history: ErrorHistory,
+    pub target_manual: Arc<RwLock<Vec<u32>>>,
+    pub sequence: Arc<RwLock<Vec<Waypoint>>>,
     pub config: Arc<RwLock<utils::Config>>,
+    pub calibration: Arc<RwLock<CalibrationSession>>,
 }
 
 // Make our own error that wraps `anyhow::Error`.
@@ -39,7 +60,24 @@ struct AppError(anyhow::Error);
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", self.0)).into_response()
+        let status = status_code(&ControlError::from_error(&self.0));
+        (status, format!("{:#}", self.0)).into_response()
+    }
+}
+
+/// HTTP status of a request failing with `error`.
+fn status_code(error: &ControlError) -> StatusCode {
+    match error {
+        ControlError::Config { .. } | ControlError::Formula { .. } | ControlError::Limit { .. } => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        ControlError::EmergencyStop { .. } => StatusCode::CONFLICT,
+        ControlError::Adc | ControlError::ZaberComm { .. } | ControlError::ZaberRejected { .. } => {
+            StatusCode::BAD_GATEWAY
+        }
+        ControlError::FollowingError(_) | ControlError::Internal => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
     }
 }
 
@@ -87,140 +125,164 @@ async fn handle_refresh(State(state): State<WebState>) -> Json<SharedState> {
     return state;
 }
 
+async fn handle_get_timing(State(state): State<WebState>) -> Json<TimingReport> {
+    tracing::debug!("GET /timing requested");
+    Json(state.zaber_state.read().unwrap().timing.clone())
+}
+
 async fn handle_post_mode(
     extract::Path(new_mode): extract::Path<ControlMode>,
     State(state): State<WebState>,
 ) -> Result<(), AppError> {
     tracing::debug!("POST mode requested - new mode: {:?}", new_mode);
-    let mut config_new = state.config.read().unwrap().clone();
-    config_new.control_mode = new_mode.clone();
-
-    write_config(&config_new)?;
-    {
-        state.config.write().unwrap().control_mode = new_mode;
-    }
+    set_control_mode(&state.config, new_mode)?;
 
     state.tx_stop_control.try_send(())?;
     tracing::debug!("POST mode exit");
     Ok(())
 }
 
+/// Invalid value of the form field `name`, its message starts with the name so the field
+/// can be marked.
+fn field_error(name: &str, message: impl Display) -> anyhow::Error {
+    ControlError::Config {
+        field: Some(name.to_string()),
+        message: message.to_string(),
+    }
+    .into()
+}
+
+/// Parses the form field `name`.
+fn parse_field<T: FromStr>(map: &HashMap<String, String>, name: &str) -> Result<T> {
+    map.get(name)
+        .ok_or_else(|| field_error(name, format!("Missing parameter {}", name)))?
+        .parse()
+        .or(Err(field_error(name, format!("Unable to parse {}", name))))
+}
+
+/// Parses the form field `name` as a quantity with an optional unit, e.g. `2.5 mm`.
+fn parse_quantity(map: &HashMap<String, String>, name: &str, quantity: Quantity) -> Result<f64> {
+    let value = map
+        .get(name)
+        .ok_or_else(|| field_error(name, format!("Missing parameter {}", name)))?;
+    units::parse(value, quantity).map_err(|e| field_error(name, e))
+}
+
+/// Parses the fields of the axis with index `i`, which are named like `axes[0].formula`.
+/// The address of the axis and the settings which are not part of the form are kept.
+fn parse_axis(map: &HashMap<String, String>, i: usize, axis: &AxisConfig) -> Result<AxisConfig> {
+    let name = |field: &str| format!("axes[{}].{}", i, field);
+
+    let axis = AxisConfig {
+        // Unchecked checkboxes are not part of the form data
+        enabled: map.contains_key(&name("enabled")),
+        formula: parse_field(map, &name("formula"))?,
+        target_kind: parse_field(map, &name("target_kind"))?,
+        calibration: parse_calibration(map, i, axis.calibration.as_ref())?,
+        limit_min: parse_quantity(map, &name("limit_min"), Quantity::Length)?,
+        limit_max: parse_quantity(map, &name("limit_max"), Quantity::Length)?,
+        limit_policy: parse_field(map, &name("limit_policy"))?,
+        maxspeed: parse_quantity(map, &name("maxspeed"), Quantity::Velocity)?,
+        accel: parse_quantity(map, &name("accel"), Quantity::Acceleration)?,
+        offset: parse_quantity(map, &name("offset"), Quantity::Length)?,
+        deadband: parse_quantity(map, &name("deadband"), Quantity::Length)?,
+        hysteresis: parse_quantity(map, &name("hysteresis"), Quantity::Length)?,
+        kp: parse_field(map, &name("kp"))?,
+        ki: parse_field(map, &name("ki"))?,
+        kd: parse_field(map, &name("kd"))?,
+        ..axis.clone()
+    };
+    if axis.target_kind == TargetKind::Calibration && axis.calibration.is_none() {
+        return Err(field_error(&name("target_kind"), "No calibration table configured"));
+    }
+
+    Ok(axis)
+}
+
+/// Parses the calibration of the axis with index `i`, the points of a table in the config
+/// are kept. Returns `None` if there is neither a CSV file nor a table in the config.
+fn parse_calibration(
+    map: &HashMap<String, String>,
+    i: usize,
+    calibration: Option<&CalibrationConfig>,
+) -> Result<Option<CalibrationConfig>> {
+    let name = |field: &str| format!("axes[{}].calibration.{}", i, field);
+
+    let path: String = parse_field(map, &name("path"))?;
+    let path = path.trim();
+    let points = calibration.map(|c| c.points.clone()).unwrap_or_default();
+    if path.is_empty() && points.is_empty() {
+        return Ok(None);
+    }
+
+    let calibration = CalibrationConfig {
+        voltage: parse_field(map, &name("voltage"))?,
+        path: match path.is_empty() {
+            true => None,
+            false => Some(path.into()),
+        },
+        points,
+        interpolation: parse_field(map, &name("interpolation"))?,
+        extrapolation: parse_field(map, &name("extrapolation"))?,
+    };
+    // Reads the CSV file, so a wrong path is reported before the control is started
+    CalibrationTable::new(&calibration).map_err(|e| field_error(&name("path"), e))?;
+
+    Ok(Some(calibration))
+}
+
+/// Settings changed by a request, named like the fields of the form.
+#[derive(Debug, Serialize)]
+struct ConfigUpdate {
+    /// Settings the running control loop picked up.
+    immediate: Vec<String>,
+    /// Settings which take effect when the control is started.
+    next_start: Vec<String>,
+}
+
+/// Applies the new config. While the control runs, only the settings which the control loop
+/// can pick up are accepted, see `Config::changes`.
 async fn handle_post_config(
     State(state): State<WebState>,
     Form(map_new): Form<HashMap<String, String>>,
-) -> Result<(), AppError> {
+) -> Result<Json<ConfigUpdate>, AppError> {
     tracing::debug!("POST /config requested");
 
-    if state.zaber_state.read().unwrap().control_state != ControlStatus::Stopped {
-        Err(anyhow!(
-            "The config cannot be changed while running. Stop the control first!"
-        ))?;
-    }
-
+    let config_current = { state.config.read().unwrap().clone() };
     let config_new = Config {
-        cycle_time_ms: Duration::from_millis(
-            map_new
-                .get("cycle_time_ms")
-                .unwrap()
-                .parse()
-                .expect("cycle_time_ms: Unable to parse cycle_time_ms"),
-        ),
-        serial_device: map_new.get("serial_device").unwrap().into(),
-        opcua_config_path: map_new.get("opcua_config_path").unwrap().into(),
-        control_mode: match &(map_new
-            .get("control_mode")
-            .ok_or(anyhow!("control_mode: Missing parameter control_mode"))?[..])
-        {
-            "Tracking" => Ok(ControlMode::Tracking),
-            "Manual" => Ok(ControlMode::Manual),
-            _ => Err(anyhow!("control_mode: Invalid control mode")),
-        }?,
-        limit_max_coax: map_new
-            .get("limit_max_coax")
-            .ok_or(anyhow!("limit_max_coax: Missing parameter limit_max_coax"))?
-            .parse()
-            .or(Err(anyhow!(
-                "limit_max_coax: Unable to parse limit_max_coax"
-            )))?,
-        limit_min_coax: map_new
-            .get("limit_min_coax")
-            .ok_or(anyhow!("limit_min_coax: Missing parameter limit_min_coax"))?
-            .parse()
-            .or(Err(anyhow!(
-                "limit_min_coax: Unable to parse limit_min_coax"
-            )))?,
-        maxspeed_coax: map_new
-            .get("maxspeed_coax")
-            .ok_or(anyhow!("maxspeed_coax: Missing parameter maxspeed_coax"))?
-            .parse()
-            .or(Err(anyhow!("maxspeed_coax: Unable to parse maxspeed_coax")))?,
-        accel_coax: map_new
-            .get("accel_coax")
-            .ok_or(anyhow!("accel_coax: Missing parameter accel_coax"))?
-            .parse()
-            .or(Err(anyhow!("accel_coax: Unable to parse accel_coax")))?,
-        offset_coax: map_new
-            .get("offset_coax")
-            .ok_or(anyhow!("offset_coax: Missing parameter offset_coax"))?
-            .parse()
-            .or(Err(anyhow!("offset_coax: Unable to parse offset_coax")))?,
-        limit_max_cross: map_new
-            .get("limit_max_cross")
-            .ok_or(anyhow!("limit_max_cross: Missing parameter limit_max_cross"))?
-            .parse()
-            .or(Err(anyhow!(
-                "limit_max_cross: Unable to parse limit_max_cross"
-            )))?,
-        limit_min_cross: map_new
-            .get("limit_min_cross")
-            .ok_or(anyhow!("limit_min_cross: Missing parameter limit_min_cross"))?
-            .parse()
-            .or(Err(anyhow!(
-                "limit_min_cross: Unable to parse limit_min_cross"
-            )))?,
-        maxspeed_cross: map_new
-            .get("maxspeed_cross")
-            .ok_or(anyhow!("maxspeed_cross: Missing parameter maxspeed_cross"))?
-            .parse()
-            .or(Err(anyhow!(
-                "maxspeed_cross: Unable to parse maxspeed_cross"
-            )))?,
-        accel_cross: map_new
-            .get("accel_cross")
-            .ok_or(anyhow!("accel_cross: Missing parameter accel_cross"))?
-            .parse()
-            .or(Err(anyhow!("accel_cross: Unable to parse accel_cross")))?,
-        mock_zaber: map_new
-            .get("mock_zaber")
-            .ok_or(anyhow!("mock_zaber: Missing parameter mock_zaber"))?
-            .parse()
-            .or(Err(anyhow!("mock_zaber: Unable to parse mock_zaber")))?,
-        mock_adc: map_new
-            .get("mock_adc")
-            .ok_or(anyhow!("mock_adc: Missing parameter mock_adc"))?
-            .parse()
-            .or(Err(anyhow!("mock_adc: Unable to parse mock_adc")))?,
-        formula_coax: map_new
-            .get("formula_coax")
-            .ok_or(anyhow!("formula_coax: Missing parameter formula_coax"))?
-            .parse()
-            .or(Err(anyhow!("formula_coax: Unable to parse formula_coax")))?,
-        formula_cross: map_new
-            .get("formula_cross")
-            .ok_or(anyhow!("formula_cross: Missing parameter formula_cross"))?
-            .parse()
-            .or(Err(anyhow!("formula_cross: Unable to parse formula_cross")))?,
-        web_port: map_new
-            .get("web_port")
-            .ok_or(anyhow!("web_port: Missing parameter web_port"))?
-            .parse()
-            .or(Err(anyhow!("web_port: Unable to parse web_port")))?,
+        cycle_time_ms: Duration::from_millis(parse_field(&map_new, "cycle_time_ms")?),
+        serial_device: parse_field(&map_new, "serial_device")?,
+        opcua_config_path: parse_field(&map_new, "opcua_config_path")?,
+        control_mode: parse_field(&map_new, "control_mode")?,
+        backend: parse_field(&map_new, "backend")?,
+        voltage_source: parse_field(&map_new, "voltage_source")?,
+        web_port: parse_field(&map_new, "web_port")?,
+        axes: config_current
+            .axes
+            .iter()
+            .enumerate()
+            .map(|(i, axis)| parse_axis(&map_new, i, axis))
+            .collect::<Result<_>>()?,
+        sequence_repetitions: parse_field(&map_new, "sequence_repetitions")?,
+        watchdog_tolerance: parse_quantity(&map_new, "watchdog_tolerance", Quantity::Length)?,
+        watchdog_timeout_ms: Duration::from_millis(parse_field(&map_new, "watchdog_timeout_ms")?),
+        transition_time_ms: Duration::from_millis(parse_field(&map_new, "transition_time_ms")?),
+        transition_speed: parse_quantity(&map_new, "transition_speed", Quantity::Velocity)?,
+        homing_policy: parse_field(&map_new, "homing_policy")?,
+        // Settings which are not part of the form are kept
+        ..config_current.clone()
     };
 
-    // If the user changes the config twice without starting
-    // in between, the stop channel would be full and this call
-    // errors, which doesn't matter.
-    let _ = state.tx_stop_control.try_send(());
+    config_new.validate()?;
+
+    let changes = config_current.changes(&config_new);
+    let running = state.zaber_state.read().unwrap().control_state == ControlStatus::Running;
+    if let (true, Some(field)) = (running, changes.cold.first()) {
+        Err(ControlError::Config {
+            field: Some(field.clone()),
+            message: format!("Stop the control first to change {}", changes.cold.join(", ")),
+        })?;
+    }
 
     let save_result = write_config(&config_new);
 
@@ -228,12 +290,36 @@ async fn handle_post_config(
     *config = config_new;
     drop(config);
 
+    let update = match running {
+        true => {
+            let _ = state.tx_reload_control.try_send(());
+            ConfigUpdate {
+                immediate: changes.hot,
+                next_start: Vec::new(),
+            }
+        }
+        false => {
+            // If the user changes the config twice without starting
+            // in between, the stop channel would be full and this call
+            // errors, which doesn't matter.
+            let _ = state.tx_stop_control.try_send(());
+            ConfigUpdate {
+                immediate: Vec::new(),
+                next_start: [changes.hot, changes.cold].concat(),
+            }
+        }
+    };
+
     save_result?;
-    Ok(())
+    Ok(Json(update))
 }
 
 async fn handle_post_start(State(state): State<WebState>) -> Result<(), AppError> {
     tracing::debug!("POST start requested");
+    if let Some(trigger) = state.estop.triggered() {
+        Err(anyhow!("It is latched and has to be reset first")
+            .context(ControlError::EmergencyStop { source: trigger.source }))?;
+    }
     state.tx_start_control.try_send(())?;
     tracing::debug!("POST start exit");
     Ok(())
@@ -246,6 +332,50 @@ async fn handle_post_stop(State(state): State<WebState>) -> Result<(), AppError>
     Ok(())
 }
 
+async fn handle_post_estop(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<WebState>,
+) -> Result<(), AppError> {
+    tracing::debug!("POST estop requested by {}", addr);
+    state.estop.trigger(&format!("web {}", addr.ip()));
+    Ok(())
+}
+
+async fn handle_post_estop_reset(State(state): State<WebState>) -> Result<(), AppError> {
+    tracing::debug!("POST estop reset requested");
+    state.estop.reset()?;
+    Ok(())
+}
+
+async fn handle_get_history(State(state): State<WebState>) -> Json<Vec<HistoryEntry>> {
+    tracing::debug!("GET history requested");
+    Json(state.history.entries())
+}
+
+async fn handle_post_history_acknowledge_all(
+    State(state): State<WebState>,
+) -> Result<Json<Vec<HistoryEntry>>, AppError> {
+    tracing::debug!("POST history acknowledge requested");
+    state.history.acknowledge(None)?;
+    Ok(Json(state.history.entries()))
+}
+
+async fn handle_post_history_acknowledge(
+    extract::Path(id): extract::Path<u64>,
+    State(state): State<WebState>,
+) -> Result<Json<Vec<HistoryEntry>>, AppError> {
+    tracing::debug!("POST history acknowledge requested - entry {}", id);
+    state.history.acknowledge(Some(id))?;
+    Ok(Json(state.history.entries()))
+}
+
+/// Removes the acknowledged entries of the history.
+async fn handle_post_history_clear(State(state): State<WebState>) -> Json<Vec<HistoryEntry>> {
+    tracing::debug!("POST history clear requested");
+    state.history.clear();
+    Json(state.history.entries())
+}
+
 async fn handle_get_config(State(state): State<WebState>) -> Json<utils::Config> {
     tracing::debug!("GET config requested");
     let config = { state.config.read().unwrap().clone() };
@@ -253,25 +383,149 @@ async fn handle_get_config(State(state): State<WebState>) -> Json<utils::Config>
     Json(config)
 }
 
+async fn handle_get_sequence(State(state): State<WebState>) -> Json<Vec<Waypoint>> {
+    tracing::debug!("GET sequence requested");
+    let sequence = { state.sequence.read().unwrap().clone() };
+
+    Json(sequence)
+}
+
+/// Replaces the waypoints of `ControlMode::Sequence`,
+/// they are used the next time the sequence is started.
+async fn handle_post_sequence(
+    State(state): State<WebState>,
+    Json(waypoints): Json<Vec<Waypoint>>,
+) -> Result<(), AppError> {
+    tracing::debug!("POST sequence requested - {} waypoints", waypoints.len());
+    if waypoints.is_empty() {
+        Err(ControlError::Config {
+            field: None,
+            message: "The sequence does not contain any waypoints".into(),
+        })?;
+    }
+
+    *state.sequence.write().unwrap() = waypoints;
+    Ok(())
+}
+
+async fn handle_get_calibration(State(state): State<WebState>) -> Json<CalibrationSession> {
+    tracing::debug!("GET calibration requested");
+    Json(state.calibration.read().unwrap().clone())
+}
+
+/// Captures the current voltage and position of an axis for the calibration.
+async fn handle_post_calibration_capture(
+    State(state): State<WebState>,
+    Form(map): Form<HashMap<String, String>>,
+) -> Result<Json<CalibrationSession>, AppError> {
+    tracing::debug!("POST calibration capture requested");
+    let axis = parse_field(&map, "axis")?;
+    let voltage = parse_field(&map, "voltage")?;
+    let shared = { state.zaber_state.read().unwrap().clone() };
+
+    let mut session = state.calibration.write().unwrap();
+    session.capture(axis, voltage, &shared)?;
+    Ok(Json(session.clone()))
+}
+
+async fn handle_post_calibration_remove(
+    extract::Path(i): extract::Path<usize>,
+    State(state): State<WebState>,
+) -> Result<Json<CalibrationSession>, AppError> {
+    tracing::debug!("POST calibration remove requested - point {}", i);
+    let mut session = state.calibration.write().unwrap();
+    session.remove(i)?;
+    Ok(Json(session.clone()))
+}
+
+async fn handle_post_calibration_clear(State(state): State<WebState>) {
+    tracing::debug!("POST calibration clear requested");
+    state.calibration.write().unwrap().points.clear();
+}
+
+/// Fits the captured points without changing the config, used as preview.
+async fn handle_post_calibration_fit(
+    State(state): State<WebState>,
+    Form(map): Form<HashMap<String, String>>,
+) -> Result<Json<Fit>, AppError> {
+    tracing::debug!("POST calibration fit requested");
+    let model: FitModel = parse_field(&map, "model")?;
+    let fit = state.calibration.read().unwrap().fit(&model)?;
+    Ok(Json(fit))
+}
+
+/// Writes the fit of the captured points into the config of the calibrated axis.
+async fn handle_post_calibration_apply(
+    State(state): State<WebState>,
+    Form(map): Form<HashMap<String, String>>,
+) -> Result<Json<Fit>, AppError> {
+    tracing::debug!("POST calibration apply requested");
+    let model: FitModel = parse_field(&map, "model")?;
+    let config_current = { state.config.read().unwrap().clone() };
+    let mut config_new = config_current.clone();
+    let session = { state.calibration.read().unwrap().clone() };
+    let axis = config_new
+        .axes
+        .get_mut(session.axis)
+        .ok_or(anyhow!("Unknown axis with index {}", session.axis))?;
+    let fit = session.apply(&model, axis)?;
+    config_new.validate()?;
+
+    // Like in `handle_post_config`, a running control loop picks up the new target
+    let changes = config_current.changes(&config_new);
+    let running = state.zaber_state.read().unwrap().control_state == ControlStatus::Running;
+    if let (true, Some(field)) = (running, changes.cold.first()) {
+        Err(ControlError::Config {
+            field: Some(field.clone()),
+            message: format!("Stop the control first to change {}", changes.cold.join(", ")),
+        })?;
+    }
+
+    let save_result = write_config(&config_new);
+    *state.config.write().unwrap() = config_new;
+    if running {
+        let _ = state.tx_reload_control.try_send(());
+    }
+
+    save_result?;
+    Ok(Json(fit))
+}
+
 async fn handle_manual_init(
     ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<WebState>,
 ) -> impl IntoResponse {
     tracing::debug!("Manual init");
-    ws.on_upgrade(move |socket| handle_manual(socket, state))
+    ws.on_upgrade(move |socket| handle_manual(socket, addr, state))
 }
 
-fn parse_message(msg: Message) -> Result<(u32, u32)> {
+/// Messages sent by the client over the WebSocket.
+#[derive(Debug, PartialEq)]
+enum WsCommand {
+    /// Manual targets of the axes in microsteps, in the order of the configured axes.
+    Targets(Vec<u32>),
+    Estop,
+}
+
+fn parse_message(msg: Message) -> Result<WsCommand> {
     let msg = msg.to_text()?;
-    let mut msg = msg.split_whitespace();
+    if msg.trim() == "estop" {
+        return Ok(WsCommand::Estop);
+    }
 
-    let val_coax = str::parse::<u32>(msg.next().ok_or(anyhow!("Missing value"))?)?;
-    let val_cross = str::parse::<u32>(msg.next().ok_or(anyhow!("Missing value"))?)?;
+    let targets = msg
+        .split_whitespace()
+        .map(str::parse::<u32>)
+        .collect::<Result<Vec<_>, _>>()?;
+    if targets.is_empty() {
+        return Err(anyhow!("Missing value"));
+    }
 
-    return Ok((val_coax, val_cross));
+    return Ok(WsCommand::Targets(targets));
 }
 
-async fn handle_manual(socket: WebSocket, state: WebState) {
+async fn handle_manual(socket: WebSocket, addr: SocketAddr, state: WebState) {
     let (mut sender, mut receiver) = socket.split();
 
     let mut recv_task = tokio::spawn(async move {
@@ -282,8 +536,12 @@ async fn handle_manual(socket: WebSocket, state: WebState) {
                 return; // client disconnected
             };
 
-            let (val_coax, val_cross) = match parse_message(msg) {
-                Ok(val) => val,
+            let targets = match parse_message(msg) {
+                Ok(WsCommand::Targets(targets)) => targets,
+                Ok(WsCommand::Estop) => {
+                    state.estop.trigger(&format!("websocket {}", addr.ip()));
+                    continue;
+                }
                 Err(e) => {
                     tracing::error!("Error parsing message: {e}");
                     continue;
@@ -293,7 +551,7 @@ async fn handle_manual(socket: WebSocket, state: WebState) {
             {
                 match state.target_manual.write() {
                     Err(e) => tracing::error!("Failed to aquire manual voltage lock: {e}"),
-                    Ok(mut v) => *v = [val_coax, val_cross],
+                    Ok(mut v) => *v = targets,
                 };
             }
         }
@@ -342,16 +600,46 @@ pub fn run_web_server(state: WebState) {
         .with_state(state.clone())
         .route("/refresh", get(handle_refresh))
         .with_state(state.clone())
+        .route("/timing", get(handle_get_timing))
+        .with_state(state.clone())
         .route("/config", post(handle_post_config))
         .with_state(state.clone())
         .route("/start", post(handle_post_start))
         .with_state(state.clone())
         .route("/stop", post(handle_post_stop))
         .with_state(state.clone())
+        .route("/estop", post(handle_post_estop))
+        .with_state(state.clone())
+        .route("/estop/reset", post(handle_post_estop_reset))
+        .with_state(state.clone())
+        .route("/history", get(handle_get_history))
+        .with_state(state.clone())
+        .route("/history/acknowledge", post(handle_post_history_acknowledge_all))
+        .with_state(state.clone())
+        .route("/history/acknowledge/:id", post(handle_post_history_acknowledge))
+        .with_state(state.clone())
+        .route("/history/clear", post(handle_post_history_clear))
+        .with_state(state.clone())
         .route("/config", get(handle_get_config))
         .with_state(state.clone())
         .route("/mode/:m", post(handle_post_mode))
         .with_state(state.clone())
+        .route("/sequence", get(handle_get_sequence))
+        .with_state(state.clone())
+        .route("/sequence", post(handle_post_sequence))
+        .with_state(state.clone())
+        .route("/calibration", get(handle_get_calibration))
+        .with_state(state.clone())
+        .route("/calibration/capture", post(handle_post_calibration_capture))
+        .with_state(state.clone())
+        .route("/calibration/remove/:i", post(handle_post_calibration_remove))
+        .with_state(state.clone())
+        .route("/calibration/clear", post(handle_post_calibration_clear))
+        .with_state(state.clone())
+        .route("/calibration/fit", post(handle_post_calibration_fit))
+        .with_state(state.clone())
+        .route("/calibration/apply", post(handle_post_calibration_apply))
+        .with_state(state.clone())
         .route("/ws", get(handle_manual_init))
         .with_state(state);
 
@@ -360,6 +648,11 @@ pub fn run_web_server(state: WebState) {
         let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.web_port))
             .await
             .unwrap();
-        axum::serve(listener, app).await.unwrap();
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
     });
 }
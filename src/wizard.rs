@@ -0,0 +1,339 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::{
+    calibration::{CalibrationConfig, CalibrationTable},
+    utils::{AxisConfig, ControlStatus, SharedState, TargetKind},
+    zaber::steps_to_mm,
+};
+
+/// Highest degree of the fitted polynomials, higher ones mostly fit the noise.
+pub const MAX_DEGREE: usize = 5;
+
+/// How the captured points are turned into the target of the axis.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FitModel {
+    /// Least squares polynomial written as formula, degree 1 is a linear regression.
+    Polynomial(usize),
+    /// The points are used as calibration table.
+    Table,
+}
+
+impl FromStr for FitModel {
+    type Err = anyhow::Error;
+
+    /// Parses `Table` or `Polynomial<degree>`, e.g. `Polynomial2`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Table" => Ok(Self::Table),
+            _ => match s.strip_prefix("Polynomial").map(|d| d.parse()) {
+                Some(Ok(degree)) if (1..=MAX_DEGREE).contains(&degree) => {
+                    Ok(Self::Polynomial(degree))
+                }
+                _ => Err(anyhow!("Unknown model {}", s)),
+            },
+        }
+    }
+}
+
+/// Result of fitting the captured points, shown as preview before it is applied.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Fit {
+    /// Coefficients of the polynomial starting with the constant term, empty for a table.
+    pub coefficients: Vec<f64>,
+    /// The polynomial as formula, not set for a table.
+    pub formula: Option<String>,
+    /// Fitted minus captured position of each point in mm.
+    pub residuals: Vec<f64>,
+    /// Root mean square of the residuals in mm.
+    pub rms: f64,
+    /// Largest absolute residual in mm.
+    pub max_residual: f64,
+}
+
+/// Voltage and position pairs captured to calibrate the target of an axis.
+///
+/// The stage is jogged to a reference in `ControlMode::Manual` and the current
+/// voltage and position are captured, repeated for every reference.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CalibrationSession {
+    /// Index of the calibrated axis.
+    pub axis: usize,
+    /// The voltage the points are captured from, 1 for `v1` and 2 for `v2`.
+    pub voltage: u8,
+    /// Pairs of the filtered voltage and the position in mm.
+    pub points: Vec<[f64; 2]>,
+}
+
+impl CalibrationSession {
+    /// Captures the current voltage and position of the axis from the published state.
+    /// Capturing for another axis or voltage discards the previous points.
+    pub fn capture(&mut self, axis: usize, voltage: u8, state: &SharedState) -> Result<[f64; 2]> {
+        if state.control_state != ControlStatus::Running {
+            return Err(anyhow!("The control has to run to capture the position"));
+        }
+        if axis >= state.position.len() {
+            return Err(anyhow!("Unknown axis with index {}", axis));
+        }
+        if !state.enabled[axis] {
+            return Err(anyhow!("The axis {} is disabled", state.axes[axis]));
+        }
+        if !(1..=2).contains(&voltage) {
+            return Err(anyhow!("Unknown voltage v{}", voltage));
+        }
+
+        if (self.axis, self.voltage) != (axis, voltage) {
+            self.points.clear();
+            self.axis = axis;
+            self.voltage = voltage;
+        }
+        let point = [
+            state.voltage[voltage as usize - 1],
+            steps_to_mm(state.position[axis]),
+        ];
+        self.points.push(point);
+
+        Ok(point)
+    }
+
+    /// Removes the captured point with index `i`.
+    pub fn remove(&mut self, i: usize) -> Result<()> {
+        if i >= self.points.len() {
+            return Err(anyhow!("No captured point with index {}", i));
+        }
+        self.points.remove(i);
+        Ok(())
+    }
+
+    pub fn fit(&self, model: &FitModel) -> Result<Fit> {
+        match model {
+            FitModel::Polynomial(degree) => {
+                let coefficients = fit_polynomial(&self.points, *degree)?;
+                let residuals = self
+                    .points
+                    .iter()
+                    .map(|[v, pos]| polynomial(&coefficients, *v) - pos)
+                    .collect();
+                Ok(Fit::new(
+                    Some(formula(&coefficients, self.voltage)),
+                    coefficients,
+                    residuals,
+                ))
+            }
+            FitModel::Table => {
+                CalibrationTable::new(&self.table(None))?;
+                Ok(Fit::new(None, Vec::new(), vec![0.; self.points.len()]))
+            }
+        }
+    }
+
+    /// Writes the fit into the config of the axis and selects it as target.
+    /// A table keeps the interpolation and the extrapolation of the previous one.
+    pub fn apply(&self, model: &FitModel, axis: &mut AxisConfig) -> Result<Fit> {
+        let fit = self.fit(model)?;
+        match &fit.formula {
+            Some(formula) => {
+                axis.formula = formula.clone();
+                axis.target_kind = TargetKind::Formula;
+            }
+            None => {
+                axis.calibration = Some(self.table(axis.calibration.as_ref()));
+                axis.target_kind = TargetKind::Calibration;
+            }
+        }
+
+        Ok(fit)
+    }
+
+    fn table(&self, previous: Option<&CalibrationConfig>) -> CalibrationConfig {
+        CalibrationConfig {
+            voltage: self.voltage,
+            path: None,
+            points: self.points.clone(),
+            interpolation: previous.map(|c| c.interpolation.clone()).unwrap_or_default(),
+            extrapolation: previous.map(|c| c.extrapolation.clone()).unwrap_or_default(),
+        }
+    }
+}
+
+impl Fit {
+    fn new(formula: Option<String>, coefficients: Vec<f64>, residuals: Vec<f64>) -> Self {
+        let n = residuals.len().max(1) as f64;
+        Self {
+            rms: (residuals.iter().map(|r| r * r).sum::<f64>() / n).sqrt(),
+            max_residual: residuals.iter().fold(0., |max, r| r.abs().max(max)),
+            coefficients,
+            formula,
+            residuals,
+        }
+    }
+}
+
+fn polynomial(coefficients: &[f64], v: f64) -> f64 {
+    coefficients.iter().rev().fold(0., |y, c| y * v + c)
+}
+
+/// Writes the polynomial as formula of the voltage `v1` or `v2`.
+fn formula(coefficients: &[f64], voltage: u8) -> String {
+    let mut formula = coefficients[0].to_string();
+    for (k, c) in coefficients.iter().enumerate().skip(1) {
+        let sign = match c.is_sign_negative() {
+            true => '-',
+            false => '+',
+        };
+        formula += &match k {
+            1 => format!(" {} {} * v{}", sign, c.abs(), voltage),
+            _ => format!(" {} {} * v{}^{}", sign, c.abs(), voltage, k),
+        };
+    }
+    formula
+}
+
+/// Least squares fit of a polynomial to the points, returns the coefficients
+/// starting with the constant term.
+pub fn fit_polynomial(points: &[[f64; 2]], degree: usize) -> Result<Vec<f64>> {
+    let n = degree + 1;
+    if points.len() < n {
+        return Err(anyhow!(
+            "A polynomial of degree {} needs at least {} points, got {}",
+            degree,
+            n,
+            points.len()
+        ));
+    }
+
+    // Normal equations with the matrix in the first n columns and the right side in the last
+    let mut m = vec![vec![0.; n + 1]; n];
+    for [v, pos] in points {
+        let powers: Vec<f64> = (0..n).map(|k| v.powi(k as i32)).collect();
+        for (row, p) in m.iter_mut().zip(&powers) {
+            for (x, q) in row.iter_mut().zip(&powers) {
+                *x += p * q;
+            }
+            row[n] += p * pos;
+        }
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&a, &b| m[a][col].abs().total_cmp(&m[b][col].abs()))
+            .unwrap();
+        if m[pivot][col].abs() < 1e-12 {
+            return Err(anyhow!(
+                "The points do not determine a polynomial of degree {}",
+                degree
+            ));
+        }
+        m.swap(col, pivot);
+        let (upper, lower) = m.split_at_mut(col + 1);
+        let pivot_row = &upper[col];
+        for row in lower {
+            let factor = row[col] / pivot_row[col];
+            for (x, p) in row.iter_mut().zip(pivot_row).skip(col) {
+                *x -= factor * p;
+            }
+        }
+    }
+
+    let mut coefficients = vec![0.; n];
+    for i in (0..n).rev() {
+        let sum: f64 = (i + 1..n).map(|j| m[i][j] * coefficients[j]).sum();
+        coefficients[i] = (m[i][n] - sum) / m[i][i];
+    }
+
+    Ok(coefficients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Config;
+    use evalexpr::{DefaultNumericTypes, Node, Value};
+
+    fn session(points: &[[f64; 2]]) -> CalibrationSession {
+        CalibrationSession {
+            axis: 0,
+            voltage: 1,
+            points: points.to_vec(),
+        }
+    }
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        assert_eq!(a.len(), b.len());
+        assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-6), "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_fit_linear() {
+        let slope = (33.30 - 47.11) / (1.282 - 0.403);
+        let line = |v: f64| 47.11 + slope * (v - 0.403);
+        let session = session(&[0.403, 1.282, 0.8].map(|v| [v, line(v)]));
+        let fit = session.fit(&FitModel::Polynomial(1)).unwrap();
+
+        assert_close(&fit.coefficients, &[line(0.), slope]);
+        assert!(fit.max_residual < 1e-9 && fit.rms <= fit.max_residual);
+
+        // The formula evaluates to the fitted polynomial
+        let node: Node<DefaultNumericTypes> =
+            evalexpr::build_operator_tree(&fit.formula.unwrap()).unwrap();
+        let context = evalexpr::context_map! { "v1" => Value::Float(1.) }.unwrap();
+        let target = node.eval_number_with_context(&context).unwrap();
+        assert_close(&[target], &[line(1.)]);
+    }
+
+    #[test]
+    fn test_fit_polynomial() {
+        let points: Vec<[f64; 2]> = (0..6)
+            .map(|i| i as f64 * 0.5)
+            .map(|v| [v, 3. - 2. * v + 0.5 * v * v])
+            .collect();
+
+        let coefficients = fit_polynomial(&points, 2).unwrap();
+        assert_close(&coefficients, &[3., -2., 0.5]);
+
+        assert!(fit_polynomial(&points[..2], 2).is_err());
+        assert!(fit_polynomial(&[[1., 2.], [1., 3.], [1., 4.]], 1).is_err());
+        assert_eq!(formula(&[3., -2., 0.5], 2), "3 - 2 * v2 + 0.5 * v2^2");
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut axis = AxisConfig::new("cross", 2);
+        let session = session(&[[0., 10.], [1., 12.], [2., 18.]]);
+
+        let fit = session.apply(&FitModel::Polynomial(1), &mut axis).unwrap();
+        assert_eq!(axis.target_kind, TargetKind::Formula);
+        assert_eq!(Some(axis.formula.clone()), fit.formula);
+        assert_close(&fit.residuals, &[-2. / 3., 4. / 3., -2. / 3.]);
+
+        session.apply(&FitModel::Table, &mut axis).unwrap();
+        assert_eq!(axis.target_kind, TargetKind::Calibration);
+        assert_eq!(axis.calibration.unwrap().points, session.points);
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut state = SharedState::new(&Config::default().axes);
+        let mut session = CalibrationSession::default();
+        assert!(session.capture(0, 1, &state).is_err());
+
+        state.control_state = ControlStatus::Running;
+        state.voltage = [1.5, 2.5];
+        state.position = vec![2000, 4000];
+        assert_eq!(session.capture(1, 2, &state).unwrap(), [2.5, steps_to_mm(4000)]);
+        session.capture(1, 2, &state).unwrap();
+        assert_eq!(session.points.len(), 2);
+
+        // Another axis starts over
+        session.capture(0, 1, &state).unwrap();
+        assert_eq!(session.points, vec![[1.5, steps_to_mm(2000)]]);
+
+        assert!(session.capture(2, 1, &state).is_err());
+        assert!(session.remove(1).is_err());
+        session.remove(0).unwrap();
+        assert!(session.points.is_empty());
+    }
+}
@@ -1,179 +1,514 @@
-use crate::{simulation::Simulator, utils::Config};
+use crate::{
+    control::Backend,
+    error::ControlError,
+    homing::{DeviceCheck, HomingPolicy, PositionStore},
+    simulation::Simulator,
+    utils::{AxisConfig, Config, Estop},
+};
 use ads1x1x::ic::{Ads1115, Resolution16Bit};
 use ads1x1x::mode::Continuous;
 use ads1x1x::Ads1x1x;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use ftdi_embedded_hal::{libftd2xx::Ft232h, I2c};
+use std::{fmt::Display, time::Duration};
 use zproto::ascii::port::OpenGeneralOptions;
 use zproto::ascii::{
-    response::{check, Status},
+    response::{check, Flag, Reply, Status},
     Port,
 };
 
+/// Microstep size and velocity factor of the LSM stages with the default resolution of
+/// 64 microsteps. They are used for all axes, the resolution of the devices is not read.
 pub const MICROSTEP_SIZE: f64 = 0.49609375; //µm
-                                            // pub const VELOCITY_FACTOR: f64 = 1.6384;
+pub const VELOCITY_FACTOR: f64 = 1.6384;
 pub const MAX_POS: u32 = 201574; // microsteps
 pub const MAX_SPEED: u32 = 153600; // microsteps/sec
 
+/// Interval the devices are polled with while waiting for them to become idle.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 pub type ZaberConn<T> = Port<'static, T>;
 pub type Adc = Ads1x1x<I2c<Ft232h>, Ads1115, Resolution16Bit, Continuous>;
 
-pub fn init_zaber_mock(config: &Config) -> Result<ZaberConn<Simulator>> {
-    let sim = Simulator::new();
+/// Connection to the Zaber devices of the configured axes.
+pub struct ZaberBackend<T: zproto::backend::Backend> {
+    pub port: ZaberConn<T>,
+    /// Disabled axes are neither homed, configured nor moved.
+    pub axes: Vec<AxisConfig>,
+}
+
+/// A device answered a command with the flag `RJ`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rejected {
+    /// Reason given in the reply, e.g. `BADDATA`.
+    pub reason: String,
+}
+
+impl Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command rejected: {}", self.reason)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// A reply did not contain the expected values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidReply(pub String);
+
+impl Display for InvalidReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid reply: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidReply {}
+
+/// Checks the flag of `reply`, rejected commands fail with `Rejected`.
+fn check_flag(reply: Reply) -> Result<Reply> {
+    match reply.flag() {
+        Flag::Rejected => Err(Rejected {
+            reason: reply.data().to_string(),
+        }
+        .into()),
+        _ => Ok(reply),
+    }
+}
+
+fn get_axis(axes: &[AxisConfig], axis: usize) -> Result<&AxisConfig> {
+    axes.get(axis)
+        .ok_or(anyhow!("Unknown axis with index {}", axis))
+}
+
+/// Adds the device and name of `axis` to an error of the communication, `None` if the
+/// error is not caused by a single axis.
+fn zaber_error(error: anyhow::Error, axis: Option<&AxisConfig>) -> anyhow::Error {
+    let device = axis.map(|axis| axis.device);
+    let name = axis.map(|axis| axis.name.as_str());
+    ControlError::zaber(&error, device, name).wrap(error)
+}
+
+/// Numbers of the devices of the enabled axes.
+fn enabled_devices(axes: &[AxisConfig]) -> Vec<u8> {
+    let mut devices: Vec<u8> = axes
+        .iter()
+        .filter(|axis| axis.enabled)
+        .map(|axis| axis.device)
+        .collect();
+    devices.sort();
+    devices.dedup();
+    devices
+}
+
+/// Device and axis number settings of `axis` are sent to, the whole device for lockstep groups.
+fn address(axis: &AxisConfig) -> (u8, u8) {
+    match axis.lockstep {
+        Some(_) => (axis.device, 0),
+        None => (axis.device, axis.axis),
+    }
+}
+
+/// Address and command moving `axis`, the axes of a lockstep group are moved through the group.
+fn motion_command(axis: &AxisConfig, command: &str) -> (u8, u8, String) {
+    match axis.lockstep {
+        Some(group) => (axis.device, 0, format!("lockstep {} {}", group, command)),
+        None => (axis.device, axis.axis, command.to_string()),
+    }
+}
+
+pub fn init_zaber_mock(config: &Config, estop: &Estop) -> Result<ZaberBackend<Simulator>> {
+    let devices = config.axes.iter().map(|axis| axis.device).max().unwrap_or(0);
+    let sim = Simulator::new(devices as usize);
     let mut opt = OpenGeneralOptions::new();
     opt.checksums(false);
     opt.message_ids(false);
     let mut sim = opt.open(sim);
-    init_axes(&mut sim, &config)?;
-    return Ok(sim);
+    init_axes(&mut sim, config, &load_positions(config), estop)
+        .map_err(|e| zaber_error(e, None))?;
+    return Ok(ZaberBackend {
+        port: sim,
+        axes: config.axes.clone(),
+    });
 }
 
 pub fn init_zaber(
     config: &Config,
-) -> Result<zproto::ascii::Port<'static, zproto::backend::Serial>, anyhow::Error> {
+    estop: &Estop,
+) -> Result<ZaberBackend<zproto::backend::Serial>> {
     return match Port::open_serial(&config.serial_device) {
         Ok(mut zaber_conn) => {
-            init_axes(&mut zaber_conn, &config)?;
-            return Ok(zaber_conn);
+            init_axes(&mut zaber_conn, config, &load_positions(config), estop)
+                .map_err(|e| zaber_error(e, None))?;
+            return Ok(ZaberBackend {
+                port: zaber_conn,
+                axes: config.axes.clone(),
+            });
         }
-        Err(e) => Err(anyhow!(
-            "Failed to open Zaber serial port '{}': {}",
-            config.serial_device,
-            e
+        Err(e) => Err(zaber_error(
+            anyhow!("Failed to open Zaber serial port '{}': {}", config.serial_device, e),
+            None,
         )),
     };
 }
 
-fn init_axes<T>(zaber_conn: &mut ZaberConn<T>, config: &Config) -> Result<()>
+/// Sends `estop` to the enabled axes without setting up the devices, used while the
+/// control loop is not running and the serial port is closed.
+pub fn estop_serial(config: &Config) -> Result<()> {
+    let mut zaber_conn = Port::open_serial(&config.serial_device).map_err(|e| {
+        zaber_error(
+            anyhow!("Failed to open Zaber serial port '{}': {}", config.serial_device, e),
+            None,
+        )
+    })?;
+    estop_zaber(&mut zaber_conn, &config.axes).map_err(|e| zaber_error(e, None))
+}
+
+/// Reads the positions stored at the last stop, an unreadable store is treated as empty.
+fn load_positions(config: &Config) -> PositionStore {
+    PositionStore::load(&config.position_store_path).unwrap_or_else(|e| {
+        tracing::warn!("{:#}", e);
+        PositionStore::default()
+    })
+}
+
+/// Checks whether `device` is homed and the axes on it are still at the stored positions
+/// with their lockstep groups set up.
+fn check_device<T>(
+    zaber_conn: &mut ZaberConn<T>,
+    axes: &[AxisConfig],
+    device: u8,
+    store: &PositionStore,
+) -> Result<DeviceCheck>
 where
     T: zproto::backend::Backend,
 {
-    zaber_conn.command_reply_n("system restore", 2, check::unchecked())?;
-    //.unwrap_or(Err(anyhow!("Failed restore axes"))?);
+    // The flag `WR` reports a missing reference position
+    let reply = zaber_conn
+        .command_reply((device, "warnings"))?
+        .check(check::unchecked())?;
+    if reply.data().split_whitespace().any(|flag| flag == "WR") {
+        return Ok(DeviceCheck::Unhomed);
+    }
 
-    let _ = zaber_conn.command_reply_n("home", 2, check::flag_ok());
+    for axis in axes.iter().filter(|axis| axis.enabled && axis.device == device) {
+        let (device, axis_number) = address(axis);
+        let reply = zaber_conn
+            .command_reply((device, axis_number, "get pos"))?
+            .check(check::unchecked())?;
+        let position = reply
+            .data()
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| InvalidReply(format!("No position of axis {} returned", axis.name)))?
+            .parse()?;
+        if let Err(reason) = store.verify(&axis.name, position) {
+            return Ok(DeviceCheck::Unverified(reason));
+        }
 
-    zaber_conn.poll_until_idle(1, check::flag_ok())?;
-    //.unwrap_or(Err(anyhow!("Failed to wait for coaxial axis to be idle"))?);
-    zaber_conn.poll_until_idle(2, check::flag_ok())?;
-    //.unwrap_or(Err(anyhow!("Failed to wait for cross axis to be idle"))?);
+        if let Some(group) = axis.lockstep {
+            let reply = zaber_conn
+                .command_reply((device, format!("lockstep {} info", group)))?
+                .check(check::unchecked())?;
+            if reply.data() == "disabled" {
+                return Ok(DeviceCheck::Unverified(format!(
+                    "lockstep group {} is not set up",
+                    group
+                )));
+            }
+        }
+    }
 
-    zaber_conn.command_reply_n("set comm.alert 0", 2, check::flag_ok())?;
+    Ok(DeviceCheck::Verified)
+}
 
-    if config.offset_coax > 0 {
-        zaber_conn
-            .command_reply((1, format!("1 move rel {}", config.offset_coax)))?
-            .flag_ok()?;
-        //.unwrap_or(Err(anyhow!("Failed to set up coax offset"))?);
-    } else if config.offset_coax < 0 {
-        zaber_conn
-            .command_reply((1, format!("2 move rel {}", config.offset_coax.abs())))?
-            .flag_ok()?;
-        //.unwrap_or(Err(anyhow!("Failed to set up coax offset"))?);
-    }
-    zaber_conn.poll_until_idle(1, check::flag_ok())?;
-    //.unwrap_or(Err(anyhow!("Failed to wait for offset axis to be idle"))?);
-
-    zaber_conn
-        .command_reply((1, format!("set maxspeed {}", config.maxspeed_coax)))?
-        .flag_ok()?;
-    //.unwrap_or(Err(anyhow!("Failed to set max speed for coaxial axis"))?);
-    zaber_conn
-        .command_reply((1, format!("set limit.max {}", config.limit_max_coax)))?
-        .flag_ok()?;
-    //.unwrap_or(Err(anyhow!("Failed to set max limit for coaxial axis"))?);
-    zaber_conn
-        .command_reply((1, format!("set limit.min {}", config.limit_min_coax)))?
-        .flag_ok()?;
-    //.unwrap_or(Err(anyhow!("Failed to set min limit for coaxial axis"))?);
-    zaber_conn
-        .command_reply((1, format!("set accel {}", config.accel_coax)))?
-        .flag_ok()?;
-    //.unwrap_or(Err(anyhow!("Failed to set acceleration for coaxial axis"))?);
-
-    zaber_conn
-        .command_reply((2, format!("set maxspeed {}", config.maxspeed_cross)))?
-        .flag_ok()?;
-    //.unwrap_or(Err(anyhow!("Failed to set max speed for cross axis"))?);
-    zaber_conn
-        .command_reply((2, format!("set limit.max {}", config.limit_max_cross)))?
-        .flag_ok()?;
-    //.unwrap_or(Err(anyhow!("Failed to set max limit for cross axis"))?);
-    zaber_conn
-        .command_reply((2, format!("set limit.min {}", config.limit_min_cross)))?
-        .flag_ok()?;
-    //.unwrap_or(Err(anyhow!("Failed to set min limit for cross axis"))?);
-    zaber_conn
-        .command_reply((2, format!("set accel {}", config.accel_cross)))?
-        .flag_ok()?;
-    //.unwrap_or(Err(anyhow!("Failed to set acceleration for cross axis"))?);
-
-    zaber_conn
-        .command_reply((1, "lockstep 1 setup enable 1 2"))?
-        .flag_ok()?;
-    //.unwrap_or(Err(anyhow!("Failed to enable lockstep mode"))?);
+/// Waits until `devices` are idle. If the emergency stop is triggered meanwhile, they
+/// are stopped right away instead of finishing their moves.
+fn wait_until_idle<T>(zaber_conn: &mut ZaberConn<T>, devices: &[u8], estop: &Estop) -> Result<()>
+where
+    T: zproto::backend::Backend,
+{
+    for &device in devices {
+        loop {
+            if let Some(trigger) = estop.triggered() {
+                // All devices are tried, even if one of them does not reply
+                for &device in devices {
+                    if let Err(e) = zaber_conn.command_reply((device, "estop")) {
+                        tracing::error!("Failed to stop device {}: {}", device, e);
+                    }
+                }
+                return Err(ControlError::EmergencyStop {
+                    source: trigger.source,
+                }
+                .into());
+            }
 
+            let reply = check_flag(
+                zaber_conn
+                    .command_reply((device, ""))?
+                    .check(check::unchecked())?,
+            )?;
+            if reply.status() == Status::Idle {
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
     Ok(())
 }
 
-pub fn get_pos_zaber<T: zproto::backend::Backend>(
+/// Sets up the devices of the enabled axes. Devices which are restored and homed, as
+/// decided by the homing policy, are also moved by the offsets of their lockstep groups,
+/// the others keep their positions and lockstep groups. Homing is aborted by `estop`.
+fn init_axes<T>(
     zaber_conn: &mut ZaberConn<T>,
-) -> Result<([bool; 2], [u32; 2])> {
-    let mut pos = [0; 2];
-    let mut is_busy = [false; 2];
-    for reply in zaber_conn.command_reply_n_iter("get pos", 2)? {
-        let reply = reply?.check(check::unchecked())?;
-        match reply.target().device() {
-            1 => {
-                pos[0] = reply
-                    .data()
-                    .split_whitespace()
-                    .next()
-                    .ok_or(anyhow!("only one value returned"))?
-                    .parse()?;
-                is_busy[0] = if reply.status() == Status::Busy {
-                    true
-                } else {
-                    false
-                };
-            }
-            2 => {
-                pos[1] = reply.data().parse()?;
-                is_busy[1] = if reply.status() == Status::Busy {
-                    true
-                } else {
-                    false
-                };
-            }
+    config: &Config,
+    store: &PositionStore,
+    estop: &Estop,
+) -> Result<()>
+where
+    T: zproto::backend::Backend,
+{
+    let axes = &config.axes;
+    let devices = enabled_devices(axes);
+    if devices.is_empty() {
+        return Err(anyhow!("All axes are disabled"));
+    }
+
+    let mut homing = Vec::new();
+    for &device in &devices {
+        let needs_homing = match config.homing_policy {
+            HomingPolicy::Always => true,
             _ => {
-                return Err(anyhow!(
-                    "Unkown device with number {}",
-                    reply.target().device()
-                ))
+                let check = check_device(zaber_conn, axes, device, store)?;
+                config.homing_policy.needs_homing(device, &check)?
             }
+        };
+        if needs_homing {
+            homing.push(device);
         }
     }
+
+    for &device in &homing {
+        zaber_conn
+            .command_reply((device, "system restore"))?
+            .check(check::unchecked())?;
+    }
+
+    for &device in &homing {
+        let _ = zaber_conn.command_reply((device, "home"));
+    }
+
+    wait_until_idle(zaber_conn, &homing, estop)?;
+
+    for &device in &devices {
+        check_flag(
+            zaber_conn
+                .command_reply((device, "set comm.alert 0"))?
+                .check(check::unchecked())?,
+        )?;
+    }
+
+    for axis in axes.iter().filter(|axis| axis.enabled) {
+        let device = axis.device;
+        let is_homing = homing.contains(&device);
+        if axis.lockstep.is_some() && is_homing {
+            let offset = distance_to_steps(axis.offset);
+            if offset > 0 {
+                check_flag(
+                    zaber_conn
+                        .command_reply((device, format!("1 move rel {}", offset)))?
+                        .check(check::unchecked())?,
+                )?;
+            } else if offset < 0 {
+                check_flag(
+                    zaber_conn
+                        .command_reply((device, format!("2 move rel {}", offset.abs())))?
+                        .check(check::unchecked())?,
+                )?;
+            }
+            wait_until_idle(zaber_conn, &[device], estop)?;
+        }
+
+        let (device, axis_number) = address(axis);
+        let [limit_min, limit_max] = axis.limits_steps();
+        for setting in [
+            format!("set maxspeed {}", axis.maxspeed_steps()),
+            format!("set limit.max {}", limit_max),
+            format!("set limit.min {}", limit_min),
+            format!("set accel {}", accel_to_steps(axis.accel)),
+        ] {
+            check_flag(
+                zaber_conn
+                    .command_reply((device, axis_number, setting))
+                    .with_context(|| format!("Failed to configure axis {}", axis.name))?
+                    .check(check::unchecked())?,
+            )?;
+        }
+
+        if let (Some(group), true) = (axis.lockstep, is_homing) {
+            check_flag(
+                zaber_conn
+                    .command_reply((device, format!("lockstep {} setup enable 1 2", group)))?
+                    .check(check::unchecked())?,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the positions of all axes can be read with a single broadcast,
+/// which requires exactly one reply per device.
+fn can_broadcast(axes: &[AxisConfig]) -> bool {
+    axes.iter().all(|axis| axis.enabled && address(axis).1 == 0)
+        && enabled_devices(axes).len() == axes.len()
+}
+
+/// Returns the busy flags and positions of the axes, disabled axes are reported as
+/// idle at position 0.
+pub fn get_pos_zaber<T: zproto::backend::Backend>(
+    zaber_conn: &mut ZaberConn<T>,
+    axes: &[AxisConfig],
+) -> Result<(Vec<bool>, Vec<u32>)> {
+    let mut pos = vec![0; axes.len()];
+    let mut is_busy = vec![false; axes.len()];
+
+    // A single broadcast is faster, but only possible if all devices are expected to reply
+    let replies: Vec<_> = match can_broadcast(axes) {
+        true => zaber_conn
+            .command_reply_n_iter("get pos", axes.len())?
+            .collect(),
+        false => axes
+            .iter()
+            .filter(|axis| axis.enabled)
+            .map(|axis| {
+                let (device, axis_number) = address(axis);
+                zaber_conn.command_reply((device, axis_number, "get pos"))
+            })
+            .collect(),
+    };
+
+    for reply in replies {
+        let reply = reply?.check(check::unchecked())?;
+        let target = reply.target();
+        let i = axes
+            .iter()
+            .position(|axis| axis.enabled && address(axis) == (target.device(), target.axis()))
+            .ok_or(anyhow!(
+                "Unkown device with number {} and axis {}",
+                target.device(),
+                target.axis()
+            ))?;
+
+        // Devices with several axes reply with one position per axis
+        pos[i] = reply
+            .data()
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| InvalidReply("No position returned".into()))?
+            .parse()?;
+        is_busy[i] = reply.status() == Status::Busy;
+    }
     return Ok((is_busy, pos));
 }
 
-pub fn move_coax_zaber<T: zproto::backend::Backend>(
+pub fn move_abs_zaber<T: zproto::backend::Backend>(
     zaber_conn: &mut ZaberConn<T>,
+    axis: &AxisConfig,
     pos: u32,
 ) -> Result<()> {
-    let cmd = format!("lockstep 1 move abs {}", pos);
-    let _ = zaber_conn.command_reply((1, cmd))?.flag_ok()?;
+    let cmd = motion_command(axis, &format!("move abs {}", pos));
+    check_flag(zaber_conn.command_reply(cmd)?.check(check::unchecked())?)?;
     Ok(())
 }
 
-pub fn move_cross_zaber<T: zproto::backend::Backend>(
+impl<T: zproto::backend::Backend> Backend for ZaberBackend<T> {
+    fn get_pos(&mut self) -> Result<(Vec<bool>, Vec<u32>)> {
+        get_pos_zaber(&mut self.port, &self.axes).map_err(|e| zaber_error(e, None))
+    }
+
+    fn move_abs(&mut self, axis: usize, target: u32) -> Result<()> {
+        let axis = get_axis(&self.axes, axis)?;
+        move_abs_zaber(&mut self.port, axis, target).map_err(|e| zaber_error(e, Some(axis)))
+    }
+
+    fn move_vel(&mut self, axis: usize, velocity: i32) -> Result<()> {
+        let axis = get_axis(&self.axes, axis)?;
+        move_vel_zaber(&mut self.port, axis, velocity).map_err(|e| zaber_error(e, Some(axis)))
+    }
+
+    fn set_maxspeed(&mut self, axis: usize, speed: u32) -> Result<()> {
+        let axis = get_axis(&self.axes, axis)?;
+        set_maxspeed_zaber(&mut self.port, axis, speed).map_err(|e| zaber_error(e, Some(axis)))
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        stop_zaber(&mut self.port, &self.axes).map_err(|e| zaber_error(e, None))
+    }
+
+    fn estop(&mut self) -> Result<()> {
+        estop_zaber(&mut self.port, &self.axes).map_err(|e| zaber_error(e, None))
+    }
+}
+
+pub fn move_vel_zaber<T: zproto::backend::Backend>(
     zaber_conn: &mut ZaberConn<T>,
-    pos: u32,
+    axis: &AxisConfig,
+    velocity: i32,
+) -> Result<()> {
+    let cmd = motion_command(axis, &format!("move vel {}", velocity));
+    check_flag(zaber_conn.command_reply(cmd)?.check(check::unchecked())?)?;
+    Ok(())
+}
+
+pub fn set_maxspeed_zaber<T: zproto::backend::Backend>(
+    zaber_conn: &mut ZaberConn<T>,
+    axis: &AxisConfig,
+    speed: u32,
 ) -> Result<()> {
-    let cmd = format!("move abs {}", pos);
-    let _ = zaber_conn.command_reply((2, cmd))?.flag_ok()?;
+    let (device, axis_number) = address(axis);
+    check_flag(
+        zaber_conn
+            .command_reply((device, axis_number, format!("set maxspeed {}", speed)))?
+            .check(check::unchecked())?,
+    )?;
     Ok(())
 }
 
+pub fn stop_zaber<T: zproto::backend::Backend>(
+    zaber_conn: &mut ZaberConn<T>,
+    axes: &[AxisConfig],
+) -> Result<()> {
+    for axis in axes.iter().filter(|axis| axis.enabled) {
+        check_flag(
+            zaber_conn
+                .command_reply(motion_command(axis, "stop"))?
+                .check(check::unchecked())?,
+        )?;
+    }
+    Ok(())
+}
+
+pub fn estop_zaber<T: zproto::backend::Backend>(
+    zaber_conn: &mut ZaberConn<T>,
+    axes: &[AxisConfig],
+) -> Result<()> {
+    // All axes are tried, even if one of them does not reply
+    let results = axes
+        .iter()
+        .filter(|axis| axis.enabled)
+        .map(|axis| -> Result<()> {
+            check_flag(
+                zaber_conn
+                    .command_reply(motion_command(axis, "estop"))?
+                    .check(check::unchecked())?,
+            )?;
+            Ok(())
+        })
+        .collect::<Vec<_>>();
+    results.into_iter().collect()
+}
+
 pub fn steps_to_mm(steps: u32) -> f64 {
     steps as f64 * MICROSTEP_SIZE / 1000.
 }
@@ -181,3 +516,95 @@ pub fn steps_to_mm(steps: u32) -> f64 {
 pub fn mm_to_steps(millis: f64) -> u32 {
     (millis * 1000. / MICROSTEP_SIZE) as u32
 }
+
+/// Converts a distance in mm into microsteps, rounded to the nearest one.
+pub fn distance_to_steps(millis: f64) -> i32 {
+    (millis * 1000. / MICROSTEP_SIZE).round() as i32
+}
+
+/// Converts a speed in mm/s into Zaber velocity units.
+pub fn vel_to_steps(millis_per_sec: f64) -> u32 {
+    (millis_per_sec * 1000. * VELOCITY_FACTOR / MICROSTEP_SIZE).round() as u32
+}
+
+/// Converts Zaber velocity units into a speed in mm/s.
+pub fn steps_to_vel(speed: u32) -> f64 {
+    speed as f64 * MICROSTEP_SIZE / VELOCITY_FACTOR / 1000.
+}
+
+/// Converts an acceleration in mm/s² into Zaber acceleration units.
+pub fn accel_to_steps(millis_per_sec2: f64) -> u32 {
+    (millis_per_sec2 * VELOCITY_FACTOR / MICROSTEP_SIZE / 10.).round() as u32
+}
+
+/// Converts Zaber acceleration units into an acceleration in mm/s².
+pub fn steps_to_accel(accel: u32) -> f64 {
+    accel as f64 * MICROSTEP_SIZE * 10. / VELOCITY_FACTOR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zproto::ascii::port::OpenGeneralOptions;
+
+    fn open(sim: Simulator) -> ZaberConn<Simulator> {
+        let mut opt = OpenGeneralOptions::new();
+        opt.checksums(false);
+        opt.message_ids(false);
+        opt.open(sim)
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(distance_to_steps(steps_to_mm(MAX_POS)), MAX_POS as i32);
+        assert_eq!(distance_to_steps(-1.), -2016);
+        assert_eq!(vel_to_steps(steps_to_vel(MAX_SPEED)), MAX_SPEED);
+        assert_eq!(accel_to_steps(steps_to_accel(205)), 205);
+        assert_eq!(accel_to_steps(1000.), 330);
+    }
+
+    #[test]
+    fn test_init_axes_homing() {
+        let mut config = Config::default();
+        config.axes = vec![
+            AxisConfig {
+                lockstep: Some(1),
+                offset: steps_to_mm(100),
+                ..AxisConfig::new("coax", 1)
+            },
+            AxisConfig::new("cross", 2),
+        ];
+        config.homing_policy = HomingPolicy::WhenUnhomed;
+        let mut port = open(Simulator::new(2));
+
+        // Unhomed devices are homed even without stored positions
+        let estop = Estop::new();
+        init_axes(&mut port, &config, &PositionStore::default(), &estop).unwrap();
+        assert_eq!(port.backend().homed, vec![true; 2]);
+        assert_eq!(port.backend().offset[0], Some(100));
+
+        move_abs_zaber(&mut port, &config.axes[1], 3000).unwrap();
+        port.poll_until_idle(2, check::flag_ok()).unwrap();
+        let (_, positions) = get_pos_zaber(&mut port, &config.axes).unwrap();
+        let store = PositionStore::new(&config.axes, &positions);
+
+        // Verified devices keep their positions and lockstep groups
+        init_axes(&mut port, &config, &store, &estop).unwrap();
+        assert_eq!(port.backend().target, vec![[100, 0], [3000, 3000]]);
+        assert_eq!(port.backend().offset[0], Some(100));
+
+        // Devices at other positions are homed again
+        let moved = PositionStore::new(&config.axes, &[positions[0], 5000]);
+        init_axes(&mut port, &config, &moved, &estop).unwrap();
+        assert_eq!(port.backend().target[1], [0, 0]);
+
+        config.homing_policy = HomingPolicy::Never;
+        assert!(init_axes(&mut open(Simulator::new(2)), &config, &store, &estop).is_err());
+
+        // Homing is aborted by the emergency stop
+        config.homing_policy = HomingPolicy::Always;
+        estop.trigger("test");
+        let error = init_axes(&mut port, &config, &store, &estop).unwrap_err();
+        assert_eq!(ControlError::from_error(&error).code(), 600);
+    }
+}